@@ -1,3 +1,21 @@
+// the embedded song is compiled with the host tools, so include it straight from the source
+#[path = "src/data.rs"]
+mod data;
+
+use std::{env, fs, path::PathBuf};
+
 fn main() {
     println!("cargo:rustc-link-arg=-Tlinkall.x");
+
+    // ---------- precompile the embedded song into a compact event stream ----------
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/data.rs");
+    println!("cargo:rerun-if-changed=src/song_stream.rs");
+
+    let stream = midi_tools::song_compiler::compile_song(data::MIDI_DATA)
+        .unwrap_or_else(|err| panic!("MIDI_DATA could not be compiled: {err}"));
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("cargo sets OUT_DIR"));
+    fs::write(out_dir.join("midi_data.song"), stream).expect("OUT_DIR is writable");
 }
@@ -0,0 +1,203 @@
+// =============================================================================================
+//                            CHORDS TAKING TURNS ON ONE BUZZER
+// =============================================================================================
+
+// when a channel runs out of buzzers, the notes of its chord take turns on the one it already
+// has, fast enough that they are heard as a chord like on old sound chips
+//
+// the turn is worked out from the time since the chord started, so notes joining and leaving
+// the chord don't change the pace
+
+use heapless::Vec;
+use midly::num::u7;
+
+/// the order the notes of a chord take their turns in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpeggioOrder {
+    Up,
+    Down,
+    UpDown, // up and back down without playing the top and bottom twice
+    AsPlayed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chord<const NOTES: usize> {
+    notes: Vec<(u7, u16), NOTES>, // key and its period, sorted by key unless played in order
+    order: ArpeggioOrder,
+    step_micros: u32,
+    started_at: u64,
+}
+
+impl<const NOTES: usize> Chord<NOTES> {
+    /// a chord of the sounding note, rate_hz is how many turns there are in a second
+    pub fn new(key: u7, period: u16, order: ArpeggioOrder, rate_hz: u16, started_at: u64) -> Self {
+        let mut notes = Vec::new();
+        let _ = notes.push((key, period));
+        Chord {
+            notes,
+            order,
+            // period divides by the step, so it's never 0
+            step_micros: (1_000_000 / rate_hz.max(1) as u32).max(1),
+            started_at,
+        }
+    }
+
+    pub fn notes(&self) -> &[(u7, u16)] {
+        &self.notes
+    }
+
+    pub fn contains(&self, key: u7) -> bool {
+        self.notes.iter().any(|(note, _)| *note == key)
+    }
+
+    /// false when the chord is full, a key already in the chord is left as it is
+    pub fn add(&mut self, key: u7, period: u16) -> bool {
+        if self.contains(key) {
+            return true;
+        }
+        if self.notes.push((key, period)).is_err() {
+            return false;
+        }
+        if self.order != ArpeggioOrder::AsPlayed {
+            self.notes.sort_unstable_by_key(|(key, _)| *key);
+        }
+        true
+    }
+
+    pub fn remove(&mut self, key: u7) {
+        self.notes.retain(|(note, _)| *note != key);
+    }
+
+    /// the period of the note whose turn it is
+    pub fn period(&self, now: u64) -> u16 {
+        let count = self.notes.len();
+        if count == 0 {
+            return 0;
+        }
+        let step = (now.saturating_sub(self.started_at) / self.step_micros as u64) as usize;
+        let index = match self.order {
+            ArpeggioOrder::Up | ArpeggioOrder::AsPlayed => step % count,
+            ArpeggioOrder::Down => count - 1 - step % count,
+            ArpeggioOrder::UpDown if count < 2 => 0,
+            ArpeggioOrder::UpDown => {
+                let cycle = 2 * count - 2;
+                let step = step % cycle;
+                if step < count { step } else { cycle - step }
+            }
+        };
+        self.notes[index].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    // 100 turns a second, a turn every 10 ms
+    const STEP: u64 = 10_000;
+
+    /// a chord of the keys in the order they're played, each key's period is the key times 10
+    fn chord(order: ArpeggioOrder, keys: &[u8]) -> Chord<4> {
+        let mut chord = Chord::new(u7::new(keys[0]), keys[0] as u16 * 10, order, 100, 0);
+        for key in &keys[1..] {
+            assert!(chord.add(u7::new(*key), *key as u16 * 10));
+        }
+        chord
+    }
+
+    /// the keys of the first turns
+    fn turns(chord: &Chord<4>, count: u64) -> Vec<u16> {
+        (0..count)
+            .map(|turn| chord.period(turn * STEP) / 10)
+            .collect()
+    }
+
+    #[test]
+    fn every_order_takes_its_turns() {
+        let keys = [64, 60, 67];
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::Up, &keys), 6),
+            [60, 64, 67, 60, 64, 67]
+        );
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::Down, &keys), 6),
+            [67, 64, 60, 67, 64, 60]
+        );
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::UpDown, &keys), 8),
+            [60, 64, 67, 64, 60, 64, 67, 64]
+        );
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::AsPlayed, &keys), 6),
+            [64, 60, 67, 64, 60, 67]
+        );
+    }
+
+    #[test]
+    fn up_and_down_doesnt_repeat_the_top_or_the_bottom() {
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::UpDown, &[67, 60]), 4),
+            [60, 67, 60, 67]
+        );
+        assert_eq!(
+            turns(&chord(ArpeggioOrder::UpDown, &[60, 64, 67, 72]), 8),
+            [60, 64, 67, 72, 67, 64, 60, 64]
+        );
+        assert_eq!(turns(&chord(ArpeggioOrder::UpDown, &[60]), 3), [60, 60, 60]);
+    }
+
+    #[test]
+    fn a_full_chord_refuses_more_notes() {
+        let mut chord = chord(ArpeggioOrder::Up, &[60, 64, 67, 72]);
+        assert!(!chord.add(u7::new(76), 760));
+        assert!(!chord.contains(u7::new(76)));
+        // a key that is already there is fine and keeps its period
+        assert!(chord.add(u7::new(64), 1));
+        assert_eq!(chord.notes().len(), 4);
+        assert_eq!(chord.notes()[1], (u7::new(64), 640));
+
+        chord.remove(u7::new(64));
+        assert_eq!(turns(&chord, 3), [60, 67, 72]);
+        assert!(chord.add(u7::new(76), 760));
+        chord.remove(u7::new(50));
+        assert_eq!(chord.notes().len(), 4);
+    }
+
+    #[test]
+    fn an_empty_chord_is_silent() {
+        let mut chord = chord(ArpeggioOrder::Up, &[60]);
+        chord.remove(u7::new(60));
+        assert_eq!(chord.period(0), 0);
+        assert_eq!(chord.period(STEP), 0);
+    }
+
+    #[test]
+    fn notes_joining_keep_the_pace() {
+        let mut chord = Chord::<4>::new(u7::new(60), 600, ArpeggioOrder::Up, 100, 1_000);
+        assert_eq!(chord.period(1_000 + 5 * STEP), 600);
+        // a turn starts every 10 ms from the start of the chord, not from when a note joins
+        chord.add(u7::new(64), 640);
+        assert_eq!(chord.period(1_000 + 6 * STEP - 1), 640);
+        assert_eq!(chord.period(1_000 + 6 * STEP), 600);
+        chord.add(u7::new(67), 670);
+        assert_eq!(chord.period(1_000 + 7 * STEP + 1), 640);
+        assert_eq!(chord.period(1_000 + 8 * STEP), 670);
+    }
+
+    #[test]
+    fn the_fastest_and_slowest_rates_still_take_turns() {
+        let mut chord = Chord::<4>::new(u7::new(60), 600, ArpeggioOrder::Up, u16::MAX, 0);
+        chord.add(u7::new(64), 640);
+        // 65535 Hz is a turn every 15 micro seconds
+        assert_eq!(chord.period(14), 600);
+        assert_eq!(chord.period(15), 640);
+
+        // a rate of 0 is a turn a second
+        let mut chord = Chord::<4>::new(u7::new(60), 600, ArpeggioOrder::Up, 0, 0);
+        chord.add(u7::new(64), 640);
+        assert_eq!(chord.period(999_999), 600);
+        assert_eq!(chord.period(1_000_000), 640);
+    }
+}
@@ -0,0 +1,19 @@
+// =============================================================================================
+//                               BLOCK DEVICES FOR THE SONG LIBRARY
+// =============================================================================================
+
+// the filesystem only reads whole 512 byte blocks and doesn't care where they come from,
+// on the device they come from the SD card, on the host from a disk image in memory
+
+use core::fmt::Debug;
+
+pub const BLOCK_LEN: usize = 512;
+
+pub type Block = [u8; BLOCK_LEN];
+
+pub trait BlockDevice {
+    type Error: Debug;
+
+    /// reads the block at the index, counted from the start of the device
+    fn read_block(&mut self, index: u32, block: &mut Block) -> Result<(), Self::Error>;
+}
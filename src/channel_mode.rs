@@ -0,0 +1,266 @@
+// =============================================================================================
+//                      CONTROLLERS AND CHANNEL MODE MESSAGES OF A CHANNEL
+// =============================================================================================
+
+// the controller messages the synth follows become ChannelControls on the sequencer side,
+// the synth keeps their state for every channel and tells the buzzer bank what the change
+// does to the notes already sounding
+//
+// the mode messages are controllers 120 - 127:
+//
+//   120 all sound off, 123 all notes off    stop the notes of the channel
+//   124 omni off, 125 omni on               a song plays every channel, only stop the notes
+//   121 reset all controllers               back to the start, keeping the volume
+//   126 mono on, 127 poly on                one note at a time or back, stop the notes too
+
+use midly::num::u7;
+
+use crate::glide::portamento_micros;
+use crate::mixer::{DEFAULT_VOLUME, MAX_LEVEL, channel_level};
+use crate::voice_queue::ChannelControl;
+
+/// the control of a controller message, None for the controllers the synth doesn't follow
+pub fn channel_control(controller: u7, value: u7) -> Option<ChannelControl> {
+    let value = value.as_int();
+    // the switches are on from 64 up
+    let control = match controller.as_int() {
+        1 => ChannelControl::ModWheel(value),
+        7 => ChannelControl::Volume(value),
+        11 => ChannelControl::Expression(value),
+        5 => ChannelControl::PortamentoTime(value),
+        65 => ChannelControl::Portamento(value >= 64),
+        68 => ChannelControl::Legato(value >= 64),
+        // all sound off and all notes off are the same for buzzers that don't ring out
+        120 | 123..=125 => ChannelControl::NotesOff,
+        121 => ChannelControl::ResetControllers,
+        126 => ChannelControl::Mono(true),
+        127 => ChannelControl::Mono(false),
+        _ => return None,
+    };
+    Some(control)
+}
+
+impl ChannelControl {
+    /// the control ends every note of the channel, changing the mode does too
+    pub const fn stops_notes(&self) -> bool {
+        matches!(self, ChannelControl::NotesOff | ChannelControl::Mono(_))
+    }
+}
+
+/// what a control does to the sounding notes of the channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlEffect {
+    /// only the next notes play differently
+    None,
+    StopNotes,
+    /// the loudness of the sounding notes changes, from 0 to MAX_LEVEL
+    SetLevel(u8),
+}
+
+/// the controllers of a channel that change how its notes play
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    pub volume: u8,
+    pub expression: u8,
+    pub mod_wheel: u8,
+    pub portamento: bool,
+    pub portamento_micros: u32,
+    // an overlapping note moves the sounding note of the channel instead of starting a new one
+    pub legato: bool,
+    pub mono: bool, // one note at a time, a new note stops the one sounding
+    pub last_period: Option<u16>, // where the next glide starts
+}
+
+pub const CHANNEL_START: ChannelState = ChannelState {
+    volume: DEFAULT_VOLUME,
+    expression: MAX_LEVEL,
+    mod_wheel: 0,
+    portamento: false,
+    portamento_micros: 0,
+    legato: false,
+    mono: false,
+    last_period: None,
+};
+
+impl ChannelState {
+    /// the loudness of the notes of the channel
+    pub const fn level(&self) -> u8 {
+        channel_level(self.volume, self.expression)
+    }
+
+    /// the glide time of the next note, 0 without portamento
+    pub const fn glide_micros(&self) -> u32 {
+        match self.portamento {
+            true => self.portamento_micros,
+            false => 0,
+        }
+    }
+
+    pub fn apply(&mut self, control: ChannelControl) -> ControlEffect {
+        match control {
+            ChannelControl::Volume(value) => self.volume = value,
+            ChannelControl::Expression(value) => self.expression = value,
+            ChannelControl::ModWheel(value) => self.mod_wheel = value,
+            ChannelControl::PortamentoTime(value) => {
+                self.portamento_micros = portamento_micros(value)
+            }
+            ChannelControl::Portamento(on) => self.portamento = on,
+            ChannelControl::Legato(on) => self.legato = on,
+            ChannelControl::NotesOff => {}
+            // the portamento time is kept like the volume and the program
+            ChannelControl::ResetControllers => {
+                self.expression = MAX_LEVEL;
+                self.mod_wheel = 0;
+                self.portamento = false;
+                self.legato = false;
+            }
+            ChannelControl::Mono(on) => self.mono = on,
+        }
+
+        match control {
+            _ if control.stops_notes() => ControlEffect::StopNotes,
+            ChannelControl::Volume(_)
+            | ChannelControl::Expression(_)
+            | ChannelControl::ResetControllers => ControlEffect::SetLevel(self.level()),
+            _ => ControlEffect::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(controller: u8, value: u8) -> Option<ChannelControl> {
+        channel_control(u7::new(controller), u7::new(value))
+    }
+
+    /// a channel with every controller moved away from the start
+    fn played_channel() -> ChannelState {
+        let mut state = CHANNEL_START;
+        for (controller, value) in [(7, 90), (11, 40), (1, 100), (5, 64), (65, 127), (68, 127)] {
+            state.apply(control(controller, value).unwrap());
+        }
+        state.last_period = Some(2273);
+        state
+    }
+
+    #[test]
+    fn the_controllers_become_channel_controls() {
+        assert_eq!(control(1, 100), Some(ChannelControl::ModWheel(100)));
+        assert_eq!(control(7, 90), Some(ChannelControl::Volume(90)));
+        assert_eq!(control(11, 40), Some(ChannelControl::Expression(40)));
+        assert_eq!(control(5, 10), Some(ChannelControl::PortamentoTime(10)));
+        assert_eq!(control(65, 63), Some(ChannelControl::Portamento(false)));
+        assert_eq!(control(65, 64), Some(ChannelControl::Portamento(true)));
+        assert_eq!(control(68, 0), Some(ChannelControl::Legato(false)));
+        assert_eq!(control(68, 127), Some(ChannelControl::Legato(true)));
+        // bank select, pan and the sustain pedal aren't followed
+        for controller in [0, 10, 64, 119, 122] {
+            assert_eq!(control(controller, 127), None, "CC {controller}");
+        }
+    }
+
+    #[test]
+    fn the_mode_messages_become_channel_controls() {
+        for controller in [120, 123, 124, 125] {
+            assert_eq!(control(controller, 0), Some(ChannelControl::NotesOff));
+        }
+        assert_eq!(control(121, 0), Some(ChannelControl::ResetControllers));
+        // the value of mono on is the number of channels, any number is one note at a time
+        assert_eq!(control(126, 0), Some(ChannelControl::Mono(true)));
+        assert_eq!(control(126, 4), Some(ChannelControl::Mono(true)));
+        assert_eq!(control(127, 0), Some(ChannelControl::Mono(false)));
+    }
+
+    #[test]
+    fn all_notes_off_stops_the_notes_and_keeps_the_controllers() {
+        for controller in [123, 124, 125] {
+            let mut state = played_channel();
+            let effect = state.apply(control(controller, 0).unwrap());
+            assert_eq!(effect, ControlEffect::StopNotes, "CC {controller}");
+            assert_eq!(state, played_channel());
+        }
+    }
+
+    #[test]
+    fn all_sound_off_stops_the_notes_and_keeps_the_controllers() {
+        let mut state = played_channel();
+        assert_eq!(
+            state.apply(control(120, 0).unwrap()),
+            ControlEffect::StopNotes
+        );
+        assert_eq!(state, played_channel());
+    }
+
+    #[test]
+    fn reset_controllers_keeps_the_volume_and_the_mode() {
+        let mut state = played_channel();
+        state.apply(ChannelControl::Mono(true));
+        let effect = state.apply(control(121, 0).unwrap());
+
+        // the sounding notes get louder with the expression back up
+        assert_eq!(effect, ControlEffect::SetLevel(90));
+        assert_eq!(
+            state,
+            ChannelState {
+                volume: 90,
+                portamento_micros: portamento_micros(64),
+                mono: true,
+                last_period: Some(2273),
+                ..CHANNEL_START
+            }
+        );
+        assert_eq!(state.glide_micros(), 0);
+    }
+
+    #[test]
+    fn mono_and_poly_stop_the_notes_and_switch_the_mode() {
+        let mut state = played_channel();
+        assert_eq!(
+            state.apply(control(126, 1).unwrap()),
+            ControlEffect::StopNotes
+        );
+        assert!(state.mono);
+        assert_eq!(
+            state.apply(control(127, 0).unwrap()),
+            ControlEffect::StopNotes
+        );
+        assert!(!state.mono);
+        assert_eq!(
+            ChannelState {
+                mono: false,
+                ..state
+            },
+            played_channel()
+        );
+    }
+
+    #[test]
+    fn volume_and_expression_set_the_level_of_the_sounding_notes() {
+        let mut state = CHANNEL_START;
+        assert_eq!(state.level(), DEFAULT_VOLUME);
+        assert_eq!(
+            state.apply(ChannelControl::Volume(MAX_LEVEL)),
+            ControlEffect::SetLevel(MAX_LEVEL)
+        );
+        assert_eq!(
+            state.apply(ChannelControl::Expression(64)),
+            ControlEffect::SetLevel(64)
+        );
+        assert_eq!(
+            state.apply(ChannelControl::Volume(0)),
+            ControlEffect::SetLevel(0)
+        );
+        // the rest only changes the next notes
+        assert_eq!(
+            state.apply(ChannelControl::ModWheel(3)),
+            ControlEffect::None
+        );
+        assert_eq!(
+            state.apply(ChannelControl::Portamento(true)),
+            ControlEffect::None
+        );
+        assert_eq!(state.glide_micros(), state.portamento_micros);
+    }
+}
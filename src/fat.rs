@@ -0,0 +1,415 @@
+// =============================================================================================
+//                           READ ONLY FAT16 AND FAT32 FILESYSTEM
+// =============================================================================================
+
+// just enough of FAT to list the root directory of a card and read files from it,
+// the volume is either the first FAT partition of an MBR or the whole device
+//
+// files are plain cursors and all reads go through the volume and its one block cache,
+// so any number of positions in the same file can be read without more memory
+//
+// long file names are not read, files show up with their 8.3 names
+
+use core::fmt;
+
+use crate::block_device::{BLOCK_LEN, Block, BlockDevice};
+
+const DIR_ENTRY_LEN: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+// MBR partition types of FAT16 and FAT32 partitions
+const FAT_PARTITION_TYPES: [u8; 5] = [0x04, 0x06, 0x0E, 0x0B, 0x0C];
+
+#[derive(Debug)]
+pub enum FatError<E> {
+    Device(E),
+    NoFilesystem,
+    UnsupportedBlockSize(u16),
+    Fat12,
+    BrokenClusterChain,
+    /// a chain with more clusters than the volume has goes around in a loop
+    ClusterChainLoop,
+    /// a directory entry or the boot sector points at a cluster the volume doesn't have
+    BadCluster(u32),
+}
+
+impl<E: fmt::Debug> fmt::Display for FatError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FatError::Device(err) => write!(f, "block device error: {err:?}"),
+            FatError::NoFilesystem => write!(f, "no FAT16 or FAT32 filesystem found"),
+            FatError::UnsupportedBlockSize(size) => {
+                write!(f, "{size} byte sectors are not supported, only {BLOCK_LEN}")
+            }
+            FatError::Fat12 => write!(f, "FAT12 is not supported"),
+            FatError::BrokenClusterChain => write!(f, "a file ends before its size"),
+            FatError::ClusterChainLoop => write!(f, "a cluster chain loops back on itself"),
+            FatError::BadCluster(cluster) => write!(f, "cluster {cluster} is outside the volume"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatKind {
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootDir {
+    // FAT16 keeps the root directory in a fixed area before the data
+    Fixed { start: u32, blocks: u32 },
+    Cluster(u32),
+}
+
+/// a file or directory in a directory listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: [u8; 11], // 8.3 name padded with spaces, without the dot
+    pub is_dir: bool,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl DirEntry {
+    fn parse(bytes: &[u8]) -> Self {
+        let mut name = [0; 11];
+        name.copy_from_slice(&bytes[..11]);
+        let cluster_high = u16::from_le_bytes([bytes[20], bytes[21]]) as u32;
+        let cluster_low = u16::from_le_bytes([bytes[26], bytes[27]]) as u32;
+        DirEntry {
+            name,
+            is_dir: bytes[11] & ATTR_DIRECTORY != 0,
+            first_cluster: cluster_high << 16 | cluster_low,
+            size: read_u32(bytes, 28),
+        }
+    }
+
+    pub fn extension(&self) -> &[u8] {
+        trim_padding(&self.name[8..])
+    }
+
+    /// the name with the dot, like SONG.MID
+    pub fn file_name(&self) -> heapless::String<12> {
+        let mut file_name = heapless::String::new();
+        let base = trim_padding(&self.name[..8]);
+        let extension = self.extension();
+        // 8.3 names are ascii, anything else is shown as ?
+        let ascii = |byte: &u8| if byte.is_ascii() { *byte as char } else { '?' };
+        for c in base.iter().map(ascii) {
+            let _ = file_name.push(c);
+        }
+        if !extension.is_empty() {
+            let _ = file_name.push('.');
+            for c in extension.iter().map(ascii) {
+                let _ = file_name.push(c);
+            }
+        }
+        file_name
+    }
+}
+
+/// a read position in a file, reads go through FatVolume::read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatFile {
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    // the cluster that holds the position, 0 before the first read
+    cluster: u32,
+    cluster_index: u32, // counted from the start of the file
+}
+
+impl FatFile {
+    pub const fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+pub struct FatVolume<D: BlockDevice> {
+    device: D,
+    kind: FatKind,
+    blocks_per_cluster: u32,
+    fat_start: u32,
+    data_start: u32,
+    cluster_count: u32,
+    root: RootDir,
+    cache: Block,
+    cached_block: Option<u32>,
+}
+
+impl<D: BlockDevice> FatVolume<D> {
+    pub fn mount(device: D) -> Result<Self, FatError<D::Error>> {
+        let mut volume = FatVolume {
+            device,
+            kind: FatKind::Fat16,
+            blocks_per_cluster: 1,
+            fat_start: 0,
+            data_start: 0,
+            cluster_count: 0,
+            root: RootDir::Cluster(0),
+            cache: [0; BLOCK_LEN],
+            cached_block: None,
+        };
+
+        // a card formatted without partitions has the boot sector in block 0
+        let boot_block = volume.partition_start()?.unwrap_or(0);
+        volume.read_boot_sector(boot_block)?;
+        Ok(volume)
+    }
+
+    /// the first FAT partition of the MBR, None if block 0 is a boot sector instead
+    fn partition_start(&mut self) -> Result<Option<u32>, FatError<D::Error>> {
+        let block = self.block(0)?;
+        if block[510..512] != [0x55, 0xAA] {
+            return Err(FatError::NoFilesystem);
+        }
+        if is_boot_sector(block) {
+            return Ok(None);
+        }
+        (0..4)
+            .map(|i| &block[446 + i * 16..446 + (i + 1) * 16])
+            .find(|entry| FAT_PARTITION_TYPES.contains(&entry[4]))
+            .map(|entry| Some(read_u32(entry, 8)))
+            .ok_or(FatError::NoFilesystem)
+    }
+
+    fn read_boot_sector(&mut self, boot_block: u32) -> Result<(), FatError<D::Error>> {
+        let block = self.block(boot_block)?;
+        if !is_boot_sector(block) {
+            return Err(FatError::NoFilesystem);
+        }
+
+        let bytes_per_sector = u16::from_le_bytes([block[11], block[12]]);
+        if bytes_per_sector as usize != BLOCK_LEN {
+            return Err(FatError::UnsupportedBlockSize(bytes_per_sector));
+        }
+        let blocks_per_cluster = block[13] as u32;
+        let reserved_blocks = u16::from_le_bytes([block[14], block[15]]) as u32;
+        let fat_count = block[16] as u32;
+        let root_entries = u16::from_le_bytes([block[17], block[18]]) as u32;
+        let total_blocks = match u16::from_le_bytes([block[19], block[20]]) {
+            0 => read_u32(block, 32),
+            blocks => blocks as u32,
+        };
+        let fat_blocks = match u16::from_le_bytes([block[22], block[23]]) {
+            0 => read_u32(block, 36),
+            blocks => blocks as u32,
+        };
+        let root_cluster = read_u32(block, 44);
+        if blocks_per_cluster == 0 || fat_count == 0 {
+            return Err(FatError::NoFilesystem);
+        }
+
+        let fat_start = boot_block + reserved_blocks;
+        let root_start = fat_start + fat_count * fat_blocks;
+        let root_blocks = (root_entries * DIR_ENTRY_LEN as u32).div_ceil(BLOCK_LEN as u32);
+        let data_start = root_start + root_blocks;
+        let cluster_count =
+            total_blocks.saturating_sub(data_start - boot_block) / blocks_per_cluster;
+
+        // the FAT type only depends on the cluster count
+        self.kind = match cluster_count {
+            0..4085 => return Err(FatError::Fat12),
+            4085..65525 => FatKind::Fat16,
+            _ => FatKind::Fat32,
+        };
+        self.root = match self.kind {
+            FatKind::Fat16 => RootDir::Fixed {
+                start: root_start,
+                blocks: root_blocks,
+            },
+            FatKind::Fat32 => RootDir::Cluster(root_cluster),
+        };
+        self.blocks_per_cluster = blocks_per_cluster;
+        self.fat_start = fat_start;
+        self.data_start = data_start;
+        self.cluster_count = cluster_count;
+        if let RootDir::Cluster(root_cluster) = self.root {
+            self.cluster_block(root_cluster)?;
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, index: u32) -> Result<&Block, FatError<D::Error>> {
+        if self.cached_block != Some(index) {
+            // a failed read leaves the cache half written
+            self.cached_block = None;
+            self.device
+                .read_block(index, &mut self.cache)
+                .map_err(FatError::Device)?;
+            self.cached_block = Some(index);
+        }
+        Ok(&self.cache)
+    }
+
+    /// the first block of the cluster, the data clusters are numbered from 2
+    fn cluster_block(&self, cluster: u32) -> Result<u32, FatError<D::Error>> {
+        if cluster < 2 || cluster >= self.cluster_count + 2 {
+            return Err(FatError::BadCluster(cluster));
+        }
+        Ok(self.data_start + (cluster - 2) * self.blocks_per_cluster)
+    }
+
+    /// the cluster after this one in the chain, None at the end of the chain
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FatError<D::Error>> {
+        // a corrupted directory entry would point past the end of the FAT
+        self.cluster_block(cluster)?;
+        let kind = self.kind;
+        let entry_len = match kind {
+            FatKind::Fat16 => 2,
+            FatKind::Fat32 => 4,
+        };
+        let offset = cluster * entry_len;
+        let block = self.block(self.fat_start + offset / BLOCK_LEN as u32)?;
+        let i = offset as usize % BLOCK_LEN;
+
+        let next = match kind {
+            FatKind::Fat16 => u16::from_le_bytes([block[i], block[i + 1]]) as u32,
+            FatKind::Fat32 => read_u32(block, i) & 0x0FFF_FFFF,
+        };
+        // free, bad and end of chain markers all end the file
+        if next < 2 || next >= self.cluster_count + 2 {
+            Ok(None)
+        } else {
+            Ok(Some(next))
+        }
+    }
+
+    /// calls `found` for every file and directory in the root directory
+    pub fn list_root(&mut self, mut found: impl FnMut(DirEntry)) -> Result<(), FatError<D::Error>> {
+        match self.root {
+            RootDir::Fixed { start, blocks } => {
+                for index in start..start + blocks {
+                    let block = self.block(index)?;
+                    for entry in block.chunks(DIR_ENTRY_LEN) {
+                        match entry[0] {
+                            0x00 => return Ok(()), // no more entries after this
+                            0xE5 => {}             // deleted
+                            _ => visit_entry(entry, &mut found),
+                        }
+                    }
+                }
+            }
+            RootDir::Cluster(first_cluster) => {
+                let mut cluster = Some(first_cluster);
+                let mut walked = 0;
+                while let Some(current) = cluster {
+                    walked += 1;
+                    if walked > self.cluster_count {
+                        return Err(FatError::ClusterChainLoop);
+                    }
+                    let start = self.cluster_block(current)?;
+                    for index in start..start + self.blocks_per_cluster {
+                        let block = self.block(index)?;
+                        for entry in block.chunks(DIR_ENTRY_LEN) {
+                            match entry[0] {
+                                0x00 => return Ok(()),
+                                0xE5 => {}
+                                _ => visit_entry(entry, &mut found),
+                            }
+                        }
+                    }
+                    cluster = self.next_cluster(current)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn open(&self, entry: &DirEntry) -> FatFile {
+        FatFile {
+            first_cluster: entry.first_cluster,
+            size: if entry.is_dir { 0 } else { entry.size },
+            position: 0,
+            cluster: 0,
+            cluster_index: 0,
+        }
+    }
+
+    /// moves the read position, positions past the end are clamped to the end
+    pub fn seek(&mut self, file: &mut FatFile, position: u32) {
+        let position = position.min(file.size);
+        let cluster_len = self.blocks_per_cluster * BLOCK_LEN as u32;
+        // the chain only goes forwards, going back starts again from the first cluster
+        if file.cluster == 0 || position / cluster_len < file.cluster_index {
+            file.cluster = 0;
+            file.cluster_index = 0;
+        }
+        file.position = position;
+    }
+
+    /// reads from the position of the file, returns how many bytes were read, 0 at the end
+    pub fn read(
+        &mut self,
+        file: &mut FatFile,
+        buffer: &mut [u8],
+    ) -> Result<usize, FatError<D::Error>> {
+        let cluster_len = self.blocks_per_cluster * BLOCK_LEN as u32;
+        let mut read = 0;
+        while read < buffer.len() && file.position < file.size {
+            // follow the chain to the cluster of the position
+            if file.cluster == 0 {
+                file.cluster = file.first_cluster;
+                file.cluster_index = 0;
+            }
+            while file.cluster_index < file.position / cluster_len {
+                file.cluster = self
+                    .next_cluster(file.cluster)?
+                    .ok_or(FatError::BrokenClusterChain)?;
+                file.cluster_index += 1;
+            }
+
+            let in_cluster = file.position % cluster_len;
+            let index = self.cluster_block(file.cluster)? + in_cluster / BLOCK_LEN as u32;
+            let in_block = in_cluster as usize % BLOCK_LEN;
+            let len = (BLOCK_LEN - in_block)
+                .min(buffer.len() - read)
+                .min((file.size - file.position) as usize);
+
+            let block = self.block(index)?;
+            buffer[read..read + len].copy_from_slice(&block[in_block..in_block + len]);
+            read += len;
+            file.position += len as u32;
+        }
+        Ok(read)
+    }
+}
+
+fn visit_entry(entry: &[u8], found: &mut impl FnMut(DirEntry)) {
+    let attributes = entry[11];
+    // long name parts and the volume label are not files
+    if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME || attributes & ATTR_VOLUME_ID != 0 {
+        return;
+    }
+    // the . and .. entries of sub directories
+    if entry[0] == b'.' {
+        return;
+    }
+    found(DirEntry::parse(entry));
+}
+
+fn is_boot_sector(block: &Block) -> bool {
+    // every FAT boot sector starts with a jump instruction
+    matches!(block[0], 0xEB | 0xE9) && block[510..512] == [0x55, 0xAA]
+}
+
+fn trim_padding(name: &[u8]) -> &[u8] {
+    let len = name
+        .iter()
+        .rposition(|byte| *byte != b' ')
+        .map_or(0, |i| i + 1);
+    &name[..len]
+}
+
+#[inline(always)]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
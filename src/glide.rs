@@ -0,0 +1,99 @@
+// =============================================================================================
+//                            PORTAMENTO GLIDES BETWEEN NOTES
+// =============================================================================================
+
+// with portamento (CC 65) on, a note slides from the period of the channel's last note
+// to its own over the portamento time (CC 5)
+//
+// the period of a key is a straight line over the keys in the sound profiles, so a straight
+// line between the periods moves through the keys between them at an even pace
+
+/// the glide time of CC 5 all the way up
+pub const MAX_PORTAMENTO_MICROS: u32 = 3_000_000;
+
+/// the glide time of a CC 5 value, slower values get finer steps than faster ones
+pub const fn portamento_micros(value: u8) -> u32 {
+    let value = if value > 127 { 127 } else { value } as u64;
+    (value * value * MAX_PORTAMENTO_MICROS as u64 / (127 * 127)) as u32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Glide {
+    from: u16,
+    to: u16,
+    started_at: u64,
+    micros: u32,
+}
+
+impl Glide {
+    pub const fn new(from: u16, to: u16, started_at: u64, micros: u32) -> Self {
+        Glide {
+            from,
+            to,
+            started_at,
+            micros,
+        }
+    }
+
+    /// a note that stays on its key
+    pub const fn steady(period: u16) -> Self {
+        Glide::new(period, period, 0, 0)
+    }
+
+    /// the period of the note at the time
+    pub const fn period(&self, now: u64) -> u16 {
+        let elapsed = now.saturating_sub(self.started_at);
+        if elapsed >= self.micros as u64 {
+            return self.to;
+        }
+        let change = (self.to as i64 - self.from as i64) * elapsed as i64 / self.micros as i64;
+        (self.from as i64 + change) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_glide_moves_evenly_from_one_period_to_the_other() {
+        // up to a higher note, 100 ms from 5 ms on
+        let glide = Glide::new(2000, 1000, 5_000, 100_000);
+        assert_eq!(glide.period(0), 2000);
+        assert_eq!(glide.period(5_000), 2000);
+        assert_eq!(glide.period(30_000), 1750);
+        assert_eq!(glide.period(55_000), 1500);
+        assert_eq!(glide.period(104_999), 1001);
+        assert_eq!(glide.period(105_000), 1000);
+        assert_eq!(glide.period(10_000_000), 1000);
+    }
+
+    #[test]
+    fn a_falling_glide_moves_the_other_way() {
+        let glide = Glide::new(1000, 3000, 0, 200_000);
+        assert_eq!(glide.period(0), 1000);
+        assert_eq!(glide.period(50_000), 1500);
+        assert_eq!(glide.period(100_000), 2000);
+        assert_eq!(glide.period(199_999), 2999);
+        assert_eq!(glide.period(200_000), 3000);
+    }
+
+    #[test]
+    fn without_a_glide_time_the_note_starts_on_its_key() {
+        let glide = Glide::new(2000, 1000, 5_000, 0);
+        assert_eq!(glide.period(0), 1000);
+        assert_eq!(glide.period(5_000), 1000);
+        let steady = Glide::steady(2273);
+        assert_eq!(steady.period(0), 2273);
+        assert_eq!(steady.period(u64::MAX), 2273);
+    }
+
+    #[test]
+    fn the_glide_time_grows_with_the_square_of_cc_5() {
+        assert_eq!(portamento_micros(0), 0);
+        assert_eq!(portamento_micros(1), 186);
+        assert_eq!(portamento_micros(64), 761_857);
+        assert_eq!(portamento_micros(127), MAX_PORTAMENTO_MICROS);
+        assert_eq!(portamento_micros(255), MAX_PORTAMENTO_MICROS);
+    }
+}
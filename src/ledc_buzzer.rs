@@ -0,0 +1,251 @@
+// =============================================================================================
+//                        LEDC PWM BUZZERS FOR HARDWARE GENERATED TONES
+// =============================================================================================
+
+// the LEDC peripheral generates the square wave by itself, so the CPU only writes
+// the registers when a note starts or stops, every voice gets its own timer and channel
+// ESP32 has 4 high speed and 4 low speed timers, so up to 8 voices
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+
+use esp_hal::{
+    gpio::{AnyPin, Level, Output, OutputConfig, OutputSignal},
+    ledc::{LSGlobalClkSource, Ledc},
+    peripherals::LEDC,
+    time::{Duration, Instant},
+};
+use heapless::LinearMap;
+use midly::num::u7;
+
+use crate::ledc_timing::{ledc_timing, period_to_millihertz};
+use crate::mixer::level_duty_pct;
+use crate::sound_profiles::SoundProfile;
+use crate::{Buzzer, SoundKey};
+
+// =============================================================================================
+//                               LEDC REGISTERS FOR DIRECT WRITES
+// =============================================================================================
+
+const LEDC_BASE: usize = 0x3FF5_9000;
+
+const HS_CHANNEL_OFFSET: usize = 0x000;
+const LS_CHANNEL_OFFSET: usize = 0x0A0;
+const CHANNEL_STRIDE: usize = 0x14;
+const CH_CONF0: usize = 0x00;
+const CH_HPOINT: usize = 0x04;
+const CH_DUTY: usize = 0x08;
+const CH_CONF1: usize = 0x0C;
+
+const HS_TIMER_OFFSET: usize = 0x140;
+const LS_TIMER_OFFSET: usize = 0x160;
+const TIMER_STRIDE: usize = 0x08;
+
+// timer conf bits
+const TIMER_DUTY_RES_SHIFT: u32 = 0;
+const TIMER_DIV_NUM_SHIFT: u32 = 5;
+const TIMER_PAUSE: u32 = 1 << 23;
+const TIMER_TICK_SEL_APB: u32 = 1 << 25;
+const LS_TIMER_PARA_UP: u32 = 1 << 26;
+
+// channel conf0 bits
+const CH_SIG_OUT_EN: u32 = 1 << 2;
+const LS_CH_PARA_UP: u32 = 1 << 4;
+
+// channel conf1: start a duty change of one step, no fading
+const CH_DUTY_START: u32 = (1 << 31) | (1 << 30) | (1 << 20) | (1 << 10);
+
+// the duty register has 4 fractional bits
+const DUTY_FRACTION_BITS: u32 = 4;
+
+const TONE_DUTY_PCT: u8 = 50;
+
+// =============================================================================================
+//                                      LEDC VOICES
+// =============================================================================================
+
+/// timer n always drives channel n of the same speed mode
+#[derive(Debug, Clone, Copy)]
+pub enum LedcVoice {
+    HighSpeed(u8),
+    LowSpeed(u8),
+}
+
+impl LedcVoice {
+    const fn number(&self) -> usize {
+        match self {
+            LedcVoice::HighSpeed(n) | LedcVoice::LowSpeed(n) => *n as usize,
+        }
+    }
+
+    const fn is_low_speed(&self) -> bool {
+        matches!(self, LedcVoice::LowSpeed(_))
+    }
+
+    const fn output_signal(&self) -> OutputSignal {
+        match self {
+            LedcVoice::HighSpeed(0) => OutputSignal::LEDC_HS_SIG0,
+            LedcVoice::HighSpeed(1) => OutputSignal::LEDC_HS_SIG1,
+            LedcVoice::HighSpeed(2) => OutputSignal::LEDC_HS_SIG2,
+            LedcVoice::HighSpeed(_) => OutputSignal::LEDC_HS_SIG3,
+            LedcVoice::LowSpeed(0) => OutputSignal::LEDC_LS_SIG0,
+            LedcVoice::LowSpeed(1) => OutputSignal::LEDC_LS_SIG1,
+            LedcVoice::LowSpeed(2) => OutputSignal::LEDC_LS_SIG2,
+            LedcVoice::LowSpeed(_) => OutputSignal::LEDC_LS_SIG3,
+        }
+    }
+
+    const fn timer_conf_reg(&self) -> *mut u32 {
+        let offset = if self.is_low_speed() {
+            LS_TIMER_OFFSET
+        } else {
+            HS_TIMER_OFFSET
+        };
+        (LEDC_BASE + offset + self.number() * TIMER_STRIDE) as *mut u32
+    }
+
+    const fn channel_reg(&self, register: usize) -> *mut u32 {
+        let offset = if self.is_low_speed() {
+            LS_CHANNEL_OFFSET
+        } else {
+            HS_CHANNEL_OFFSET
+        };
+        (LEDC_BASE + offset + self.number() * CHANNEL_STRIDE + register) as *mut u32
+    }
+}
+
+/// enables the LEDC peripheral and selects the APB clock for the low speed timers,
+/// the returned driver has to be kept alive while the LEDC buzzers are used
+pub fn init_ledc(ledc: LEDC<'static>) -> Ledc<'static> {
+    let mut ledc = Ledc::new(ledc);
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    ledc
+}
+
+// =============================================================================================
+//                           PIN OWNING LEDC BUZZERS FOR PLAYING NOTES
+// =============================================================================================
+
+static LEDC_VOICES: Mutex<RefCell<LinearMap<SoundKey, LedcBuzzer<'static>, 16>>> =
+    Mutex::new(RefCell::new(LinearMap::new()));
+
+pub struct LedcBuzzer<'a> {
+    _buzzer_pin: Output<'a>,
+    voice: LedcVoice,
+    ends_at: Option<Instant>,
+    period_micros: u16, // of the sounding note, 0 when silent
+    duty_pct: u8,       // TONE_DUTY_PCT or less for a quieter channel
+}
+
+impl<'a> LedcBuzzer<'a> {
+    pub fn new(pin: AnyPin<'a>, voice: LedcVoice) -> Self {
+        assert!(voice.number() < 4); // 4 timers per speed mode
+        let buzzer_pin = Output::new(pin, Level::Low, OutputConfig::default());
+        voice.output_signal().connect_to(&buzzer_pin);
+
+        let mut buzzer = Self {
+            _buzzer_pin: buzzer_pin,
+            voice,
+            ends_at: None,
+            period_micros: 0,
+            duty_pct: TONE_DUTY_PCT,
+        };
+        buzzer.silence();
+        buzzer
+    }
+
+    /// programs the timer for the toggle period used by the bit banged buzzers
+    fn start_tone(&mut self, period_micros: u16) {
+        self.period_micros = period_micros;
+        let Some(timing) = ledc_timing(period_to_millihertz(period_micros)) else {
+            self.silence();
+            return;
+        };
+
+        let mut timer_conf = (timing.divider << TIMER_DIV_NUM_SHIFT)
+            | ((timing.duty_resolution as u32) << TIMER_DUTY_RES_SHIFT);
+        if timing.use_apb_clock {
+            timer_conf |= TIMER_TICK_SEL_APB;
+        }
+        if self.voice.is_low_speed() {
+            timer_conf |= LS_TIMER_PARA_UP;
+        }
+
+        let duty = timing.duty_for_percentage(self.duty_pct) << DUTY_FRACTION_BITS;
+        let mut channel_conf = self.voice.number() as u32 | CH_SIG_OUT_EN;
+        if self.voice.is_low_speed() {
+            channel_conf |= LS_CH_PARA_UP;
+        }
+
+        // the addresses are the ESP32 LEDC registers for this voice, the voice number is
+        // checked to be 0..4 in new() and each buzzer is the only owner of its timer and channel
+        unsafe {
+            self.voice.timer_conf_reg().write_volatile(timer_conf);
+            self.voice.channel_reg(CH_HPOINT).write_volatile(0);
+            self.voice.channel_reg(CH_DUTY).write_volatile(duty);
+            self.voice
+                .channel_reg(CH_CONF1)
+                .write_volatile(CH_DUTY_START);
+            self.voice
+                .channel_reg(CH_CONF0)
+                .write_volatile(channel_conf);
+        }
+    }
+
+    /// stops the output at the low idle level and pauses the timer
+    fn silence(&mut self) {
+        let mut channel_conf = self.voice.number() as u32;
+        let mut timer_conf = TIMER_PAUSE;
+        if self.voice.is_low_speed() {
+            channel_conf |= LS_CH_PARA_UP;
+            timer_conf |= LS_TIMER_PARA_UP;
+        }
+
+        // same registers as in start_tone
+        unsafe {
+            self.voice
+                .channel_reg(CH_CONF0)
+                .write_volatile(channel_conf);
+            self.voice.timer_conf_reg().write_volatile(timer_conf);
+        }
+    }
+}
+
+impl Buzzer for LedcBuzzer<'static> {
+    fn play_note(&mut self, sound_profile: &SoundProfile, key: u7) {
+        self.start_tone(sound_profile.period_for_key(key.as_int()));
+        self.ends_at = sound_profile
+            .duration
+            .map(|duration| Instant::now() + Duration::from_micros(duration.max(0) as u64));
+    }
+
+    fn reset(&mut self) {
+        self.silence();
+        self.ends_at = None;
+        self.period_micros = 0;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.ends_at
+            .is_some_and(|ends_at| Instant::now() >= ends_at)
+    }
+
+    fn set_period(&mut self, period_micros: u16) {
+        self.start_tone(period_micros);
+    }
+
+    fn set_level(&mut self, level: u8) {
+        let duty_pct = level_duty_pct(level, TONE_DUTY_PCT);
+        if duty_pct != self.duty_pct {
+            self.duty_pct = duty_pct;
+            // a silent buzzer takes the duty with its next note
+            if self.period_micros != 0 {
+                self.start_tone(self.period_micros);
+            }
+        }
+    }
+
+    fn with_taken<R>(f: impl FnOnce(&mut LinearMap<SoundKey, Self, 16>) -> R) -> R {
+        critical_section::with(|cs| f(&mut LEDC_VOICES.borrow_ref_mut(cs)))
+    }
+}
@@ -0,0 +1,191 @@
+// =============================================================================================
+//                          LEDC TIMER SETTINGS FOR A REQUESTED FREQUENCY
+// =============================================================================================
+
+// pure calculation without register access, the LEDC buzzer writes the result to the hardware
+//
+// LEDC output frequency = source clock / (divider * 2^duty_resolution)
+// the divider is a 10.8 fixed point number, so 256 = divide by 1.0
+
+pub const APB_CLK_HZ: u32 = 80_000_000;
+pub const REF_TICK_HZ: u32 = 1_000_000;
+
+pub const MIN_DUTY_RESOLUTION: u8 = 1;
+pub const MAX_DUTY_RESOLUTION: u8 = 20;
+
+const DIVIDER_FRACTION_BITS: u32 = 8;
+const MIN_DIVIDER: u64 = 1 << DIVIDER_FRACTION_BITS; // 1.0
+const MAX_DIVIDER: u64 = (1 << 18) - 1; // 18 bit register, ~1023.996
+
+// dividers above this keep the frequency error under 1 / (2 * 16 * 256), ~0.2 cents,
+// the remaining headroom goes to duty resolution for volume control
+const PRECISE_DIVIDER: u64 = 16 << DIVIDER_FRACTION_BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedcTiming {
+    pub use_apb_clock: bool, // true: APB clock, false: REF_TICK
+    pub divider: u32,        // 10.8 fixed point clock divider
+    pub duty_resolution: u8, // bits in the duty counter
+}
+
+impl LedcTiming {
+    pub const fn source_hz(&self) -> u32 {
+        if self.use_apb_clock {
+            APB_CLK_HZ
+        } else {
+            REF_TICK_HZ
+        }
+    }
+
+    /// the frequency the hardware will actually produce, in milli hertz
+    pub const fn actual_millihertz(&self) -> u32 {
+        let numerator = (self.source_hz() as u64 * 1000) << DIVIDER_FRACTION_BITS;
+        let denominator = (self.divider as u64) << self.duty_resolution;
+        ((numerator + denominator / 2) / denominator) as u32
+    }
+
+    /// duty counter value for a duty cycle in percent, 50 % is the loudest square wave
+    pub const fn duty_for_percentage(&self, duty_pct: u8) -> u32 {
+        let duty_pct = if duty_pct > 100 { 100 } else { duty_pct };
+        (((1u64 << self.duty_resolution) * duty_pct as u64) / 100) as u32
+    }
+}
+
+/// picks the clock source, divider and duty resolution for a frequency given in milli hertz,
+/// returns None if the frequency cannot be produced
+pub const fn ledc_timing(millihertz: u32) -> Option<LedcTiming> {
+    if millihertz == 0 {
+        return None;
+    }
+    match timing_for_source(APB_CLK_HZ, millihertz) {
+        Some((divider, duty_resolution)) => Some(LedcTiming {
+            use_apb_clock: true,
+            divider,
+            duty_resolution,
+        }),
+        // too slow for the APB clock even with the largest resolution
+        None => match timing_for_source(REF_TICK_HZ, millihertz) {
+            Some((divider, duty_resolution)) => Some(LedcTiming {
+                use_apb_clock: false,
+                divider,
+                duty_resolution,
+            }),
+            None => None,
+        },
+    }
+}
+
+const fn timing_for_source(source_hz: u32, millihertz: u32) -> Option<(u32, u8)> {
+    let numerator = (source_hz as u64 * 1000) << DIVIDER_FRACTION_BITS;
+
+    // higher resolution means a smaller divider, so walk down from the highest resolution
+    // and take the first one that is precise enough, or the largest divider that still fits
+    let mut best: Option<(u32, u8)> = None;
+    let mut resolution = MAX_DUTY_RESOLUTION;
+    while resolution >= MIN_DUTY_RESOLUTION {
+        let denominator = (millihertz as u64) << resolution;
+        let divider = (numerator + denominator / 2) / denominator;
+
+        if divider > MAX_DIVIDER {
+            break;
+        }
+        if divider >= MIN_DIVIDER {
+            best = Some((divider as u32, resolution));
+            if divider >= PRECISE_DIVIDER {
+                break;
+            }
+        }
+        resolution -= 1;
+    }
+    best
+}
+
+/// toggle period of the bit banged buzzers in micro seconds to a frequency in milli hertz,
+/// one full wave is two toggles
+pub const fn period_to_millihertz(period_micros: u16) -> u32 {
+    if period_micros == 0 {
+        return 0;
+    }
+    (1_000_000_000 / (2 * period_micros as u64)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// equal temperament with A4 = key 69 at 440 Hz
+    fn key_hz(key: u8) -> f64 {
+        440.0 * 2f64.powf((key as f64 - 69.0) / 12.0)
+    }
+
+    fn cents_between(a_hz: f64, b_hz: f64) -> f64 {
+        1200.0 * (a_hz / b_hz).log2()
+    }
+
+    #[test]
+    fn every_midi_key_fits_the_timer_limits() {
+        for key in 0..=127 {
+            let millihertz = (key_hz(key) * 1000.0).round() as u32;
+            let timing =
+                ledc_timing(millihertz).unwrap_or_else(|| panic!("key {key} has no timing"));
+
+            assert!(
+                (MIN_DIVIDER..=MAX_DIVIDER).contains(&(timing.divider as u64)),
+                "key {key}: divider {}",
+                timing.divider
+            );
+            assert!(
+                (MIN_DUTY_RESOLUTION..=MAX_DUTY_RESOLUTION).contains(&timing.duty_resolution),
+                "key {key}: resolution {}",
+                timing.duty_resolution
+            );
+            // the 50 % duty has to be a whole counter value above 0
+            assert!(timing.duty_for_percentage(50) > 0, "key {key}");
+        }
+    }
+
+    #[test]
+    fn every_midi_key_is_within_half_a_cent() {
+        for key in 0..=127 {
+            let ideal_hz = key_hz(key);
+            let timing = ledc_timing((ideal_hz * 1000.0).round() as u32).unwrap();
+            let actual_hz = timing.actual_millihertz() as f64 / 1000.0;
+
+            let cents = cents_between(actual_hz, ideal_hz);
+            assert!(
+                cents.abs() < 0.5,
+                "key {key}: {actual_hz} Hz is {cents} cents off {ideal_hz} Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn precise_dividers_are_taken_when_the_clock_allows() {
+        // above the lowest keys the APB clock always has room for a precise divider
+        for key in 24..=127 {
+            let timing = ledc_timing((key_hz(key) * 1000.0).round() as u32).unwrap();
+            assert!(timing.use_apb_clock, "key {key}");
+            assert!(timing.divider as u64 >= PRECISE_DIVIDER, "key {key}");
+        }
+    }
+
+    #[test]
+    fn silence_has_no_timing() {
+        assert_eq!(ledc_timing(0), None);
+    }
+
+    #[test]
+    fn slow_frequencies_fall_back_to_the_ref_tick() {
+        // 0.01 Hz needs a larger divider than the APB clock has
+        let timing = ledc_timing(10).unwrap();
+        assert!(!timing.use_apb_clock);
+        assert_eq!(timing.actual_millihertz(), 10);
+    }
+
+    #[test]
+    fn toggle_periods_are_half_waves() {
+        assert_eq!(period_to_millihertz(0), 0);
+        assert_eq!(period_to_millihertz(500), 1_000_000);
+        assert_eq!(period_to_millihertz(1136), 440_140);
+    }
+}
@@ -12,16 +12,180 @@
 mod data;
 use data::MIDI_DATA;
 
+mod song_stream;
+use song_stream::SongStream;
+
+// MIDI_DATA precompiled into a song stream by build.rs
+const MIDI_STREAM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/midi_data.song"));
+
+// play the precompiled stream instead of parsing the midi file while playing
+const PLAY_PRECOMPILED: bool = true;
+
 mod sound_profiles;
-use sound_profiles::{INSTRUMENTS, SoundProfile};
+use sound_profiles::{
+    DEFAULT_SOUND, MAX_PERIOD_MICROS, MIN_PERIOD_MICROS, PROGRAM_NAMES, SoundProfile,
+};
+
+mod transpose;
+use transpose::{Key, Transpose};
+
+// semitones the whole song is moved by, the midi_transpose tool suggests one for a song
+const SONG_TRANSPOSE: i8 = 0;
+// and the same for each channel on top of the song transpose
+const CHANNEL_TRANSPOSE: [i8; 16] = [0; 16];
+
+// the key the encoder button moves the song to, it keeps its own key until the button is pressed
+const TARGET_KEY: Key = Key::C_MAJOR;
+// how far the encoder can transpose the song while it plays
+const MAX_LIVE_TRANSPOSE: i8 = 24;
+
+mod playback_clock;
+use playback_clock::{MAX_SPEED_PCT, MIN_SPEED_PCT, NORMAL_SPEED_PCT, PlaybackClock, clamp_speed};
+
+// playback speed steps of the encoder, the song starts at NORMAL_SPEED_PCT
+const SPEED_STEP_PCT: u16 = 5;
+
+mod tempo_map;
+use tempo_map::{BarBeat, TempoMap};
+
+// room for the tempo and time signature changes of a song
+type SongTempoMap = TempoMap<64, 16>;
+
+mod metronome;
+use metronome::Metronome;
+
+// blink the LED on the beats, the first beat of a bar is longer
+const METRONOME: bool = true;
+// also click a buzzer on GPIO 33 on the beats
+const METRONOME_CLICK: bool = false;
+// bars of beats before the song starts
+const COUNT_IN_BARS: u8 = 1;
+
+mod song_check;
+use song_check::{ErrorPolicy, SongError, check_song, ticks_per_quarter};
+
+// what happens to a midi file with broken tracks, the precompiled stream is checked by build.rs
+const ON_SONG_ERROR: ErrorPolicy = ErrorPolicy::SkipTrack;
+
+mod track_merger;
+use track_merger::{MergedEvent, TrackMerger};
+
+// tracks of a midi file played together, the precompiled stream has no limit
+const MAX_TRACKS: usize = 32;
+
+// the one pattern of a format 2 file to play when the midi file is parsed while playing,
+// None plays all of them one after another like the precompiled stream does
+const FORMAT_2_PATTERN: Option<usize> = None;
+
+mod midi_reader;
+use midi_reader::{MidiReader, SongSource};
+
+// bytes of every track kept in memory while a song is read a piece at a time
+const TRACK_BUFFER: usize = 64;
+
+type SongReader<S> = MidiReader<S, MAX_TRACKS, TRACK_BUFFER>;
+
+mod block_device;
+mod fat;
+mod sd_card;
+use sd_card::SdCard;
+
+mod song_library;
+use song_library::SongLibrary;
+
+// read songs from an SD card on SPI2: SCK GPIO 21, MOSI GPIO 22, MISO GPIO 32, CS GPIO 15
+const SD_CARD: bool = false;
+// midi files on the card that fit in the playlist
+const MAX_LIBRARY_SONGS: usize = 64;
+
+type CardLibrary = SongLibrary<SdCard<'static>, MAX_LIBRARY_SONGS>;
+
+mod settings;
+use settings::{MAX_PROFILE_TWEAKS, ProfileTweak, Settings, SettingsStore, program_sound};
+
+mod settings_flash;
+use settings_flash::SettingsPartition;
+
+mod profile_bank;
+use profile_bank::{BankEntry, BankReader};
+
+mod profile_tuner;
+use profile_tuner::ProfileTuner;
+
+// the longest line of PROFILES.TXT on the SD card
+const BANK_LINE_LEN: usize = 128;
+
+// the tuned volume is saved once the encoder has been left alone for this long
+const SETTINGS_SAVE_DELAY_MICROS: u64 = 3_000_000;
+
+type FlashSettings = SettingsStore<SettingsPartition<'static>>;
+
+mod tone_clock;
+use tone_clock::{FULL_DUTY_PCT, TICK_MICROS, ToneClock};
+
+mod voice_queue;
+use voice_queue::{ChannelControl, VoiceQueue, VoiceReceiver, VoiceSink, split_voice_queue};
+
+mod vibrato;
+use vibrato::VibratoLfo;
+
+mod glide;
+use glide::Glide;
+
+mod arpeggio;
+use arpeggio::{ArpeggioOrder, Chord};
+
+// a chord on a channel without enough buzzers takes turns on the buzzer the channel has,
+// otherwise the notes that don't get a buzzer are dropped
+const ARPEGGIATE_CHORDS: bool = true;
+// turns per second and the order the notes take them in
+const ARPEGGIO_RATE_HZ: u16 = 30;
+const ARPEGGIO_ORDER: ArpeggioOrder = ArpeggioOrder::Up;
+// notes of one chord sharing a buzzer
+const CHORD_NOTES: usize = 8;
+
+mod mixer;
+use mixer::{CommandReader, Mixer, MixerCommand, level_duty_pct};
+
+mod channel_mode;
+use channel_mode::{CHANNEL_START, ChannelState, ControlEffect, channel_control};
+
+// mute and solo channels from the serial console while the song plays, the console receives
+// on GPIO 3 and takes the pin of the eighth buzzer, so it's off unless it's turned on here
+const MIXER_CONSOLE: bool = false;
+// the longest console command
+const CONSOLE_LINE_LEN: usize = 32;
+
+#[cfg(feature = "ledc")]
+mod ledc_timing;
+
+#[cfg(feature = "ledc")]
+mod ledc_buzzer;
+#[cfg(feature = "ledc")]
+use ledc_buzzer::{LedcBuzzer, LedcVoice, init_ledc};
+
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicI8, AtomicU16, Ordering},
+};
+use critical_section::Mutex;
 
 use esp_backtrace as _;
 
 use esp_hal::{
+    Blocking,
     analog::dac::Dac,
     clock::CpuClock,
-    gpio::{AnyPin, Input, InputConfig, Level, Output, OutputConfig, Pin, Pull},
+    gpio::{AnyPin, Event, Input, InputConfig, Io, Level, Output, OutputConfig, Pin, Pull},
+    handler,
+    interrupt::Priority,
     main,
+    peripherals::{FLASH, GPIO3, UART0},
+    spi::master::{Config as SpiConfig, Spi},
+    system::{CpuControl, Stack},
+    time::{Duration, Instant},
+    timer::{PeriodicTimer, timg::TimerGroup},
+    uart::{Config as UartConfig, UartRx},
 };
 
 use esp_println::println;
@@ -30,7 +194,7 @@ use log::info;
 use heapless::{Deque, LinearMap, Vec};
 
 use midly::{
-    EventIter, Header, MetaMessage, MidiMessage, Timing, TrackEventKind,
+    Format, Header, MetaMessage, MidiMessage, TrackEventKind,
     num::{u4, u7},
     parse,
 };
@@ -59,6 +223,12 @@ pub fn delay_cycles(cycles: u32) {
     }
 }
 
+/// micro seconds since boot
+#[inline(always)]
+fn now_micros() -> u64 {
+    Instant::now().duration_since_epoch().as_micros()
+}
+
 #[inline(always)]
 fn read_ccount() -> u32 {
     let count: u32;
@@ -68,6 +238,167 @@ fn read_ccount() -> u32 {
     count
 }
 
+// =============================================================================================
+//                              TIMER INTERRUPT FOR TONE TOGGLING
+// =============================================================================================
+
+// the pins are toggled from a periodic timer interrupt with a fixed tick,
+// so the main context is free to parse the song without affecting the pitch
+
+type VoiceMap = LinearMap<SoundKey, SoundBuzzer<'static>, 16>;
+
+static ACTIVE_VOICES: Mutex<RefCell<VoiceMap>> = Mutex::new(RefCell::new(LinearMap::new()));
+static TONE_TIMER: Mutex<RefCell<Option<PeriodicTimer<'static, Blocking>>>> =
+    Mutex::new(RefCell::new(None));
+
+#[handler(priority = Priority::Priority3)]
+fn tone_tick() {
+    critical_section::with(|cs| {
+        if let Some(timer) = TONE_TIMER.borrow_ref_mut(cs).as_mut() {
+            timer.clear_interrupt();
+        }
+        for buzzer in ACTIVE_VOICES.borrow_ref_mut(cs).values_mut() {
+            buzzer.update(TICK_MICROS);
+        }
+    });
+}
+
+fn start_tone_timer(mut timer: PeriodicTimer<'static, Blocking>) {
+    timer.set_interrupt_handler(tone_tick);
+    timer.listen();
+
+    // started inside the critical section so the first interrupt always finds the timer
+    critical_section::with(|cs| {
+        timer
+            .start(Duration::from_micros(TICK_MICROS as u64))
+            .expect("valid tone tick period");
+        TONE_TIMER.borrow_ref_mut(cs).replace(timer);
+    });
+}
+
+/// gives the main context access to the voices played by the timer interrupt
+#[inline(always)]
+fn with_voices<R>(f: impl FnOnce(&mut VoiceMap) -> R) -> R {
+    critical_section::with(|cs| f(&mut ACTIVE_VOICES.borrow_ref_mut(cs)))
+}
+
+// =============================================================================================
+//                         ENCODER INTERRUPT FOR LIVE CONTROLS
+// =============================================================================================
+
+// the main context is busy playing the song, so the encoder is read from the gpio interrupt
+// and the song player picks up the changes when the next note starts
+//   turning: transposes the song a semitone at a time
+//   turning with the button held down: changes the playback speed
+//   button: moves the song to TARGET_KEY and back
+
+static ENCODER: Mutex<RefCell<Option<Encoder>>> = Mutex::new(RefCell::new(None));
+
+static LIVE_TRANSPOSE: AtomicI8 = AtomicI8::new(0);
+static TRANSPOSE_TO_KEY: AtomicBool = AtomicBool::new(false);
+static PLAYBACK_SPEED_PCT: AtomicU16 = AtomicU16::new(NORMAL_SPEED_PCT);
+
+#[handler]
+fn encoder_changed() {
+    critical_section::with(|cs| {
+        let mut encoder = ENCODER.borrow_ref_mut(cs);
+        let Some(encoder) = encoder.as_mut() else {
+            return;
+        };
+        encoder.clear_interrupts();
+
+        // only written here, so load + store doesn't lose updates
+        let input = encoder.poll();
+        let semitones = LIVE_TRANSPOSE.load(Ordering::Relaxed);
+        let speed_pct = PLAYBACK_SPEED_PCT.load(Ordering::Relaxed);
+        match (input.rotation, input.held) {
+            (Some(Rotation::Left), false) => {
+                LIVE_TRANSPOSE.store((semitones - 1).max(-MAX_LIVE_TRANSPOSE), Ordering::Relaxed)
+            }
+            (Some(Rotation::Right), false) => {
+                LIVE_TRANSPOSE.store((semitones + 1).min(MAX_LIVE_TRANSPOSE), Ordering::Relaxed)
+            }
+            (Some(Rotation::Left), true) => PLAYBACK_SPEED_PCT.store(
+                speed_pct.saturating_sub(SPEED_STEP_PCT).max(MIN_SPEED_PCT),
+                Ordering::Relaxed,
+            ),
+            (Some(Rotation::Right), true) => PLAYBACK_SPEED_PCT.store(
+                (speed_pct + SPEED_STEP_PCT).min(MAX_SPEED_PCT),
+                Ordering::Relaxed,
+            ),
+            (None, _) => {}
+        }
+        if input.released {
+            let to_key = TRANSPOSE_TO_KEY.load(Ordering::Relaxed);
+            TRANSPOSE_TO_KEY.store(!to_key, Ordering::Relaxed);
+        }
+    });
+}
+
+/// hands the encoder to the interrupt for the live controls
+fn start_live_controls(io: &mut Io<'_>, mut encoder: Encoder) {
+    io.set_interrupt_handler(encoder_changed);
+    critical_section::with(|cs| {
+        encoder.listen();
+        ENCODER.borrow_ref_mut(cs).replace(encoder);
+    });
+}
+
+/// takes the encoder back from the interrupt
+fn stop_live_controls() -> Encoder {
+    let mut encoder = critical_section::with(|cs| ENCODER.borrow_ref_mut(cs).take())
+        .expect("live controls were started");
+    encoder.unlisten();
+    encoder
+}
+
+// =============================================================================================
+//                              SERIAL CONSOLE FOR THE MIXER
+// =============================================================================================
+
+// the commands are typed into the same serial monitor the prints show up in,
+// the song player reads them while it waits for the next event
+
+struct MixerConsole {
+    rx: UartRx<'static, Blocking>,
+    reader: CommandReader<CONSOLE_LINE_LEN>,
+}
+
+impl MixerConsole {
+    /// only receives on UART0, the prints keep going out on its transmit pin
+    fn new(uart: UART0<'static>, rx_pin: GPIO3<'static>) -> Option<Self> {
+        let rx = match UartRx::new(uart, UartConfig::default()) {
+            Ok(rx) => rx.with_rx(rx_pin),
+            Err(err) => {
+                println!("no mixer console: {:?}", err);
+                return None;
+            }
+        };
+        println!("mixer console: mute, unmute, solo or unsolo a channel 0 - 15, or reset");
+        Some(MixerConsole {
+            rx,
+            reader: CommandReader::new(),
+        })
+    }
+
+    /// the next typed command, doesn't wait for one
+    fn poll(&mut self) -> Option<MixerCommand> {
+        let mut byte = [0];
+        while self.rx.read_ready() {
+            if let Err(err) = self.rx.read(&mut byte) {
+                println!("mixer console: {:?}", err);
+                return None;
+            }
+            match self.reader.feed(byte[0]) {
+                Some(Ok(command)) => return Some(command),
+                Some(Err(err)) => println!("mixer console: {}", err),
+                None => {}
+            }
+        }
+        None
+    }
+}
+
 // =============================================================================================
 //                                      SONG METADATA
 // =============================================================================================
@@ -82,19 +413,15 @@ struct SongMetaData {
 }
 
 impl SongMetaData {
-    fn new(header: Header) -> Self {
-        let timing = match header.timing {
-            Timing::Metrical(metric_timing) => metric_timing.as_int(),
-            Timing::Timecode(_, _) => unimplemented!(),
-        };
-
-        Self {
-            ticks_per_quarter: timing,
+    /// songs timed in SMPTE frames are refused, the player only counts ticks per quarter
+    fn new(header: Header) -> Result<Self, SongError> {
+        Ok(Self {
+            ticks_per_quarter: ticks_per_quarter(&header)?,
             tempo: 500_000,                // default tempo
             _bpm: 120,                     // default BPM
             time_signature: [4, 4, 24, 8], // default: 4/4
             key: (0, false),               // default: C major
-        }
+        })
     }
 
     fn refresh_bpm(&mut self, tempo: u32) {
@@ -103,196 +430,366 @@ impl SongMetaData {
     }
 }
 
+/// how far the song has played
+struct SongPosition {
+    metadata: SongMetaData,
+    micros: u64, // of the last played event
+}
+
+impl SongPosition {
+    fn new(header: Header) -> Result<Self, SongError> {
+        Ok(SongPosition {
+            metadata: SongMetaData::new(header)?,
+            micros: 0,
+        })
+    }
+}
+
+/// what the check found in the tracks and what the error policy makes of it, the tempo map
+/// and the player both go by it so the patterns of a format 2 file line up
+struct SongPlan {
+    sequential: bool,
+    pattern: Option<usize>,
+    broken_tracks: Vec<usize, MAX_TRACKS>,
+    skip_broken: bool,
+    stop_at: Option<SongError>,
+}
+
+impl SongPlan {
+    /// checks a whole midi file, `pattern` picks the one pattern of a format 2 file to play
+    fn for_midi(
+        midi: &[u8],
+        policy: ErrorPolicy,
+        pattern: Option<usize>,
+    ) -> Result<Self, SongError> {
+        let (header, _) = parse(midi)?;
+        let sequential = header.format == Format::Sequential;
+        Self::check(policy, sequential, pattern, |broken_track| {
+            check_song(midi, broken_track)
+        })
+    }
+
+    /// like for_midi, for a song read a piece at a time
+    fn for_reader<S: SongSource>(
+        reader: &mut SongReader<S>,
+        policy: ErrorPolicy,
+        pattern: Option<usize>,
+    ) -> Result<Self, SongError> {
+        let sequential = reader.header().format == Format::Sequential;
+        Self::check(policy, sequential, pattern, |broken_track| {
+            Ok(reader.check(broken_track))
+        })
+    }
+
+    /// runs the check before anything plays, a song the policy doesn't play at all is an error
+    fn check(
+        policy: ErrorPolicy,
+        sequential: bool,
+        pattern: Option<usize>,
+        check: impl FnOnce(&mut dyn FnMut(SongError)) -> Result<usize, SongError>,
+    ) -> Result<Self, SongError> {
+        let selected = |track: usize| !sequential || pattern.is_none_or(|pattern| pattern == track);
+        let mut broken_tracks: Vec<usize, MAX_TRACKS> = Vec::new();
+        let mut first_break: Option<SongError> = None;
+        check(&mut |err| {
+            if err.track().is_some_and(|track| !selected(track)) {
+                return;
+            }
+            println!("{}", err);
+            if let Some(track) = err.track() {
+                let _ = broken_tracks.push(track);
+            }
+            // the checked tracks come in file order, which is also the playing order of patterns
+            if first_break
+                .as_ref()
+                .is_none_or(|first| !sequential && err.playable_ticks() < first.playable_ticks())
+            {
+                first_break = Some(err);
+            }
+        })?;
+
+        let mut stop_at = None;
+        if let Some(err) = first_break {
+            match (policy, &err) {
+                (ErrorPolicy::SkipTrack, _) => {}
+                (ErrorPolicy::Stop, SongError::Event { .. }) => stop_at = Some(err),
+                // a missing or unreadable track breaks the song from the start
+                _ => return Err(err),
+            }
+        }
+        Ok(SongPlan {
+            sequential,
+            pattern,
+            broken_tracks,
+            skip_broken: policy == ErrorPolicy::SkipTrack,
+            stop_at,
+        })
+    }
+
+    /// the patterns of a format 2 file play one after another, or only the selected one,
+    /// and the broken tracks are left out when the policy skips them
+    fn plays_track(&self, track: usize) -> bool {
+        let selected = !self.sequential || self.pattern.is_none_or(|pattern| pattern == track);
+        selected && (!self.skip_broken || !self.broken_tracks.contains(&track))
+    }
+
+    /// the song played to the end, but the break can be at the very end of it
+    fn finish(self) -> Result<(), SongError> {
+        match self.stop_at {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
 // =============================================================================================
 //                                      SONG PLAYER
 // =============================================================================================
 
-struct SongPlayer<'a> {
+// the sequencer half, decides what is played and when, the voices are played by the sink
+
+struct SongPlayer<V: VoiceSink<SoundKey, SoundProfile>> {
     instrument_sounds: [SoundProfile; 16],
-    free_buzzers: Deque<SoundBuzzer<'a>, 16>,
-    taken_buzzers: LinearMap<SoundKey, SoundBuzzer<'a>, 16>,
+    transpose: Transpose,
+    song_key: Key,
+    clock: PlaybackClock,
+    bar: u32, // the bar being played, for printing the progress
+    metronome: Metronome,
+    moved_keys: LinearMap<SoundKey, u7, 16>, // sounding notes that play a different key
+    profile_tweaks: Vec<ProfileTweak, MAX_PROFILE_TWEAKS>, // saved sounds used for the programs
+    mixer: Mixer,                            // the channels that are heard
+    console: Option<MixerConsole>,
+    voices: V,
 }
 
-impl<'a> SongPlayer<'a> {
-    fn new(buzzers: Deque<SoundBuzzer<'a>, 16>) -> Self {
+impl<V: VoiceSink<SoundKey, SoundProfile>> SongPlayer<V> {
+    fn new(voices: V, metronome: Metronome) -> Self {
         SongPlayer {
-            instrument_sounds: [SoundProfile {
-                wait_time: 3800,
-                duration: None,
-                wait_change_per_key: 50,
-            }; 16],
-            free_buzzers: buzzers,
-            taken_buzzers: LinearMap::new(),
+            instrument_sounds: [DEFAULT_SOUND; 16],
+            transpose: Transpose {
+                channel_semitones: CHANNEL_TRANSPOSE,
+                ..Transpose::new(SONG_TRANSPOSE)
+            },
+            song_key: Key::C_MAJOR,
+            clock: PlaybackClock::new(NORMAL_SPEED_PCT),
+            bar: 0,
+            metronome,
+            moved_keys: LinearMap::new(),
+            profile_tweaks: Vec::new(),
+            mixer: Mixer::new(),
+            console: None,
+            voices,
         }
     }
 
     fn reset(&mut self) {
-        let mut keys = Deque::<SoundKey, 16>::new();
+        self.metronome.stop();
+        self.moved_keys.clear();
+        self.voices.all_off();
+    }
 
-        for key in self.taken_buzzers.keys() {
-            if keys.push_back(*key).is_err() {
-                break;
-            }
-        }
-        while let Some(key) = keys.pop_front() {
-            if let Some(mut taken_buzzer) = self.taken_buzzers.remove(&key) {
-                taken_buzzer.reset();
-                let _ = self.free_buzzers.push_back(taken_buzzer);
-            }
-        }
+    /// starts the clock and prints how long the song is
+    fn start_song(&mut self, tempo_map: &SongTempoMap) {
+        let length_micros = tempo_map.length_micros();
+        println!(
+            "song length: {}.{:03}s, {} bars",
+            length_micros / 1_000_000,
+            length_micros / 1000 % 1000,
+            tempo_map
+                .tick_to_bar_beat(tempo_map.length_ticks().saturating_sub(1))
+                .bar
+        );
+        self.bar = 0;
+
+        self.update_playback_speed();
+        self.metronome
+            .count_in(tempo_map, self.clock.speed_pct(), COUNT_IN_BARS);
+        self.clock.start(now_micros());
+        self.metronome.start();
     }
 
-    #[inline(always)]
-    fn play_buzzers(&mut self) {
-        for buzzer in self.taken_buzzers.values_mut() {
-            buzzer.update();
+    fn show_position(&mut self, position: BarBeat) {
+        if position.bar != self.bar {
+            self.bar = position.bar;
+            println!("bar {}", position.bar);
         }
     }
 
-    fn free_buzzers(&mut self) {
-        let mut freed_keys = Deque::<SoundKey, 16>::new();
+    /// waits for the song time, scaled by the playback speed, the timer interrupt plays the buzzers
+    fn wait_micros(&mut self, micros: u64, tempo_map: &SongTempoMap) {
+        self.update_playback_speed();
+        let deadline = self.clock.advance(micros);
 
-        for key in self
-            .taken_buzzers
-            .iter()
-            .filter(|(_, buzzer)| buzzer.max_period < 0)
-            .map(|(key, _)| key)
-        {
-            if freed_keys.push_back(*key).is_err() {
+        self.voices.refresh();
+        loop {
+            let now = now_micros();
+            if now >= deadline {
                 break;
             }
+            self.metronome.update(now, tempo_map, &self.clock);
+            self.update_mixer();
         }
-        while let Some(key) = freed_keys.pop_front() {
-            if let Some(mut taken_buzzer) = self.taken_buzzers.remove(&key) {
-                taken_buzzer.reset();
-                let _ = self.free_buzzers.push_back(taken_buzzer);
+    }
+
+    /// takes the commands typed on the console, the channels that go silent stop their notes
+    fn update_mixer(&mut self) {
+        let Some(command) = self.console.as_mut().and_then(MixerConsole::poll) else {
+            return;
+        };
+        let before = self.mixer;
+        self.mixer.apply(command);
+        println!("mixer: {}", self.mixer);
+
+        for channel in (0..16).map(u4::new) {
+            if before.plays(channel) && !self.mixer.plays(channel) {
+                self.voices
+                    .control(channel.as_int(), ChannelControl::NotesOff);
+                self.forget_moved_keys(channel);
             }
         }
     }
 
-    const fn delta_to_micros(delta_ticks: u16, meta_data: &SongMetaData) -> u64 {
-        (delta_ticks as u64 * meta_data.tempo as u64) / meta_data.ticks_per_quarter as u64
+    /// takes the speed set with the encoder, the time already played is not affected
+    fn update_playback_speed(&mut self) {
+        let speed_pct = PLAYBACK_SPEED_PCT.load(Ordering::Relaxed);
+        if speed_pct != self.clock.speed_pct() {
+            self.clock.set_speed(speed_pct);
+            println!("playback speed: {}%", self.clock.speed_pct());
+        }
     }
 
-    fn play_song(&mut self, midi_track: &[u8]) {
-        // ------------------- parse the track -------------------
+    /// plays a song precompiled by build.rs, the tempo map is already applied to the timestamps
+    /// and is only needed for showing the bars
+    fn play_stream(&mut self, stream: &SongStream<'_>, tempo_map: &SongTempoMap) {
+        let (sharps, minor) = stream.key_signature();
+        self.song_key = Key::new(sharps, minor);
+        self.start_song(tempo_map);
+
+        let mut last_micros = 0;
+        for event in stream.iter() {
+            self.wait_micros(event.micros.saturating_sub(last_micros) as u64, tempo_map);
+            last_micros = event.micros;
+
+            let tick = tempo_map.micros_to_tick(event.micros as u64);
+            self.show_position(tempo_map.tick_to_bar_beat(tick));
+
+            self.match_midi_message(event.channel, event.message);
+        }
+        // the song can end with a rest after the last note off
+        self.wait_micros(
+            stream.length_micros().saturating_sub(last_micros) as u64,
+            tempo_map,
+        );
+        self.reset();
+    }
 
-        let (header, track_iter) = parse(midi_track).expect("valid midi track");
-        let mut metadata = SongMetaData::new(header);
+    /// plays a song the plan was made for, with the tempo map of the tracks the plan plays
+    fn play_song(
+        &mut self,
+        midi_track: &[u8],
+        tempo_map: &SongTempoMap,
+        plan: SongPlan,
+    ) -> Result<(), SongError> {
+        // ------------------- parse the track -------------------
 
-        let mut all_tracks: [Option<(u16, TrackEventKind<'_>)>; 16] = [None; 16];
+        let (header, track_iter) = parse(midi_track)?;
 
         // todo: take while delta = 0 from first track, see if there are meta info there, maybe
-        let mut tracks: Vec<EventIter<'_>, 16> = track_iter.flatten().collect();
+        // the check already reported the tracks that can't be read
+        let tracks = track_iter
+            .enumerate()
+            .filter(|(i, _)| plan.plays_track(*i))
+            .filter_map(|(i, track)| Some((i, track.ok()?)));
+        let merger = if plan.sequential {
+            TrackMerger::<MAX_TRACKS>::sequential(tracks)?
+        } else {
+            TrackMerger::<MAX_TRACKS>::new(tracks)?
+        };
 
-        let mut track_count = 0;
-        for (i, t) in tracks.iter_mut().enumerate() {
-            if let Some(Ok(first_event)) = t.next() {
-                track_count += 1;
-                all_tracks[i] = Some((first_event.delta.as_int() as u16, first_event.kind));
-            }
+        // ------------------- play all the track events in order -------------------
+
+        let mut position = SongPosition::new(header)?;
+        self.start_song(tempo_map);
+        for event in merger {
+            self.play_event(event, &plan, &mut position, tempo_map)?;
         }
+        self.reset();
+        plan.finish()
+    }
 
-        let playable_tracks = &mut all_tracks[..track_count];
+    /// plays a song read a piece at a time, like play_song does with the whole file
+    fn play_streamed_song<S: SongSource>(
+        &mut self,
+        reader: &mut SongReader<S>,
+        tempo_map: &SongTempoMap,
+        plan: SongPlan,
+    ) -> Result<(), SongError> {
+        reader.start_merge(|track| plan.plays_track(track));
+
+        let mut position = SongPosition::new(reader.header())?;
+        self.start_song(tempo_map);
+        while let Some(event) = reader.next_merged() {
+            self.play_event(event, &plan, &mut position, tempo_map)?;
+        }
+        self.reset();
+        plan.finish()
+    }
 
-        // ------------------- play all the track events in order -------------------
-        while playable_tracks.iter().any(|x| x.is_some()) {
-            // pick the next event with the lowest delta
-            let next_track_idx = Self::find_min_index(playable_tracks, &track_count);
-            let (delay, event_kind) = playable_tracks[next_track_idx].expect("cannot fail");
-
-            // ------------------- apply the delay to each of the items -------------------
-            if delay != 0 {
-                playable_tracks
-                    .iter_mut()
-                    .filter_map(|event| event.as_mut())
-                    .for_each(|inner_event| inner_event.0 -= delay);
-            }
-
-            // ------------------- replace the picked next event -------------------
-            match tracks[next_track_idx].next() {
-                Some(next_event) => {
-                    let replacing_event = next_event.expect("invalid track event");
-                    playable_tracks[next_track_idx] =
-                        Some((replacing_event.delta.as_int() as u16, replacing_event.kind));
-                }
-                None => playable_tracks[next_track_idx] = None,
-            }
-            // println!(
-            //     "delay: {:?}, tracknum: {:?}, event: {:?}",
-            //     delay, next_track_idx, event_kind
-            // );
-
-            // ------------------- Wait until the delay is gone and play buzzers -------------------
-            let arbitrary_len = 15.0;
-
-            let mut delta_time =
-                (Self::delta_to_micros(delay, &metadata) as f32 * arbitrary_len) as i64;
-            println!("{}", delta_time);
-            if self.taken_buzzers.is_empty() {
-                while delta_time > 0 {
-                    delta_time -= 25;
-                    delay_cycles(290);
-                }
+    /// waits until the event is due and plays it, an error once the song is past a break
+    /// it has to stop at
+    fn play_event(
+        &mut self,
+        MergedEvent {
+            tick: song_tick,
+            track,
+            kind,
+        }: MergedEvent,
+        plan: &SongPlan,
+        position: &mut SongPosition,
+        tempo_map: &SongTempoMap,
+    ) -> Result<(), SongError> {
+        // ------------------- Wait until the delay is gone, the timer interrupt plays the buzzers -------------------
+        if let Some(SongError::Event {
+            track: broken_track,
+            tick,
+        }) = &plan.stop_at
+        {
+            // a broken pattern is over once the next one starts
+            let past_break = if plan.sequential {
+                track > *broken_track
             } else {
-                while delta_time > 0 {
-                    self.play_buzzers();
-                    delay_cycles(870);
-                    delta_time -= 100;
-                }
+                song_tick > *tick
+            };
+            if past_break {
+                self.reset();
+                return Err(SongError::Event {
+                    track: *broken_track,
+                    tick: *tick,
+                });
             }
+        }
+        // the waits come from the tempo map at the tick of each event so the rounding doesn't add up
+        let event_micros = tempo_map.tick_to_micros(song_tick);
+        self.wait_micros(event_micros - position.micros, tempo_map);
+        position.micros = event_micros;
+        self.show_position(tempo_map.tick_to_bar_beat(song_tick));
 
-            // ------------------- handle the current event -------------------
+        // ------------------- handle the current event -------------------
 
-            self.match_music_events(&mut metadata, event_kind);
-        }
-        self.reset();
+        self.match_music_events(&mut position.metadata, kind);
+        Ok(())
     }
 
     fn match_music_events(&mut self, metadata: &mut SongMetaData, event_kind: TrackEventKind) {
         match event_kind {
-            TrackEventKind::Midi { channel, message } => match message {
-                MidiMessage::NoteOff { key, vel } => {
-                    println!("taken buzzers len: {}", self.taken_buzzers.len());
-                    if let Some(mut free_buzzer) = self.taken_buzzers.remove(&(channel, key)) {
-                        println!("buzzer removed");
-                        free_buzzer.reset();
-                        let _ = self.free_buzzers.push_back(free_buzzer);
-                    }
-                }
-                MidiMessage::NoteOn { key, vel } => {
-                    if let Some(mut free_buzzer) = self.free_buzzers.pop_front() {
-                        //println!("temp change, instrument is whatever, change this back");
-                        //let note_to_play: &SoundProfile = &INSTRUMENTS[8];
-                        let note_to_play: &SoundProfile =
-                            &self.instrument_sounds[channel.as_int() as usize];
-                        free_buzzer.play_note(note_to_play, key);
-                        let _ = self.taken_buzzers.insert((channel, key), free_buzzer);
-                    } else {
-                        println!("no free buzzers")
-                    }
-                }
-                MidiMessage::ProgramChange { program } => {
-                    // gets the instrument index for the channel
-                    self.instrument_sounds[channel.as_int() as usize] =
-                        INSTRUMENTS[program.as_int() as usize]
-                }
-                MidiMessage::Aftertouch { key, vel } => {
-                    println!("not implemented: midi aftertouch")
-                }
-                MidiMessage::Controller { controller, value } => {
-                    println!("not implemented: midi controller")
-                }
-
-                MidiMessage::ChannelAftertouch { vel } => {
-                    println!("not implemented: midi channel aftertouch")
-                }
-                MidiMessage::PitchBend { bend } => println!("not implemented: midi pitch bend"),
-            },
+            TrackEventKind::Midi { channel, message } => self.match_midi_message(channel, message),
             TrackEventKind::Meta(meta_message) => match meta_message {
                 MetaMessage::Tempo(tempo) => metadata.tempo = tempo.as_int(),
                 MetaMessage::TimeSignature(a, b, c, d) => metadata.time_signature = [a, b, c, d],
-                MetaMessage::KeySignature(key, sharp) => metadata.key = (key, sharp),
+                MetaMessage::KeySignature(key, sharp) => {
+                    metadata.key = (key, sharp);
+                    self.song_key = Key::new(key, sharp);
+                }
                 MetaMessage::EndOfTrack => println!("End of track"),
 
                 MetaMessage::InstrumentName(bytes) => println!("not implemented: name"),
@@ -308,22 +805,415 @@ impl<'a> SongPlayer<'a> {
         }
     }
 
-    #[inline(always)]
-    fn find_min_index(list: &[Option<(u16, TrackEventKind<'_>)>], track_count: &usize) -> usize {
-        // find the index with the lowest u16
-        let mut min_index = 0;
-        let mut min_delta: u16 = u16::MAX;
-        for (i, track_item) in list[..*track_count].iter().enumerate() {
-            if let Some((delta, _)) = track_item {
-                if delta == &0 {
-                    return i;
-                } else if *delta < min_delta {
-                    min_index = i;
-                    min_delta = *delta;
+    fn match_midi_message(&mut self, channel: u4, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOff { key, vel } => {
+                let played_key = self.moved_keys.remove(&(channel, key)).unwrap_or(key);
+                self.voices.note_off((channel, played_key));
+            }
+            MidiMessage::NoteOn { key, vel } => {
+                if !self.mixer.plays(channel) {
+                    return;
+                }
+                //println!("temp change, instrument is whatever, change this back");
+                //let note_to_play = INSTRUMENTS[8];
+                let note_to_play = self.instrument_sounds[channel.as_int() as usize];
+                self.update_live_transpose();
+                let played_key = self.transpose.key_for(channel, key, &note_to_play);
+                self.remember_played_key((channel, key), played_key);
+                self.voices.note_on((channel, played_key), note_to_play);
+            }
+            MidiMessage::ProgramChange { program } => {
+                // gets the instrument index for the channel
+                self.instrument_sounds[channel.as_int() as usize] =
+                    program_sound(&self.profile_tweaks, program.as_int())
+            }
+            MidiMessage::Aftertouch { key, vel } => {
+                println!("not implemented: midi aftertouch")
+            }
+            MidiMessage::Controller { controller, value } => {
+                let Some(control) = channel_control(controller, value) else {
+                    println!("not implemented: midi controller");
+                    return;
+                };
+                self.voices.control(channel.as_int(), control);
+                if control.stops_notes() {
+                    self.forget_moved_keys(channel);
                 }
             }
+
+            MidiMessage::ChannelAftertouch { vel } => {
+                println!("not implemented: midi channel aftertouch")
+            }
+            MidiMessage::PitchBend { bend } => println!("not implemented: midi pitch bend"),
+        }
+    }
+
+    /// takes the transpose set with the encoder, the sounding notes keep their keys
+    fn update_live_transpose(&mut self) {
+        let live_semitones = LIVE_TRANSPOSE.load(Ordering::Relaxed);
+        let key_semitones = if TRANSPOSE_TO_KEY.load(Ordering::Relaxed) {
+            self.song_key.semitones_to(TARGET_KEY)
+        } else {
+            0
+        };
+
+        if (live_semitones, key_semitones)
+            != (self.transpose.live_semitones, self.transpose.key_semitones)
+        {
+            self.transpose.live_semitones = live_semitones;
+            self.transpose.key_semitones = key_semitones;
+            println!(
+                "transpose: {} semitones, {} to the target key",
+                live_semitones, key_semitones
+            );
+        }
+    }
+
+    /// the notes of the channel were stopped, their note offs have nothing left to stop
+    fn forget_moved_keys(&mut self, channel: u4) {
+        self.moved_keys
+            .retain(|(moved_channel, _), _| *moved_channel != channel);
+    }
+
+    /// the note off has to stop the key that was played, even if the transpose changes meanwhile
+    fn remember_played_key(&mut self, sound_key: SoundKey, played_key: u7) {
+        let previous_key = if played_key == sound_key.1 {
+            self.moved_keys.remove(&sound_key)
+        } else {
+            match self.moved_keys.insert(sound_key, played_key) {
+                Ok(previous_key) => previous_key,
+                Err(_) => {
+                    println!("too many moved notes");
+                    None
+                }
+            }
+        };
+
+        // the same note again, but moved somewhere else, so the old key has to stop
+        match previous_key {
+            Some(previous_key) if previous_key != played_key => {
+                self.voices.note_off((sound_key.0, previous_key))
+            }
+            _ => {}
+        }
+    }
+}
+
+// =============================================================================================
+//                                  BUZZER BANK FOR THE VOICES
+// =============================================================================================
+
+// the synthesis half, hands out free buzzers for notes and takes them back when the notes end
+// the taken buzzers live wherever the buzzer backend keeps them, see Buzzer::with_taken
+// the glides and vibratos of the notes are kept here and move the periods of the taken buzzers
+
+/// how the period of a taken buzzer moves
+#[derive(Debug, Clone)]
+struct VoicePitch {
+    glide: Glide,
+    chord: Option<Chord<CHORD_NOTES>>, // the notes taking turns on the buzzer instead
+    vibrato: VibratoLfo,
+    playing: u16, // the period the buzzer plays
+}
+
+struct BuzzerBank<B: Buzzer> {
+    free_buzzers: Deque<B, 16>,
+    pitches: LinearMap<SoundKey, VoicePitch, 16>,
+    channels: [ChannelState; 16],
+    next_pitch_update_at: u64,
+}
+
+impl<B: Buzzer> BuzzerBank<B> {
+    fn new(buzzers: Deque<B, 16>) -> Self {
+        BuzzerBank {
+            free_buzzers: buzzers,
+            pitches: LinearMap::new(),
+            channels: [CHANNEL_START; 16],
+            next_pitch_update_at: 0,
+        }
+    }
+
+    /// takes back every taken buzzer the filter picks
+    fn free_buzzers_where(&mut self, filter: impl Fn(&SoundKey, &B) -> bool) {
+        let free_buzzers = &mut self.free_buzzers;
+        let pitches = &mut self.pitches;
+        B::with_taken(|taken_buzzers| {
+            let mut freed_keys = Deque::<SoundKey, 16>::new();
+
+            for key in taken_buzzers
+                .iter()
+                .filter(|(key, buzzer)| filter(key, buzzer))
+                .map(|(key, _)| key)
+            {
+                if freed_keys.push_back(*key).is_err() {
+                    break;
+                }
+            }
+            while let Some(key) = freed_keys.pop_front() {
+                pitches.remove(&key);
+                if let Some(mut taken_buzzer) = taken_buzzers.remove(&key) {
+                    taken_buzzer.reset();
+                    let _ = free_buzzers.push_back(taken_buzzer);
+                }
+            }
+        });
+    }
+
+    /// moves the buzzer of a taken key over to another key without starting the note again
+    fn move_voice(&mut self, from: SoundKey, to: SoundKey, pitch: VoicePitch) -> bool {
+        let playing = pitch.playing;
+        let replaced = B::with_taken(|taken_buzzers| {
+            let mut buzzer = taken_buzzers.remove(&from)?;
+            buzzer.set_period(playing);
+            // there's room, the key was just taken out
+            taken_buzzers.insert(to, buzzer).ok()
+        });
+        let Some(replaced) = replaced else {
+            return false;
+        };
+        if let Some(mut replaced_buzzer) = replaced {
+            // the new key was already playing, the old buzzer is free again
+            replaced_buzzer.reset();
+            let _ = self.free_buzzers.push_back(replaced_buzzer);
+        }
+        let _ = self.pitches.insert(to, pitch);
+        true
+    }
+
+    /// moves a sounding note of the channel over to the new key,
+    /// false if nothing else is sounding on the channel
+    fn take_over(&mut self, sound_key: SoundKey, period: u16, glide_micros: u32) -> bool {
+        let Some(held_key) = self
+            .pitches
+            .iter()
+            .find(|(key, pitch)| {
+                key.0 == sound_key.0 && **key != sound_key && pitch.chord.is_none()
+            })
+            .map(|(key, _)| *key)
+        else {
+            return false;
+        };
+        let Some(mut pitch) = self.pitches.remove(&held_key) else {
+            return false;
+        };
+
+        let now = now_micros();
+        pitch.glide = Glide::new(pitch.playing, period, now, glide_micros);
+        pitch.playing = pitch.glide.period(now);
+        let playing = pitch.playing;
+        if !self.move_voice(held_key, sound_key, pitch) {
+            return false;
+        }
+        self.channels[sound_key.0.as_int() as usize].last_period = Some(playing);
+        true
+    }
+
+    /// plays the note in turns with the buzzer of a sounding note of its channel,
+    /// false if the channel has nothing sounding or the chord is full
+    fn join_chord(&mut self, sound_key: SoundKey, period: u16) -> bool {
+        let now = now_micros();
+        let Some((held_key, pitch)) = self
+            .pitches
+            .iter_mut()
+            .find(|(key, _)| key.0 == sound_key.0)
+        else {
+            return false;
+        };
+        let held_period = pitch.glide.period(now);
+        let chord = pitch.chord.get_or_insert_with(|| {
+            Chord::new(
+                held_key.1,
+                held_period,
+                ARPEGGIO_ORDER,
+                ARPEGGIO_RATE_HZ,
+                now,
+            )
+        });
+        chord.add(sound_key.1, period)
+    }
+
+    /// takes the key out of the chord it takes turns in, false if it isn't in one
+    fn leave_chord(&mut self, sound_key: SoundKey) -> bool {
+        let Some((held_key, pitch)) = self.pitches.iter_mut().find(|(key, pitch)| {
+            key.0 == sound_key.0
+                && pitch
+                    .chord
+                    .as_ref()
+                    .is_some_and(|chord| chord.contains(sound_key.1))
+        }) else {
+            return false;
+        };
+        let held_key = *held_key;
+        let Some(chord) = &mut pitch.chord else {
+            return false;
+        };
+        chord.remove(sound_key.1);
+        let Some(&(next_key, next_period)) = chord.notes().first() else {
+            return false;
+        };
+        if let [_] = chord.notes() {
+            // the last note of the chord plays on its own
+            pitch.chord = None;
+            pitch.glide = Glide::steady(next_period);
+        }
+        if held_key != sound_key {
+            return true;
+        }
+
+        // the buzzer is taken by the key that left, it goes over to a note still in the chord
+        let Some(pitch) = self.pitches.remove(&held_key) else {
+            return false;
+        };
+        self.move_voice(held_key, (sound_key.0, next_key), pitch)
+    }
+
+    /// moves the periods of the gliding notes and the notes with a vibrato,
+    /// a few times per LFO cycle is plenty
+    fn update_pitches(&mut self) {
+        const PITCH_UPDATE_MICROS: u64 = 1000;
+
+        let now = now_micros();
+        if now < self.next_pitch_update_at || self.pitches.is_empty() {
+            return;
+        }
+        self.next_pitch_update_at = now + PITCH_UPDATE_MICROS;
+
+        let (pitches, channels) = (&mut self.pitches, &mut self.channels);
+        B::with_taken(|taken_buzzers| {
+            for (key, pitch) in pitches.iter_mut() {
+                let channel = &mut channels[key.0.as_int() as usize];
+                let base_period = match &pitch.chord {
+                    Some(chord) => chord.period(now),
+                    None => pitch.glide.period(now),
+                };
+                let period = pitch.vibrato.period(base_period, now, channel.mod_wheel);
+                if period != pitch.playing
+                    && let Some(buzzer) = taken_buzzers.get_mut(key)
+                {
+                    buzzer.set_period(period);
+                    pitch.playing = period;
+                }
+                channel.last_period = Some(base_period);
+            }
+        });
+    }
+}
+
+impl<B: Buzzer> VoiceSink<SoundKey, SoundProfile> for BuzzerBank<B> {
+    fn note_on(&mut self, sound_key: SoundKey, sound_profile: SoundProfile) {
+        // a key played again while it takes turns in a chord gets a buzzer like a new note
+        self.leave_chord(sound_key);
+
+        let channel = self.channels[sound_key.0.as_int() as usize];
+        let period = sound_profile.period_for_key(sound_key.1.as_int());
+        let glide_micros = channel.glide_micros();
+        if (channel.portamento || channel.legato) && self.take_over(sound_key, period, glide_micros)
+        {
+            return;
+        }
+        if channel.mono {
+            self.free_buzzers_where(|key, _| key.0 == sound_key.0);
+        }
+
+        if let Some(mut free_buzzer) = self.free_buzzers.pop_front() {
+            free_buzzer.set_level(channel.level());
+            free_buzzer.play_note(&sound_profile, sound_key.1);
+            let now = now_micros();
+            let glide = match channel.last_period {
+                Some(last_period) if channel.portamento => {
+                    Glide::new(last_period, period, now, glide_micros)
+                }
+                _ => Glide::steady(period),
+            };
+            let playing = glide.period(now);
+            if playing != period {
+                free_buzzer.set_period(playing);
+            }
+            let pitch = VoicePitch {
+                glide,
+                chord: None,
+                vibrato: VibratoLfo::new(sound_profile.vibrato, now),
+                playing,
+            };
+            let _ = self.pitches.insert(sound_key, pitch);
+            self.channels[sound_key.0.as_int() as usize].last_period = Some(playing);
+
+            if let Ok(Some(mut replaced_buzzer)) =
+                B::with_taken(|taken_buzzers| taken_buzzers.insert(sound_key, free_buzzer))
+            {
+                // the same key was already playing, the old buzzer is free again
+                replaced_buzzer.reset();
+                let _ = self.free_buzzers.push_back(replaced_buzzer);
+            }
+        } else if !(ARPEGGIATE_CHORDS && self.join_chord(sound_key, period)) {
+            println!("no free buzzers")
+        }
+    }
+
+    fn note_off(&mut self, sound_key: SoundKey) {
+        if self.leave_chord(sound_key) {
+            return;
+        }
+        self.pitches.remove(&sound_key);
+        if let Some(mut free_buzzer) =
+            B::with_taken(|taken_buzzers| taken_buzzers.remove(&sound_key))
+        {
+            free_buzzer.reset();
+            let _ = self.free_buzzers.push_back(free_buzzer);
+        }
+    }
+
+    fn all_off(&mut self) {
+        self.free_buzzers_where(|_, _| true);
+        // the next song starts without the controllers of the last one
+        self.channels = [CHANNEL_START; 16];
+    }
+
+    fn control(&mut self, channel: u8, control: ChannelControl) {
+        let channel = u4::new(channel & 0x0F);
+        match self.channels[channel.as_int() as usize].apply(control) {
+            ControlEffect::None => {}
+            ControlEffect::StopNotes => self.free_buzzers_where(|key, _| key.0 == channel),
+            ControlEffect::SetLevel(level) => B::with_taken(|taken_buzzers| {
+                for (_, buzzer) in taken_buzzers.iter_mut().filter(|(key, _)| key.0 == channel) {
+                    buzzer.set_level(level);
+                }
+            }),
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.free_buzzers_where(|_, buzzer| buzzer.is_finished());
+        self.update_pitches();
+    }
+}
+
+// =============================================================================================
+//                                 SYNTHESIS LOOP ON THE APP CORE
+// =============================================================================================
+
+static mut APP_CORE_STACK: Stack<8192> = Stack::new();
+
+static mut VOICE_QUEUE: VoiceQueue<SoundKey, SoundProfile, 64> = VoiceQueue::new();
+
+// refreshing takes the voices from the tone interrupt, so it's done on a schedule instead of
+// on every pass of the loop, often enough for the vibratos and the arpeggios
+const REFRESH_MICROS: u64 = 500;
+
+/// runs on the second core, plays the voice commands sent by the sequencer
+fn synth_loop<B: Buzzer>(
+    mut receiver: VoiceReceiver<'static, SoundKey, SoundProfile>,
+    mut buzzer_bank: BuzzerBank<B>,
+) -> ! {
+    let mut next_refresh_at = 0;
+    loop {
+        let applied = receiver.drain_into(&mut buzzer_bank);
+        let now = now_micros();
+        if applied > 0 || now >= next_refresh_at {
+            buzzer_bank.refresh();
+            next_refresh_at = now + REFRESH_MICROS;
         }
-        min_index
     }
 }
 
@@ -350,11 +1240,29 @@ impl Analog8 {
 //                           PIN OWNING BUZZERS FOR PLAYING NOTES
 // =============================================================================================
 
+// a buzzer backend, either bit banged from the tone timer interrupt or generated by the LEDC peripheral
+trait Buzzer: Sized {
+    fn play_note(&mut self, sound_profile: &SoundProfile, key: u7);
+
+    /// stops the note so the buzzer can be used for the next one
+    fn reset(&mut self);
+
+    /// true when the note duration of the sound profile has run out
+    fn is_finished(&self) -> bool;
+
+    /// changes the pitch of the sounding note without starting it again
+    fn set_period(&mut self, period_micros: u16);
+
+    /// loudness from 0 to MAX_LEVEL, for the sounding note and the next ones
+    fn set_level(&mut self, level: u8);
+
+    /// gives access to the buzzers that are currently playing a note
+    fn with_taken<R>(f: impl FnOnce(&mut LinearMap<SoundKey, Self, 16>) -> R) -> R;
+}
+
 struct SoundBuzzer<'a> {
     _buzzer_pin: Output<'a>,
-    period_micros: u16,
-    max_period: i32,
-    current_micros: u16,
+    clock: ToneClock,
     pin_state: bool,
     pin_mask: u32,
 }
@@ -364,42 +1272,18 @@ impl<'a> SoundBuzzer<'a> {
         assert!((0..=31).contains(&pin_num)); // register only for pins 0 - 31
         Self {
             _buzzer_pin: Output::new(pin, Level::Low, OutputConfig::default()),
-            period_micros: 4000,
-            max_period: i32::MAX,
-            current_micros: 0,
+            clock: ToneClock::new(4000),
             pin_state: false,
             pin_mask: 1 << pin_num,
         }
     }
 
-    fn reset(&mut self) {
-        self.current_micros = 0;
-        self.max_period = i32::MAX;
-
-        // self.buzzer_pin.set_low();
-        // self.pin_state = false;
-    }
-
+    /// called from the tone timer interrupt every tick
     #[inline(always)]
-    fn play_note(&mut self, sound_profile: &SoundProfile, key: u7) {
-        // key between 0 and 127, so 64 is the middle point
-        // less than 64 = note goes down, so wait time goes up
-        // more than 64 = note goes up, so wait time goes down
-
-        self.period_micros = sound_profile.wait_time
-            - (sound_profile.wait_change_per_key * (key.as_int() as u16 - 64));
-
-        self.max_period = sound_profile.duration.unwrap_or(i32::MAX);
-        println!("period micros: {}", self.period_micros);
-    }
-
-    #[inline(always)]
-    fn update(&mut self) {
+    fn update(&mut self, tick_micros: u16) {
         // TODO: when changing the frequency to be from hz, remake this
 
-        self.current_micros += 20;
-
-        if self.max_period > 0 && self.current_micros > self.period_micros {
+        if self.clock.tick(tick_micros, self.pin_state) {
             const REGISTERS: [*mut u32; 2] = [GPIO_0_31_SET_REG, GPIO_0_31_CLEAR_REG];
             // we use unsafe instead of pin toggle because this is faster (measured)
             // and the speed is needed with possibly thousands of toggles per seconds
@@ -416,20 +1300,50 @@ impl<'a> SoundBuzzer<'a> {
                     .write_volatile(self.pin_mask);
             }
             self.pin_state = !self.pin_state;
-            self.current_micros = 0
         }
-        self.max_period -= 5;
     }
 
     #[inline(always)]
-    fn adjust_period(&mut self, delta: i16) {
-        self.period_micros = self.period_micros.saturating_add_signed(delta);
-        self.period_micros = self.period_micros.clamp(100, 20000);
-        println!(
-            "Period: {}us ({}Hz)",
-            self.period_micros,
-            1_000_000 / self.period_micros as u32
-        );
+    fn adjust_period(&mut self, delta: i16) -> u16 {
+        self.clock.period_micros = self.clock.period_micros.saturating_add_signed(delta);
+        self.clock.period_micros = self
+            .clock
+            .period_micros
+            .clamp(MIN_PERIOD_MICROS, MAX_PERIOD_MICROS);
+        self.clock.period_micros
+    }
+}
+
+impl Buzzer for SoundBuzzer<'static> {
+    #[inline(always)]
+    fn play_note(&mut self, sound_profile: &SoundProfile, key: u7) {
+        let period_micros = sound_profile.period_for_key(key.as_int());
+
+        self.clock.start(period_micros, sound_profile.duration);
+    }
+
+    fn reset(&mut self) {
+        self.clock.reset();
+
+        // self.buzzer_pin.set_low();
+        // self.pin_state = false;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.clock.is_finished()
+    }
+
+    #[inline(always)]
+    fn set_period(&mut self, period_micros: u16) {
+        self.clock.period_micros = period_micros;
+    }
+
+    fn set_level(&mut self, level: u8) {
+        self.clock.duty_pct = level_duty_pct(level, FULL_DUTY_PCT);
+    }
+
+    fn with_taken<R>(f: impl FnOnce(&mut VoiceMap) -> R) -> R {
+        with_voices(f)
     }
 }
 
@@ -472,6 +1386,287 @@ const fn get_knob_rotation(
     }
 }
 
+// the rotary encoder with a push button, read either from the main loop or the gpio interrupt
+struct Encoder {
+    clk: Input<'static>,
+    dt: Input<'static>,
+    sw: Input<'static>,
+    last_clk_state: bool,
+    last_dt_state: bool,
+    last_sw_state: bool,
+    turned_while_held: bool,
+}
+
+struct EncoderInput {
+    rotation: Option<Rotation>,
+    held: bool,     // the button is down
+    released: bool, // the button was let go without turning the knob while it was down
+}
+
+impl Encoder {
+    fn new(clk: Input<'static>, dt: Input<'static>, sw: Input<'static>) -> Self {
+        Encoder {
+            last_clk_state: clk.is_high(),
+            last_dt_state: dt.is_high(),
+            last_sw_state: sw.is_low(),
+            turned_while_held: false,
+            clk,
+            dt,
+            sw,
+        }
+    }
+
+    /// what happened since the last poll
+    fn poll(&mut self) -> EncoderInput {
+        // current states
+        let current_clk_state = self.clk.is_high();
+        let current_dt_state = self.dt.is_high();
+        let current_sw_state = self.sw.is_low();
+
+        let rotation = get_knob_rotation(
+            self.last_clk_state,
+            self.last_dt_state,
+            current_clk_state,
+            current_dt_state,
+        );
+        let released = !current_sw_state && current_sw_state != self.last_sw_state;
+
+        let input = EncoderInput {
+            held: current_sw_state,
+            released: released && !self.turned_while_held,
+            rotation,
+        };
+
+        if !current_sw_state {
+            self.turned_while_held = false;
+        } else if input.rotation.is_some() {
+            self.turned_while_held = true;
+        }
+
+        // reset current states
+        self.last_clk_state = current_clk_state;
+        self.last_dt_state = current_dt_state;
+        self.last_sw_state = current_sw_state;
+        input
+    }
+
+    fn listen(&mut self) {
+        self.clk.listen(Event::AnyEdge);
+        self.dt.listen(Event::AnyEdge);
+        self.sw.listen(Event::AnyEdge);
+    }
+
+    fn unlisten(&mut self) {
+        self.clk.unlisten();
+        self.dt.unlisten();
+        self.sw.unlisten();
+    }
+
+    fn clear_interrupts(&mut self) {
+        self.clk.clear_interrupt();
+        self.dt.clear_interrupt();
+        self.sw.clear_interrupt();
+    }
+}
+
+// =============================================================================================
+//                                  SONG LIBRARY ON THE SD CARD
+// =============================================================================================
+
+fn open_song_library(spi: Spi<'static, Blocking>, cs: Output<'static>) -> Option<CardLibrary> {
+    let card = match SdCard::new(spi, cs) {
+        Ok(card) => card,
+        Err(err) => {
+            println!("no SD card: {:?}", err);
+            return None;
+        }
+    };
+    match SongLibrary::open(card) {
+        Ok(library) => Some(library),
+        Err(err) => {
+            println!("can't read the SD card: {}", err);
+            None
+        }
+    }
+}
+
+fn print_playlist(library: &CardLibrary) {
+    println!("songs on the SD card:");
+    for (i, song) in library.songs().iter().enumerate() {
+        println!("  {}: {} ({} bytes)", i, song.file_name(), song.size);
+    }
+    if library.left_out() > 0 {
+        println!(
+            "  and {} more that don't fit in the playlist",
+            library.left_out()
+        );
+    }
+}
+
+/// plays the songs on the card one after another, a song that can't be played is skipped
+fn play_song_library<V: VoiceSink<SoundKey, SoundProfile>>(
+    song_player: &mut SongPlayer<V>,
+    library: &mut CardLibrary,
+) {
+    for index in 0..library.songs().len() {
+        let name = library.songs()[index].file_name();
+        let Some(song) = library.song(index) else {
+            continue;
+        };
+        println!("playing {}", name);
+
+        let mut reader = match SongReader::open(song) {
+            Ok(reader) => reader,
+            Err(err) => {
+                println!("can't play {}: {}", name, err);
+                continue;
+            }
+        };
+        // the check comes first, a pattern it leaves out doesn't take any time in the tempo map
+        let plan = match SongPlan::for_reader(&mut reader, ON_SONG_ERROR, FORMAT_2_PATTERN) {
+            Ok(plan) => plan,
+            Err(err) => {
+                println!("can't play {}: {}", name, err);
+                continue;
+            }
+        };
+        match SongTempoMap::from_reader(&mut reader, |track| plan.plays_track(track)) {
+            Ok(tempo_map) => {
+                if let Err(err) = song_player.play_streamed_song(&mut reader, &tempo_map, plan) {
+                    println!("song not played to the end: {}", err);
+                }
+            }
+            Err(err) => println!("can't play {}: {}", name, err),
+        }
+        // a track that can't be read from the card ends there
+        if let Some(err) = reader.source_error() {
+            println!("reading {} from the SD card failed: {}", name, err);
+        }
+    }
+}
+
+// =============================================================================================
+//                                 SETTINGS IN THE FLASH
+// =============================================================================================
+
+fn open_settings(flash: FLASH<'static>) -> Option<FlashSettings> {
+    match SettingsStore::open(SettingsPartition::new(flash)) {
+        Ok(store) => {
+            println!("settings: {:?}", store.loaded());
+            Some(store)
+        }
+        Err(err) => {
+            println!("can't read the settings: {}", err);
+            None
+        }
+    }
+}
+
+/// takes the instrument sounds of PROFILES.TXT on the card, true if they changed the settings
+///
+/// a bank with an error is left out as a whole so a half read bank doesn't mix with the old sounds
+fn load_profile_bank(library: &mut CardLibrary, settings: &mut Settings) -> bool {
+    let Some(mut bank) = library.profile_bank() else {
+        return false;
+    };
+    let mut loaded = settings.clone();
+    let mut left_out = 0;
+    // the key range and the vibrato the tuner saved are kept
+    let mut found = |entry: BankEntry| {
+        if loaded
+            .set_tweak(entry.apply(loaded.tweak(entry.program)))
+            .is_err()
+        {
+            left_out += 1;
+        }
+    };
+
+    let mut reader = BankReader::<BANK_LINE_LEN>::new();
+    let mut cursor = bank.cursor();
+    let mut chunk = [0; 64];
+    loop {
+        let read = match bank.read(&mut cursor, &mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) => {
+                println!("can't read PROFILES.TXT: {}", err);
+                return false;
+            }
+        };
+        if let Err(err) = reader.feed(&chunk[..read], &mut found) {
+            println!("PROFILES.TXT {}", err);
+            return false;
+        }
+    }
+    if let Err(err) = reader.finish(&mut found) {
+        println!("PROFILES.TXT {}", err);
+        return false;
+    }
+
+    if left_out > 0 {
+        println!(
+            "{} sounds of PROFILES.TXT left out, only {} programs can have their own sound",
+            left_out, MAX_PROFILE_TWEAKS
+        );
+    }
+    let changed = loaded != *settings;
+    *settings = loaded;
+    changed
+}
+
+/// puts the settings into use, values from an older firmware are kept in range
+fn apply_settings(settings: &Settings, volume: &mut Analog8) {
+    LIVE_TRANSPOSE.store(
+        settings
+            .transpose
+            .clamp(-MAX_LIVE_TRANSPOSE, MAX_LIVE_TRANSPOSE),
+        Ordering::Relaxed,
+    );
+    TRANSPOSE_TO_KEY.store(settings.transpose_to_key, Ordering::Relaxed);
+    PLAYBACK_SPEED_PCT.store(clamp_speed(settings.speed_pct), Ordering::Relaxed);
+    volume.value = settings.volume;
+}
+
+/// takes the live controls and the volume as they are now into the settings
+fn update_settings(settings: &mut Settings, volume: &Analog8) {
+    settings.volume = volume.value;
+    settings.transpose = LIVE_TRANSPOSE.load(Ordering::Relaxed);
+    settings.transpose_to_key = TRANSPOSE_TO_KEY.load(Ordering::Relaxed);
+    settings.speed_pct = PLAYBACK_SPEED_PCT.load(Ordering::Relaxed);
+}
+
+/// writes the settings if they changed, a failed save only loses them at the next reset
+fn save_settings(store: &mut Option<FlashSettings>, settings: &Settings) {
+    let Some(store) = store else {
+        return;
+    };
+    match store.save(settings) {
+        Ok(true) => println!("settings saved"),
+        Ok(false) => {}
+        Err(err) => println!("can't save the settings: {}", err),
+    }
+}
+
+/// keeps the tuned sound of a program in the settings and saves them
+fn save_profile_tweak(
+    store: &mut Option<FlashSettings>,
+    settings: &mut Settings,
+    tweak: ProfileTweak,
+) {
+    if settings.set_tweak(tweak).is_err() {
+        println!(
+            "only {} programs can have their own sound, save the built in sound of one to make room",
+            MAX_PROFILE_TWEAKS
+        );
+        return;
+    }
+    println!(
+        "program {} {} sound kept",
+        tweak.program, PROGRAM_NAMES[tweak.program as usize]
+    );
+    save_settings(store, settings);
+}
+
 // =============================================================================================
 //                                         MAIN
 // =============================================================================================
@@ -484,6 +1679,19 @@ fn main() -> ! {
 
     esp_println::logger::init_logger_from_env();
 
+    // ---------- tone timer, started on the synthesis core ----------
+
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let tone_timer = PeriodicTimer::new(timg0.timer0);
+
+    // ---------- saved settings, read before the other core runs from the flash ----------
+
+    let mut settings_store = open_settings(peripherals.FLASH);
+    let mut settings = settings_store
+        .as_ref()
+        .map(|store| store.settings().clone())
+        .unwrap_or_default();
+
     // ---------- load track ----------
 
     //  let (header, track_iter) = parse(MIDI_DATA).unwrap();
@@ -496,7 +1704,28 @@ fn main() -> ! {
 
     // ---------- set up pins ----------
 
-    let mut led = Output::new(peripherals.GPIO2, Level::Low, OutputConfig::default());
+    let mut song_library = if SD_CARD {
+        let spi = Spi::new(peripherals.SPI2, SpiConfig::default())
+            .expect("the default SPI config is valid")
+            .with_sck(peripherals.GPIO21)
+            .with_mosi(peripherals.GPIO22)
+            .with_miso(peripherals.GPIO32);
+        let cs = Output::new(peripherals.GPIO15, Level::High, OutputConfig::default());
+        open_song_library(spi, cs)
+    } else {
+        None
+    };
+    if let Some(library) = &mut song_library {
+        print_playlist(library);
+        // the sounds on the card are saved, so they stay after the card is taken out
+        if load_profile_bank(library, &mut settings) {
+            save_settings(&mut settings_store, &settings);
+        }
+    }
+
+    let led = Output::new(peripherals.GPIO2, Level::Low, OutputConfig::default());
+    let metronome_click = METRONOME_CLICK
+        .then(|| Output::new(peripherals.GPIO33, Level::Low, OutputConfig::default()));
 
     // roatry encoder input pins
 
@@ -505,6 +1734,9 @@ fn main() -> ! {
     let clk = Input::new(peripherals.GPIO18, up_input_config.with_pull(Pull::Up));
     let dt = Input::new(peripherals.GPIO19, up_input_config.with_pull(Pull::Up));
     let sw = Input::new(peripherals.GPIO23, up_input_config.with_pull(Pull::Up));
+    let encoder = Encoder::new(clk, dt, sw);
+
+    let mut io = Io::new(peripherals.IO_MUX);
 
     // ---------- set up analog DAC pins ----------
 
@@ -512,17 +1744,43 @@ fn main() -> ! {
 
     // ---------- set baseline states ----------
 
-    let buzzer_1 = SoundBuzzer::new(peripherals.GPIO5.degrade(), 5);
-    let buzzer_2 = SoundBuzzer::new(peripherals.GPIO13.degrade(), 13);
-    let buzzer_3 = SoundBuzzer::new(peripherals.GPIO14.degrade(), 14);
-    let buzzer_4 = SoundBuzzer::new(peripherals.GPIO27.degrade(), 27);
-    let buzzer_5 = SoundBuzzer::new(peripherals.GPIO16.degrade(), 16);
-    let buzzer_6 = SoundBuzzer::new(peripherals.GPIO17.degrade(), 17);
-    let buzzer_7 = SoundBuzzer::new(peripherals.GPIO26.degrade(), 26);
-    let buzzer_8 = SoundBuzzer::new(peripherals.GPIO3.degrade(), 3);
+    // the console and the eighth buzzer both want GPIO 3
+    let (console_pin, buzzer_8_pin) = if MIXER_CONSOLE {
+        (Some(peripherals.GPIO3), None)
+    } else {
+        (None, Some(peripherals.GPIO3))
+    };
+
+    #[cfg(not(feature = "ledc"))]
+    let (buzzer_1, buzzer_2, buzzer_3, buzzer_4, buzzer_5, buzzer_6, buzzer_7, buzzer_8) = (
+        SoundBuzzer::new(peripherals.GPIO5.degrade(), 5),
+        SoundBuzzer::new(peripherals.GPIO13.degrade(), 13),
+        SoundBuzzer::new(peripherals.GPIO14.degrade(), 14),
+        SoundBuzzer::new(peripherals.GPIO27.degrade(), 27),
+        SoundBuzzer::new(peripherals.GPIO16.degrade(), 16),
+        SoundBuzzer::new(peripherals.GPIO17.degrade(), 17),
+        SoundBuzzer::new(peripherals.GPIO26.degrade(), 26),
+        buzzer_8_pin.map(|pin| SoundBuzzer::new(pin.degrade(), 3)),
+    );
+
+    // same pins, but the tones are generated by the LEDC peripheral
+    #[cfg(feature = "ledc")]
+    let _ledc = init_ledc(peripherals.LEDC);
+    #[cfg(feature = "ledc")]
+    let (buzzer_1, buzzer_2, buzzer_3, buzzer_4, buzzer_5, buzzer_6, buzzer_7, buzzer_8) = (
+        LedcBuzzer::new(peripherals.GPIO5.degrade(), LedcVoice::HighSpeed(0)),
+        LedcBuzzer::new(peripherals.GPIO13.degrade(), LedcVoice::HighSpeed(1)),
+        LedcBuzzer::new(peripherals.GPIO14.degrade(), LedcVoice::HighSpeed(2)),
+        LedcBuzzer::new(peripherals.GPIO27.degrade(), LedcVoice::HighSpeed(3)),
+        LedcBuzzer::new(peripherals.GPIO16.degrade(), LedcVoice::LowSpeed(0)),
+        LedcBuzzer::new(peripherals.GPIO17.degrade(), LedcVoice::LowSpeed(1)),
+        LedcBuzzer::new(peripherals.GPIO26.degrade(), LedcVoice::LowSpeed(2)),
+        buzzer_8_pin.map(|pin| LedcBuzzer::new(pin.degrade(), LedcVoice::LowSpeed(3))),
+    );
 
     let mut analog_value_pin25 = Analog8::default();
-    let mut buzzer_queue: Deque<SoundBuzzer, 16> = Deque::new();
+    apply_settings(&settings, &mut analog_value_pin25);
+    let mut buzzer_queue: Deque<_, 16> = Deque::new();
     let _ = buzzer_queue.push_back(buzzer_1);
     //let _ = buzzer_queue.push_back(buzzer_2);
     //let _ = buzzer_queue.push_back(buzzer_3);
@@ -530,67 +1788,177 @@ fn main() -> ! {
     //let _ = buzzer_queue.push_back(buzzer_5);
     //let _ = buzzer_queue.push_back(buzzer_6);
     //let _ = buzzer_queue.push_back(buzzer_7);
-    //let _ = buzzer_queue.push_back(buzzer_8);
+    //if let Some(buzzer_8) = buzzer_8 {
+    //    let _ = buzzer_queue.push_back(buzzer_8);
+    //}
+
+    // ---------- split the sequencer and the synthesis between the cores ----------
+
+    // both statics are only borrowed here, and main is only called once
+    let voice_queue = unsafe { &mut *&raw mut VOICE_QUEUE };
+    let app_core_stack = unsafe { &mut *&raw mut APP_CORE_STACK };
+
+    let (voice_sender, voice_receiver) = split_voice_queue(voice_queue);
+
+    let mut cpu_control = CpuControl::new(peripherals.CPU_CTRL);
+    let _synth_core = cpu_control
+        .start_app_core(app_core_stack, move || {
+            // the interrupt is handled by the core that enables it
+            start_tone_timer(tone_timer);
+            synth_loop(voice_receiver, BuzzerBank::new(buzzer_queue));
+        })
+        .expect("app core starts");
+
+    // the encoder transposes the song while it plays
+    start_live_controls(&mut io, encoder);
+
+    let mut song_player = SongPlayer::new(
+        voice_sender,
+        Metronome::new(led, metronome_click, METRONOME),
+    );
+    song_player.profile_tweaks = settings.profile_tweaks.clone();
+    song_player.console = console_pin.and_then(|pin| MixerConsole::new(peripherals.UART0, pin));
+    if let Some(library) = &mut song_library {
+        play_song_library(&mut song_player, library);
+    } else {
+        if PLAY_PRECOMPILED {
+            // build.rs only compiles whole songs, every pattern plays
+            match SongTempoMap::from_midi(MIDI_DATA, |_| true) {
+                Ok(tempo_map) => {
+                    let song =
+                        SongStream::new(MIDI_STREAM).expect("build.rs writes a valid song stream");
+                    song_player.play_stream(&song, &tempo_map);
+                }
+                Err(err) => println!("can't play the song: {}", err),
+            }
+        } else {
+            match SongPlan::for_midi(MIDI_DATA, ON_SONG_ERROR, FORMAT_2_PATTERN) {
+                Ok(plan) => {
+                    match SongTempoMap::from_midi(MIDI_DATA, |track| plan.plays_track(track)) {
+                        Ok(tempo_map) => {
+                            if let Err(err) = song_player.play_song(MIDI_DATA, &tempo_map, plan) {
+                                println!("song not played to the end: {}", err);
+                            }
+                        }
+                        Err(err) => println!("can't play the song: {}", err),
+                    }
+                }
+                Err(err) => println!("can't play the song: {}", err),
+            }
+        }
+    }
+    // the synthesis core has to be done with the song voices before the tuning buzzer is added
+    song_player.voices.flush();
+    let mut led = song_player.metronome.into_led();
 
-    let mut song_player = SongPlayer::new(buzzer_queue);
-    // todo: add a self healing meachanism that tries to catch up / slow down to get the correct beat
-    song_player.play_song(MIDI_DATA);
+    // back to tuning the buzzer with the encoder
+    let mut encoder = stop_live_controls();
+    update_settings(&mut settings, &analog_value_pin25);
+    save_settings(&mut settings_store, &settings);
 
     dac_25.write(analog_value_pin25.value);
 
-    // last states for rotary encode pins
-    let mut last_clk_state = clk.is_high();
-    let mut last_dt_state = dt.is_high();
-    let mut last_sw_state = sw.is_low();
+    // the tuning buzzer is played by the timer interrupt like any song voice
+    const TUNING_KEY: SoundKey = (u4::new(0), u7::new(64));
 
     let mut buzzer_0 = SoundBuzzer::new(peripherals.GPIO4.degrade(), 4);
-    buzzer_0.max_period = 1_000_000_000;
-
-    //buzzer_queue.push_back(buzzer_0);
+    buzzer_0.clock.remaining_micros = i32::MAX;
+    let _ = with_voices(|voices| voices.insert(TUNING_KEY, buzzer_0));
 
     println!("song over");
 
-    loop {
-        // current states
-        let current_clk_state = clk.is_high();
-        let current_dt_state = dt.is_high();
-        let current_sw_state = sw.is_low();
+    // the last turn of the encoder, the volume is saved once it has settled
+    let mut unsaved_since: Option<u64> = None;
 
-        // pin logic
-        if sw.is_high() && current_sw_state != last_sw_state {
-            led.toggle();
-            buzzer_0.max_period = 1_000_000_000;
+    // turning right with the button held down tunes the instrument sounds, turning left goes
+    // back to the buzzer, the tuning buzzer is taken out of the voices while the sounds play
+    // on the song buzzers
+    let mut profile_tuner: Option<ProfileTuner> = None;
+    let mut parked_buzzer = None;
 
-            println!("playing: {}", buzzer_0.max_period > 0)
-        }
-
-        if let Some(rotation) = get_knob_rotation(
-            last_clk_state,
-            last_dt_state,
-            current_clk_state,
-            current_dt_state,
-        ) {
-            match rotation {
-                Rotation::Left => {
-                    buzzer_0.adjust_period(20);
-                    analog_value_pin25.dec();
+    loop {
+        let input = encoder.poll();
+
+        if input.held && input.rotation.is_some() {
+            let tune_sounds = matches!(input.rotation, Some(Rotation::Right));
+            match profile_tuner.take() {
+                None if tune_sounds => {
+                    parked_buzzer = with_voices(|voices| voices.remove(&TUNING_KEY));
+                    let tuner = ProfileTuner::new(&settings);
+                    println!("tuning the instrument sounds: {}", tuner);
+                    profile_tuner = Some(tuner);
                 }
-                Rotation::Right => {
-                    buzzer_0.adjust_period(-20);
-                    analog_value_pin25.inc();
+                Some(mut tuner) if !tune_sounds => {
+                    tuner.stop(&mut song_player.voices);
+                    if let Some(buzzer_0) = parked_buzzer.take() {
+                        let _ = with_voices(|voices| voices.insert(TUNING_KEY, buzzer_0));
+                    }
+                    println!("tuning the buzzer");
                 }
+                tuner => profile_tuner = tuner,
+            }
+        } else if let Some(tuner) = &mut profile_tuner {
+            if input.released {
+                tuner.next_field();
+                println!("{}", tuner);
+            }
+            if let Some(rotation) = input.rotation {
+                let right = matches!(rotation, Rotation::Right);
+                if let Some(tweak) = tuner.turn(right, &settings, now_micros()) {
+                    save_profile_tweak(&mut settings_store, &mut settings, tweak);
+                }
+                println!("{}", tuner);
+            }
+            tuner.update(now_micros(), &mut song_player.voices);
+        } else {
+            if input.released {
+                led.toggle();
+                let playing = with_voices(|voices| {
+                    voices.get_mut(&TUNING_KEY).is_some_and(|buzzer_0| {
+                        buzzer_0.clock.remaining_micros = i32::MAX;
+                        !buzzer_0.clock.is_finished()
+                    })
+                });
+
+                println!("playing: {}", playing)
             }
 
-            println!("analog led pin value: {}", analog_value_pin25.value);
-            dac_25.write(analog_value_pin25.value);
-        }
+            if let Some(rotation) = input.rotation {
+                let period_delta = match rotation {
+                    Rotation::Left => {
+                        analog_value_pin25.dec();
+                        20
+                    }
+                    Rotation::Right => {
+                        analog_value_pin25.inc();
+                        -20
+                    }
+                };
+
+                // printing is kept outside of the critical section so the tone doesn't stall
+                if let Some(period_micros) = with_voices(|voices| {
+                    voices
+                        .get_mut(&TUNING_KEY)
+                        .map(|buzzer_0| buzzer_0.adjust_period(period_delta))
+                }) {
+                    println!(
+                        "Period: {}us ({}Hz)",
+                        period_micros,
+                        1_000_000 / period_micros as u32
+                    );
+                }
 
-        buzzer_0.update();
+                println!("analog led pin value: {}", analog_value_pin25.value);
+                dac_25.write(analog_value_pin25.value);
+                unsaved_since = Some(now_micros());
+            }
+        }
 
-        // reset current states
-        last_dt_state = current_dt_state;
-        last_sw_state = current_sw_state;
-        last_clk_state = current_clk_state;
+        if unsaved_since.is_some_and(|since| now_micros() - since > SETTINGS_SAVE_DELAY_MICROS) {
+            update_settings(&mut settings, &analog_value_pin25);
+            save_settings(&mut settings_store, &settings);
+            unsaved_since = None;
+        }
 
         delay_cycles(1700);
     }
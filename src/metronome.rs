@@ -0,0 +1,156 @@
+// =============================================================================================
+//                           METRONOME ON THE LED AND A CLICK BUZZER
+// =============================================================================================
+
+// blinks the on-board LED on every beat of the song, longer on the first beat of a bar,
+// and can click a buzzer with a higher click on the first beat
+// the beats come from the tempo map, so they follow the tempo and time signature changes,
+// and from the playback clock, so they follow the playback speed
+
+use esp_hal::gpio::Output;
+
+use crate::now_micros;
+use crate::playback_clock::{PlaybackClock, scale_micros};
+use crate::tempo_map::TempoMap;
+
+const BEAT_FLASH_MICROS: u64 = 40_000;
+const DOWNBEAT_FLASH_MICROS: u64 = 120_000;
+
+const CLICK_MICROS: u64 = 15_000;
+const BEAT_CLICK_TOGGLE_MICROS: u64 = 500; // 1 kHz
+const DOWNBEAT_CLICK_TOGGLE_MICROS: u64 = 250; // 2 kHz
+
+pub struct Metronome {
+    led: Output<'static>,
+    click: Option<Output<'static>>,
+    enabled: bool,
+    next_beat_tick: u64,
+    next_beat_micros: u64, // song time of the next beat
+    flash_ends_at: u64,
+    click_ends_at: u64,
+    click_toggle_micros: u64,
+    next_click_toggle: u64,
+}
+
+impl Metronome {
+    /// a disabled metronome only keeps the pins, so they can be taken back after the song
+    pub fn new(led: Output<'static>, click: Option<Output<'static>>, enabled: bool) -> Self {
+        Metronome {
+            led,
+            click,
+            enabled,
+            next_beat_tick: 0,
+            next_beat_micros: 0,
+            flash_ends_at: 0,
+            click_ends_at: 0,
+            click_toggle_micros: BEAT_CLICK_TOGGLE_MICROS,
+            next_click_toggle: 0,
+        }
+    }
+
+    pub fn into_led(self) -> Output<'static> {
+        self.led
+    }
+
+    /// plays bars of beats with the tempo and time signature the song starts with,
+    /// returns when the first beat of the song is due
+    pub fn count_in<const T: usize, const M: usize>(
+        &mut self,
+        tempo_map: &TempoMap<T, M>,
+        speed_pct: u16,
+        bars: u8,
+    ) {
+        if !self.enabled || bars == 0 {
+            return;
+        }
+
+        let beats_per_bar = tempo_map.beats_per_bar(0) as u32;
+        let first_beat_micros = tempo_map.tick_to_micros(tempo_map.next_beat(1));
+        let beat_micros = scale_micros(first_beat_micros, speed_pct);
+
+        let start = now_micros();
+        for beat in 0..beats_per_bar * bars as u32 {
+            let beat_at = start + beat as u64 * beat_micros;
+            self.wait_until(beat_at);
+            self.beat(beat_at, beat % beats_per_bar == 0);
+        }
+        self.wait_until(start + (beats_per_bar * bars as u32) as u64 * beat_micros);
+    }
+
+    /// the song starts from its first beat
+    pub fn start(&mut self) {
+        self.next_beat_tick = 0;
+        self.next_beat_micros = 0;
+    }
+
+    /// plays the beats that are due, called all the time while waiting for the next event
+    pub fn update<const T: usize, const M: usize>(
+        &mut self,
+        now: u64,
+        tempo_map: &TempoMap<T, M>,
+        clock: &PlaybackClock,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        if now >= clock.real_micros_at(self.next_beat_micros) {
+            let position = tempo_map.tick_to_bar_beat(self.next_beat_tick);
+            self.beat(now, position.beat == 1);
+
+            self.next_beat_tick = tempo_map.next_beat(self.next_beat_tick + 1);
+            self.next_beat_micros = tempo_map.tick_to_micros(self.next_beat_tick);
+        }
+        self.update_outputs(now);
+    }
+
+    /// turns everything off at the end of the song
+    pub fn stop(&mut self) {
+        self.led.set_low();
+        if let Some(click) = self.click.as_mut() {
+            click.set_low();
+        }
+        self.flash_ends_at = 0;
+        self.click_ends_at = 0;
+    }
+
+    fn beat(&mut self, now: u64, downbeat: bool) {
+        let (flash_micros, toggle_micros) = if downbeat {
+            (DOWNBEAT_FLASH_MICROS, DOWNBEAT_CLICK_TOGGLE_MICROS)
+        } else {
+            (BEAT_FLASH_MICROS, BEAT_CLICK_TOGGLE_MICROS)
+        };
+
+        self.led.set_high();
+        self.flash_ends_at = now + flash_micros;
+
+        self.click_ends_at = now + CLICK_MICROS;
+        self.click_toggle_micros = toggle_micros;
+        self.next_click_toggle = now;
+    }
+
+    fn update_outputs(&mut self, now: u64) {
+        if now >= self.flash_ends_at {
+            self.led.set_low();
+        }
+
+        if let Some(click) = self.click.as_mut() {
+            if now >= self.click_ends_at {
+                click.set_low();
+            } else if now >= self.next_click_toggle {
+                click.toggle();
+                self.next_click_toggle += self.click_toggle_micros;
+            }
+        }
+    }
+
+    fn wait_until(&mut self, deadline: u64) {
+        loop {
+            let now = now_micros();
+            if now >= deadline {
+                break;
+            }
+            self.update_outputs(now);
+        }
+    }
+}
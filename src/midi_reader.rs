@@ -0,0 +1,542 @@
+// =============================================================================================
+//                           READING A MIDI FILE A PIECE AT A TIME
+// =============================================================================================
+
+// midly's parse needs the whole file as one slice, which is fine for the embedded song but
+// not for songs on the SD card, the reader only keeps a small buffer for every track and
+// pulls the next bytes of a track from the source when its buffer runs low
+//
+// the events are still decoded by midly, from the bytes in the track buffer, so they are the
+// same as with parse, also on broken tracks, except for meta and sysex events with more data
+// than fits in the buffer: those are cut to what fits and the rest of the data is skipped
+//
+// the tracks are merged like TrackMerger does, the event data lives in the buffer of its
+// track, so an event can only be looked at until the next one is asked for
+
+use core::fmt::{self, Debug};
+
+use heapless::{
+    Vec,
+    binary_heap::{BinaryHeap, Min},
+};
+use midly::{EventIter, Format, Header, MetaMessage, TrackEvent, TrackEventKind, parse};
+
+use crate::song_check::{SongError, ticks_per_quarter};
+use crate::track_merger::MergedEvent;
+
+// the longest event that isn't meta or sysex data: a 4 byte delta, a meta status, the meta
+// type and a 4 byte data length, a channel message is shorter
+const MAX_EVENT_HEAD: usize = 10;
+// the header chunk with its id and length
+const HEADER_LEN: usize = 14;
+const CHUNK_HEAD_LEN: u32 = 8;
+
+/// where the bytes of a song come from, every track reads with its own cursor,
+/// so the tracks can be read in any order without seeking back and forth
+pub trait SongSource {
+    type Error: Debug;
+    type Cursor;
+
+    /// bytes in the song
+    fn size(&self) -> u32;
+
+    /// a cursor at the start of the song
+    fn cursor(&self) -> Self::Cursor;
+
+    fn seek(&mut self, cursor: &mut Self::Cursor, position: u32);
+
+    /// reads from the cursor, returns how many bytes were read, 0 at the end
+    fn read(&mut self, cursor: &mut Self::Cursor, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// a song that is already in memory
+impl SongSource for &[u8] {
+    type Error = core::convert::Infallible;
+    type Cursor = usize;
+
+    fn size(&self) -> u32 {
+        self.len() as u32
+    }
+
+    fn cursor(&self) -> usize {
+        0
+    }
+
+    fn seek(&mut self, cursor: &mut usize, position: u32) {
+        *cursor = (position as usize).min(self.len());
+    }
+
+    fn read(&mut self, cursor: &mut usize, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buffer.len().min(self.len() - *cursor);
+        buffer[..len].copy_from_slice(&self[*cursor..*cursor + len]);
+        *cursor += len;
+        Ok(len)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReaderError<E> {
+    /// the source can't be read
+    Source(E),
+    Song(SongError),
+}
+
+impl<E: Debug> fmt::Display for ReaderError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReaderError::Source(err) => write!(f, "the song can't be read: {err:?}"),
+            ReaderError::Song(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl<E> From<SongError> for ReaderError<E> {
+    fn from(err: SongError) -> Self {
+        ReaderError::Song(err)
+    }
+}
+
+impl<E> From<midly::Error> for ReaderError<E> {
+    fn from(err: midly::Error) -> Self {
+        ReaderError::Song(SongError::Header(err))
+    }
+}
+
+struct TrackReader<C, const BUFFER: usize> {
+    // the chunk data in the file
+    start: u32,
+    end: u32,
+    cursor: C,
+    position: u32, // of the next byte read into the buffer
+    buffer: [u8; BUFFER],
+    // the bytes in the buffer that haven't been read yet
+    read: usize,
+    filled: usize,
+    running_status: Option<u8>,
+    // data of a cut event that is still in the source
+    skip: u32,
+    // bytes of the next event, it's decoded once more when it's taken
+    pending: Option<usize>,
+    done: bool,
+    tick: u64, // of the next event when merging
+}
+
+impl<C, const BUFFER: usize> TrackReader<C, BUFFER> {
+    fn new<S: SongSource<Cursor = C>>(source: &S, start: u32, end: u32) -> Self {
+        TrackReader {
+            start,
+            end,
+            cursor: source.cursor(),
+            position: start,
+            buffer: [0; BUFFER],
+            read: 0,
+            filled: 0,
+            running_status: None,
+            skip: 0,
+            pending: None,
+            done: false,
+            tick: 0,
+        }
+    }
+
+    fn rewind<S: SongSource<Cursor = C>>(&mut self, source: &mut S) {
+        source.seek(&mut self.cursor, self.start);
+        self.position = self.start;
+        self.read = 0;
+        self.filled = 0;
+        self.running_status = None;
+        self.skip = 0;
+        self.pending = None;
+        self.done = false;
+        self.tick = 0;
+    }
+
+    #[inline(always)]
+    fn window(&self) -> &[u8] {
+        &self.buffer[self.read..self.filled]
+    }
+
+    /// all bytes of the chunk are in the buffer
+    #[inline(always)]
+    fn exhausted(&self) -> bool {
+        self.position == self.end
+    }
+
+    /// moves the unread bytes to the front and fills the rest of the buffer
+    fn refill<S: SongSource<Cursor = C>>(&mut self, source: &mut S) -> Result<(), S::Error> {
+        if self.skip > 0 {
+            self.position = self.position.saturating_add(self.skip).min(self.end);
+            self.skip = 0;
+            source.seek(&mut self.cursor, self.position);
+        }
+        self.buffer.copy_within(self.read..self.filled, 0);
+        self.filled -= self.read;
+        self.read = 0;
+
+        while self.filled < BUFFER && !self.exhausted() {
+            let len = (BUFFER - self.filled).min((self.end - self.position) as usize);
+            let read = source.read(&mut self.cursor, &mut self.buffer[self.filled..][..len])?;
+            if read == 0 {
+                // the file is shorter than the source said
+                self.end = self.position;
+            }
+            self.filled += read;
+            self.position += read as u32;
+        }
+        Ok(())
+    }
+
+    /// gets the next event into the buffer and returns its delta, None once the track is over
+    fn prepare<S: SongSource<Cursor = C>>(
+        &mut self,
+        source: &mut S,
+    ) -> Result<Option<u32>, S::Error> {
+        if self.done {
+            return Ok(None);
+        }
+        if self.window().len() < MAX_EVENT_HEAD && !self.exhausted() {
+            self.refill(source)?;
+        }
+        if let Some(data) = varlen_data(self.window())
+            && data.end > self.window().len()
+            && !self.exhausted()
+        {
+            self.refill(source)?;
+            if data.end > self.window().len() && !self.exhausted() {
+                self.cut_data(data);
+            }
+        }
+
+        let window = self.window();
+        let mut events = EventIter::new(window);
+        *events.running_status_mut() = self.running_status;
+        let decoded = match events.next() {
+            Some(Ok(event)) => Some((window.len() - events.unread().len(), event.delta.as_int())),
+            _ => None,
+        };
+        match decoded {
+            Some((len, delta)) => {
+                self.pending = Some(len);
+                Ok(Some(delta))
+            }
+            // the end of the track or a broken event, both end the track like in midly
+            None => {
+                self.done = true;
+                Ok(None)
+            }
+        }
+    }
+
+    /// shortens the data of the event at the front of the full buffer to what fits in it,
+    /// the length is written back in as many bytes as before so nothing has to move
+    fn cut_data(&mut self, data: VarlenData) {
+        let kept = (self.filled - self.read - data.start) as u32;
+        self.skip = data.len - kept;
+
+        let length = &mut self.buffer[self.read + data.length_start..self.read + data.start];
+        let last = length.len() - 1;
+        for (i, byte) in length.iter_mut().enumerate() {
+            let continues = if i < last { 0x80 } else { 0 };
+            *byte = (kept >> (7 * (last - i))) as u8 & 0x7F | continues;
+        }
+    }
+
+    /// the event from prepare, it stays in the buffer until the next prepare
+    fn take(&mut self) -> Option<TrackEvent<'_>> {
+        let len = self.pending.take()?;
+        let event_bytes = &self.buffer[self.read..self.read + len];
+        let mut events = EventIter::new(event_bytes);
+        *events.running_status_mut() = self.running_status;
+        let event = events.next()?.ok()?;
+        self.running_status = events.running_status();
+        self.read += len;
+        Some(event)
+    }
+}
+
+/// where the data of a meta or sysex event starts and ends, counted from the event start
+#[derive(Debug, Clone, Copy)]
+struct VarlenData {
+    length_start: usize,
+    start: usize,
+    end: usize,
+    len: u32,
+}
+
+/// None for channel messages and when the bytes end before the data length
+fn varlen_data(bytes: &[u8]) -> Option<VarlenData> {
+    let mut i = 0;
+    read_varlen(bytes, &mut i)?;
+    let status = *bytes.get(i)?;
+    match status {
+        0xFF => i += 2,
+        0xF0 | 0xF7 => i += 1,
+        // running status only continues channel messages
+        _ => return None,
+    }
+    let length_start = i;
+    let len = read_varlen(bytes, &mut i)?;
+    Some(VarlenData {
+        length_start,
+        start: i,
+        end: i + len as usize,
+        len,
+    })
+}
+
+/// a variable length number of at most 4 bytes like midly reads them
+fn read_varlen(bytes: &[u8], i: &mut usize) -> Option<u32> {
+    let mut value = 0;
+    for _ in 0..4 {
+        let byte = *bytes.get(*i)?;
+        *i += 1;
+        value = value << 7 | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Some(value)
+}
+
+pub struct MidiReader<S: SongSource, const TRACKS: usize, const BUFFER: usize> {
+    source: S,
+    header: Header,
+    declared: usize, // tracks in the header
+    tracks: Vec<TrackReader<S::Cursor, BUFFER>, TRACKS>,
+    // the first read error, the track that ran into it ended there
+    error: Option<S::Error>,
+    // merging, like TrackMerger but over the track buffers
+    queue: BinaryHeap<(u64, usize), Min, TRACKS>,
+    tick: u64,
+    sequential: bool,
+    playing: Vec<usize, TRACKS>,
+    next_pattern: usize,
+    // the track of the last merged event, it reads on when the next event is asked for
+    taken: Option<usize>,
+}
+
+impl<S: SongSource, const TRACKS: usize, const BUFFER: usize> MidiReader<S, TRACKS, BUFFER> {
+    /// reads the header and finds the track chunks, RIFF wrapped midi files are not supported
+    pub fn open(mut source: S) -> Result<Self, ReaderError<S::Error>> {
+        const {
+            assert!(
+                BUFFER >= 2 * MAX_EVENT_HEAD,
+                "the track buffer is too small"
+            )
+        };
+
+        let mut cursor = source.cursor();
+        let mut header_bytes = [0; HEADER_LEN];
+        let len =
+            read_full(&mut source, &mut cursor, &mut header_bytes).map_err(ReaderError::Source)?;
+        // midly reads the header from the start of the file like it would from the whole file
+        let (header, tracks) = parse(&header_bytes[..len])?;
+        ticks_per_quarter(&header)?;
+        let declared = tracks.size_hint().0;
+
+        let mut reader = MidiReader {
+            source,
+            header,
+            declared,
+            tracks: Vec::new(),
+            error: None,
+            queue: BinaryHeap::new(),
+            tick: 0,
+            sequential: header.format == Format::Sequential,
+            playing: Vec::new(),
+            next_pattern: 0,
+            taken: None,
+        };
+        // the tracks come after the header chunk, whatever its length
+        let [_, _, _, _, a, b, c, d, ..] = header_bytes;
+        let first_chunk = u32::from_be_bytes([a, b, c, d]).saturating_add(CHUNK_HEAD_LEN);
+        reader.find_tracks(&mut cursor, first_chunk)?;
+        Ok(reader)
+    }
+
+    /// the chunks after the header, chunks that aren't tracks are skipped
+    /// and a chunk that runs past the end of the file gets the rest of it
+    fn find_tracks(
+        &mut self,
+        cursor: &mut S::Cursor,
+        mut position: u32,
+    ) -> Result<(), ReaderError<S::Error>> {
+        let size = self.source.size();
+        let mut track_count = 0;
+        while position < size {
+            self.source.seek(cursor, position);
+            let mut chunk_head = [0; CHUNK_HEAD_LEN as usize];
+            let len = read_full(&mut self.source, cursor, &mut chunk_head)
+                .map_err(ReaderError::Source)?;
+            // midly stops at a chunk without a whole id and length too
+            if len < chunk_head.len() {
+                break;
+            }
+            let [id @ .., a, b, c, d] = chunk_head;
+            let start = position + CHUNK_HEAD_LEN;
+            let end = start
+                .saturating_add(u32::from_be_bytes([a, b, c, d]))
+                .min(size);
+
+            if id == *b"MTrk" {
+                track_count += 1;
+                // keep counting, so the error tells how many tracks the song has
+                let _ = self.tracks.push(TrackReader::new(&self.source, start, end));
+            }
+            position = end;
+        }
+
+        if track_count > TRACKS {
+            return Err(SongError::TooManyTracks {
+                tracks: track_count,
+                capacity: TRACKS,
+            }
+            .into());
+        }
+        for track in &mut self.tracks {
+            track.rewind(&mut self.source);
+        }
+        Ok(())
+    }
+
+    pub const fn header(&self) -> Header {
+        self.header
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// the first error from the source, the track that ran into it ended there
+    pub fn source_error(&self) -> Option<&S::Error> {
+        self.error.as_ref()
+    }
+
+    /// every track back to its start, for reading the song again
+    pub fn rewind(&mut self) {
+        for track in &mut self.tracks {
+            track.rewind(&mut self.source);
+        }
+        self.queue.clear();
+        self.tick = 0;
+        self.playing.clear();
+        self.next_pattern = 0;
+        self.taken = None;
+    }
+
+    fn prepare(&mut self, track: usize) -> Option<u32> {
+        let reader = self.tracks.get_mut(track)?;
+        match reader.prepare(&mut self.source) {
+            Ok(delta) => delta,
+            Err(err) => {
+                reader.done = true;
+                self.error.get_or_insert(err);
+                None
+            }
+        }
+    }
+
+    /// the next event of a single track, None once the track is over or if there is no such track
+    pub fn next_event(&mut self, track: usize) -> Option<TrackEvent<'_>> {
+        self.prepare(track)?;
+        self.tracks[track].take()
+    }
+
+    /// reads every track once like check_song, passes each broken track to `broken_track`
+    /// and rewinds, returns the number of tracks
+    pub fn check(&mut self, mut broken_track: impl FnMut(SongError)) -> usize {
+        for track in 0..self.tracks.len() {
+            let mut tick = 0u64;
+            let mut ended = false;
+            while let Some(event) = self.next_event(track) {
+                tick += event.delta.as_int() as u64;
+                ended = matches!(event.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+            }
+            if !ended {
+                broken_track(SongError::Event { track, tick });
+            }
+        }
+        for track in self.tracks.len()..self.declared {
+            broken_track(SongError::MissingTrack {
+                track,
+                declared: self.declared,
+            });
+        }
+        self.rewind();
+        self.tracks.len()
+    }
+
+    /// starts merging the tracks from the start of the song, `plays_track` picks the tracks,
+    /// the patterns of a format 2 file play one after another
+    pub fn start_merge(&mut self, plays_track: impl Fn(usize) -> bool) {
+        self.rewind();
+        for track in (0..self.tracks.len()).filter(|track| plays_track(*track)) {
+            let _ = self.playing.push(track);
+        }
+        if self.sequential {
+            self.start_next_pattern();
+        } else {
+            for i in 0..self.playing.len() {
+                self.queue_track(self.playing[i]);
+            }
+        }
+    }
+
+    /// queues the next event of the track, false once the track is over
+    fn queue_track(&mut self, track: usize) -> bool {
+        let Some(delta) = self.prepare(track) else {
+            return false;
+        };
+        let reader = &mut self.tracks[track];
+        reader.tick += delta as u64;
+        // every track is queued at most once, so the queue can't be full
+        let _ = self.queue.push((reader.tick, track));
+        true
+    }
+
+    fn start_next_pattern(&mut self) {
+        while let Some(&track) = self.playing.get(self.next_pattern) {
+            self.next_pattern += 1;
+            self.tracks[track].tick = self.tick;
+            if self.queue_track(track) {
+                break;
+            }
+        }
+    }
+
+    /// the next event of the merged tracks, it can't be kept past the next call
+    pub fn next_merged(&mut self) -> Option<MergedEvent<'_>> {
+        if let Some(track) = self.taken.take() {
+            self.queue_track(track);
+        }
+        if self.queue.is_empty() && self.sequential {
+            self.start_next_pattern();
+        }
+        let (tick, track) = self.queue.pop()?;
+        self.tick = tick;
+        self.taken = Some(track);
+        let event = self.tracks[track].take()?;
+        Some(MergedEvent {
+            tick,
+            track,
+            kind: event.kind,
+        })
+    }
+}
+
+/// reads until the buffer is full or the source ends
+fn read_full<S: SongSource>(
+    source: &mut S,
+    cursor: &mut S::Cursor,
+    buffer: &mut [u8],
+) -> Result<usize, S::Error> {
+    let mut len = 0;
+    while len < buffer.len() {
+        let read = source.read(cursor, &mut buffer[len..])?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    Ok(len)
+}
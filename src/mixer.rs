@@ -0,0 +1,325 @@
+// =============================================================================================
+//                           CHANNEL MIXER AND ITS SERIAL CONSOLE
+// =============================================================================================
+
+// the loudness of a channel is its volume (CC 7) times its expression (CC 11), the buzzers
+// turn that into a duty cycle, a thinner pulse is a quieter note
+//
+// channels can be muted or soloed while the song plays, to hear the parts of an arrangement
+// on their own, one command per line on the serial console:
+//
+//   mute 9      unmute 9
+//   solo 0      unsolo 0
+//   reset       everything plays again
+//
+// the channels are numbered 0 - 15 like the tools print them
+
+use core::fmt;
+
+use midly::num::u4;
+
+/// volume of a channel before the song sets one, the general midi default
+pub const DEFAULT_VOLUME: u8 = 100;
+pub const MAX_LEVEL: u8 = 127;
+
+/// the loudness of the notes of a channel from 0 to MAX_LEVEL
+pub const fn channel_level(volume: u8, expression: u8) -> u8 {
+    let volume = if volume > MAX_LEVEL {
+        MAX_LEVEL
+    } else {
+        volume
+    };
+    let expression = if expression > MAX_LEVEL {
+        MAX_LEVEL
+    } else {
+        expression
+    };
+    (volume as u16 * expression as u16 / MAX_LEVEL as u16) as u8
+}
+
+/// the duty cycle of a level, full_duty_pct is the loudest the buzzer gets
+pub const fn level_duty_pct(level: u8, full_duty_pct: u8) -> u8 {
+    let level = if level > MAX_LEVEL { MAX_LEVEL } else { level };
+    let duty_pct = (level as u16 * full_duty_pct as u16 / MAX_LEVEL as u16) as u8;
+    // the quietest levels still make a sound
+    if duty_pct == 0 && level > 0 {
+        1
+    } else {
+        duty_pct
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixerCommand {
+    Mute(u4),
+    Unmute(u4),
+    Solo(u4),
+    Unsolo(u4),
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    LineTooLong,
+    UnknownCommand,
+    BadChannel,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problem = match self {
+            CommandError::LineTooLong => "the line is too long",
+            CommandError::UnknownCommand => "expected mute, unmute, solo, unsolo or reset",
+            CommandError::BadChannel => "channels go from 0 to 15",
+        };
+        f.write_str(problem)
+    }
+}
+
+/// which channels are heard, one bit per channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mixer {
+    muted: u16,
+    soloed: u16,
+}
+
+impl Mixer {
+    pub const fn new() -> Self {
+        Mixer {
+            muted: 0,
+            soloed: 0,
+        }
+    }
+
+    /// a soloed channel plays even when it's muted, and once anything is soloed only the
+    /// soloed channels play
+    pub fn plays(&self, channel: u4) -> bool {
+        let bit = 1 << channel.as_int();
+        if self.soloed != 0 {
+            self.soloed & bit != 0
+        } else {
+            self.muted & bit == 0
+        }
+    }
+
+    pub fn apply(&mut self, command: MixerCommand) {
+        match command {
+            MixerCommand::Mute(channel) => self.muted |= 1 << channel.as_int(),
+            MixerCommand::Unmute(channel) => self.muted &= !(1 << channel.as_int()),
+            MixerCommand::Solo(channel) => self.soloed |= 1 << channel.as_int(),
+            MixerCommand::Unsolo(channel) => self.soloed &= !(1 << channel.as_int()),
+            MixerCommand::Reset => *self = Mixer::new(),
+        }
+    }
+}
+
+/// the channels that play, a dot for the silent ones: "0123.56789abcdef"
+impl fmt::Display for Mixer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for channel in 0..16 {
+            let shown = match self.plays(u4::new(channel)) {
+                true => DIGITS[channel as usize] as char,
+                false => '.',
+            };
+            write!(f, "{}", shown)?;
+        }
+        Ok(())
+    }
+}
+
+/// collects the console bytes into lines, longer lines than LINE bytes are an error
+pub struct CommandReader<const LINE: usize> {
+    line: heapless::Vec<u8, LINE>,
+    too_long: bool,
+}
+
+impl<const LINE: usize> Default for CommandReader<LINE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LINE: usize> CommandReader<LINE> {
+    pub const fn new() -> Self {
+        CommandReader {
+            line: heapless::Vec::new(),
+            too_long: false,
+        }
+    }
+
+    /// the command once the byte ends a line, terminals send \r, \n or both
+    pub fn feed(&mut self, byte: u8) -> Option<Result<MixerCommand, CommandError>> {
+        if byte != b'\r' && byte != b'\n' {
+            if self.line.push(byte).is_err() {
+                self.too_long = true;
+            }
+            return None;
+        }
+
+        let parsed = match self.too_long {
+            true => Some(Err(CommandError::LineTooLong)),
+            false => parse_command(&self.line).transpose(),
+        };
+        self.line.clear();
+        self.too_long = false;
+        parsed
+    }
+}
+
+/// the command of a line, None for a blank line
+pub fn parse_command(line: &[u8]) -> Result<Option<MixerCommand>, CommandError> {
+    let mut words = line
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|word| !word.is_empty());
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let channel = words.next();
+    if words.next().is_some() {
+        return Err(CommandError::UnknownCommand);
+    }
+
+    let command: fn(u4) -> MixerCommand = match name {
+        b"mute" => MixerCommand::Mute,
+        b"unmute" => MixerCommand::Unmute,
+        b"solo" => MixerCommand::Solo,
+        b"unsolo" => MixerCommand::Unsolo,
+        b"reset" if channel.is_none() => return Ok(Some(MixerCommand::Reset)),
+        _ => return Err(CommandError::UnknownCommand),
+    };
+    let channel = channel.ok_or(CommandError::BadChannel)?;
+    let number = core::str::from_utf8(channel)
+        .ok()
+        .and_then(|number| number.parse::<u8>().ok())
+        .filter(|number| *number < 16)
+        .ok_or(CommandError::BadChannel)?;
+    Ok(Some(command(u4::new(number))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::ToString, vec::Vec};
+
+    use super::*;
+
+    fn mixer(commands: &[MixerCommand]) -> Mixer {
+        let mut mixer = Mixer::new();
+        for command in commands {
+            mixer.apply(*command);
+        }
+        mixer
+    }
+
+    #[test]
+    fn the_lines_become_commands() {
+        let ch = u4::new;
+        assert_eq!(
+            parse_command(b"mute 9"),
+            Ok(Some(MixerCommand::Mute(ch(9))))
+        );
+        assert_eq!(
+            parse_command(b"  unmute\t15 "),
+            Ok(Some(MixerCommand::Unmute(ch(15))))
+        );
+        assert_eq!(
+            parse_command(b"solo 0"),
+            Ok(Some(MixerCommand::Solo(ch(0))))
+        );
+        assert_eq!(
+            parse_command(b"unsolo 3"),
+            Ok(Some(MixerCommand::Unsolo(ch(3))))
+        );
+        assert_eq!(parse_command(b"reset"), Ok(Some(MixerCommand::Reset)));
+        assert_eq!(parse_command(b""), Ok(None));
+        assert_eq!(parse_command(b"   "), Ok(None));
+
+        assert_eq!(parse_command(b"mute"), Err(CommandError::BadChannel));
+        assert_eq!(parse_command(b"mute 16"), Err(CommandError::BadChannel));
+        assert_eq!(parse_command(b"mute -1"), Err(CommandError::BadChannel));
+        assert_eq!(parse_command(b"mute nine"), Err(CommandError::BadChannel));
+        assert_eq!(
+            parse_command(b"mute 1 2"),
+            Err(CommandError::UnknownCommand)
+        );
+        assert_eq!(parse_command(b"reset 1"), Err(CommandError::UnknownCommand));
+        assert_eq!(parse_command(b"MUTE 1"), Err(CommandError::UnknownCommand));
+    }
+
+    #[test]
+    fn the_reader_ends_a_line_at_any_line_break() {
+        let mut reader = CommandReader::<8>::new();
+        let mut results = Vec::new();
+        for byte in b"mute 1\rsolo 2\n\r\nreset\r\nmute 99999\nunmute 3\n" {
+            results.extend(reader.feed(*byte));
+        }
+        assert_eq!(
+            results,
+            [
+                Ok(MixerCommand::Mute(u4::new(1))),
+                Ok(MixerCommand::Solo(u4::new(2))),
+                Ok(MixerCommand::Reset),
+                Err(CommandError::LineTooLong),
+                Ok(MixerCommand::Unmute(u4::new(3))),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_solo_wins_over_a_mute() {
+        let ch = u4::new;
+        let muted = mixer(&[MixerCommand::Mute(ch(9))]);
+        assert!(!muted.plays(ch(9)));
+        assert!(muted.plays(ch(0)));
+
+        // only the soloed channels play, even the muted ones
+        let soloed = mixer(&[
+            MixerCommand::Mute(ch(9)),
+            MixerCommand::Solo(ch(9)),
+            MixerCommand::Solo(ch(2)),
+        ]);
+        assert!(soloed.plays(ch(9)));
+        assert!(soloed.plays(ch(2)));
+        assert!(!soloed.plays(ch(0)));
+        assert_eq!(soloed.to_string(), "..2......9......");
+
+        // the mute is back once the solos are gone
+        let unsoloed = mixer(&[
+            MixerCommand::Mute(ch(9)),
+            MixerCommand::Solo(ch(9)),
+            MixerCommand::Unsolo(ch(9)),
+        ]);
+        assert_eq!(unsoloed.to_string(), "012345678.abcdef");
+
+        let reset = mixer(&[
+            MixerCommand::Mute(ch(1)),
+            MixerCommand::Solo(ch(2)),
+            MixerCommand::Reset,
+        ]);
+        assert_eq!(reset, Mixer::new());
+        assert_eq!(reset.to_string(), "0123456789abcdef");
+    }
+
+    #[test]
+    fn the_level_follows_the_volume_and_the_expression() {
+        assert_eq!(channel_level(127, 127), 127);
+        assert_eq!(channel_level(DEFAULT_VOLUME, 127), DEFAULT_VOLUME);
+        assert_eq!(channel_level(127, 64), 64);
+        assert_eq!(channel_level(64, 64), 32);
+        assert_eq!(channel_level(0, 127), 0);
+        assert_eq!(channel_level(127, 0), 0);
+        // out of range values count as the loudest
+        assert_eq!(channel_level(255, 200), 127);
+    }
+
+    #[test]
+    fn the_duty_cycle_follows_the_level() {
+        assert_eq!(level_duty_pct(MAX_LEVEL, 50), 50);
+        assert_eq!(level_duty_pct(64, 50), 25);
+        assert_eq!(level_duty_pct(0, 50), 0);
+        // the quietest levels still make a sound
+        assert_eq!(level_duty_pct(1, 50), 1);
+        assert_eq!(level_duty_pct(2, 50), 1);
+        assert_eq!(level_duty_pct(200, 50), 50);
+    }
+}
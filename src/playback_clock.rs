@@ -0,0 +1,101 @@
+// =============================================================================================
+//                        PLAYBACK CLOCK WITH AN ADJUSTABLE SPEED
+// =============================================================================================
+
+// turns positions in the song into real time deadlines, the song timings come from the
+// tempo map and the speed only stretches them, so the pitch of the notes doesn't change
+//
+// every deadline is counted from the last speed change instead of adding up the waits,
+// so rounding and the time spent handling the events don't build up over the song
+
+pub const MIN_SPEED_PCT: u16 = 25;
+pub const MAX_SPEED_PCT: u16 = 400;
+pub const NORMAL_SPEED_PCT: u16 = 100;
+
+// how much faster the real clock runs than it should, in parts per million,
+// measure a long song at 100 % against a stopwatch and put the difference here
+pub const CLOCK_CALIBRATION_PPM: i32 = 0;
+
+/// real micro seconds for song micro seconds played at the speed
+pub const fn scale_micros(song_micros: u64, speed_pct: u16) -> u64 {
+    calibrated_micros(song_micros, speed_pct, CLOCK_CALIBRATION_PPM)
+}
+
+/// scale_micros on a clock that runs calibration_ppm too fast
+pub const fn calibrated_micros(song_micros: u64, speed_pct: u16, calibration_ppm: i32) -> u64 {
+    let speed_pct = clamp_speed(speed_pct) as u128;
+    let calibrated = (1_000_000 + calibration_ppm as i64) as u128;
+    (song_micros as u128 * 100 * calibrated / (speed_pct * 1_000_000)) as u64
+}
+
+pub const fn clamp_speed(speed_pct: u16) -> u16 {
+    if speed_pct < MIN_SPEED_PCT {
+        MIN_SPEED_PCT
+    } else if speed_pct > MAX_SPEED_PCT {
+        MAX_SPEED_PCT
+    } else {
+        speed_pct
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaybackClock {
+    speed_pct: u16,
+    song_micros: u64,
+    // the song and real time of the last start or speed change
+    anchor_song_micros: u64,
+    anchor_real_micros: u64,
+}
+
+impl PlaybackClock {
+    pub const fn new(speed_pct: u16) -> Self {
+        PlaybackClock {
+            speed_pct: clamp_speed(speed_pct),
+            song_micros: 0,
+            anchor_song_micros: 0,
+            anchor_real_micros: 0,
+        }
+    }
+
+    /// the song starts from the beginning at the given real time
+    pub fn start(&mut self, now_micros: u64) {
+        self.song_micros = 0;
+        self.anchor_song_micros = 0;
+        self.anchor_real_micros = now_micros;
+    }
+
+    pub const fn speed_pct(&self) -> u16 {
+        self.speed_pct
+    }
+
+    /// the song time already played stays where it is, only the rest is stretched
+    pub fn set_speed(&mut self, speed_pct: u16) {
+        let speed_pct = clamp_speed(speed_pct);
+        if speed_pct != self.speed_pct {
+            self.anchor_real_micros = self.deadline_micros();
+            self.anchor_song_micros = self.song_micros;
+            self.speed_pct = speed_pct;
+        }
+    }
+
+    /// moves the song position forward and returns the real time to wait until
+    pub fn advance(&mut self, song_micros: u64) -> u64 {
+        self.song_micros += song_micros;
+        self.deadline_micros()
+    }
+
+    /// the real time of the current song position
+    pub const fn deadline_micros(&self) -> u64 {
+        self.real_micros_at(self.song_micros)
+    }
+
+    /// the real time of a song position after the last speed change,
+    /// earlier positions are already played and get the time of the change
+    pub const fn real_micros_at(&self, song_micros: u64) -> u64 {
+        self.anchor_real_micros
+            + scale_micros(
+                song_micros.saturating_sub(self.anchor_song_micros),
+                self.speed_pct,
+            )
+    }
+}
@@ -0,0 +1,367 @@
+// =============================================================================================
+//                              PROFILE BANK TEXT FORMAT
+// =============================================================================================
+
+// instrument sounds that replace the built in ones, one program per line:
+//
+//   # program  wait_time  change_per_key  duration
+//   40         3600       126             -
+//   45         5100       76              150000
+//
+// the numbers are the fields of SoundProfile: micro seconds between pin toggles at key 64,
+// how much that changes per key, and how long the note sounds in micro seconds, or - to
+// hold it until the note off
+// the key range and the vibrato stay as the settings have them, so what the profile tuner
+// saved of those isn't lost when the bank is read again at the next start
+//
+// everything after a # is a comment, blank lines are skipped
+//
+// the text is fed in pieces of any size, so it's read from the SD card a chunk at a time
+// without keeping all of it in memory
+
+use core::fmt;
+
+use crate::{settings::ProfileTweak, sound_profiles::PROGRAM_NAMES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BankErrorKind {
+    LineTooLong,
+    MissingField,
+    ExtraField,
+    BadNumber,
+    ProgramOutOfRange,
+    NegativeDuration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    pub line: usize, // counted from 1
+    pub kind: BankErrorKind,
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let problem = match self.kind {
+            BankErrorKind::LineTooLong => "the line is too long",
+            BankErrorKind::MissingField => {
+                "expected program, wait time, change per key and duration"
+            }
+            BankErrorKind::ExtraField => "more than four fields",
+            BankErrorKind::BadNumber => "not a number in range",
+            BankErrorKind::ProgramOutOfRange => "programs go from 0 to 127",
+            BankErrorKind::NegativeDuration => "the duration is - or a positive number",
+        };
+        write!(f, "line {}: {}", self.line, problem)
+    }
+}
+
+/// the fields of a sound a bank line sets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankEntry {
+    pub program: u8,
+    pub wait_time: u16,
+    pub wait_change_per_key: u16,
+    pub duration: Option<i32>,
+}
+
+impl BankEntry {
+    /// the tweak with the fields of the line, the rest of it is kept
+    pub const fn apply(&self, tweak: ProfileTweak) -> ProfileTweak {
+        ProfileTweak {
+            program: self.program,
+            wait_time: self.wait_time,
+            wait_change_per_key: self.wait_change_per_key,
+            duration: self.duration,
+            ..tweak
+        }
+    }
+}
+
+/// reads a bank a piece at a time, lines longer than LINE bytes are an error
+pub struct BankReader<const LINE: usize> {
+    line: heapless::Vec<u8, LINE>,
+    line_number: usize,
+    too_long: bool,
+}
+
+impl<const LINE: usize> Default for BankReader<LINE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const LINE: usize> BankReader<LINE> {
+    pub const fn new() -> Self {
+        BankReader {
+            line: heapless::Vec::new(),
+            line_number: 0,
+            too_long: false,
+        }
+    }
+
+    /// calls found for every complete line with a profile
+    pub fn feed(
+        &mut self,
+        bytes: &[u8],
+        found: &mut impl FnMut(BankEntry),
+    ) -> Result<(), BankError> {
+        for byte in bytes {
+            if *byte == b'\n' {
+                self.end_line(found)?;
+            } else if self.line.push(*byte).is_err() {
+                self.too_long = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// the last line doesn't need a line break
+    pub fn finish(mut self, found: &mut impl FnMut(BankEntry)) -> Result<(), BankError> {
+        if !self.line.is_empty() || self.too_long {
+            self.end_line(found)?;
+        }
+        Ok(())
+    }
+
+    fn end_line(&mut self, found: &mut impl FnMut(BankEntry)) -> Result<(), BankError> {
+        self.line_number += 1;
+        let error = |kind| BankError {
+            line: self.line_number,
+            kind,
+        };
+        if self.too_long {
+            return Err(error(BankErrorKind::LineTooLong));
+        }
+        let parsed = parse_line(&self.line).map_err(error)?;
+        self.line.clear();
+        if let Some(entry) = parsed {
+            found(entry);
+        }
+        Ok(())
+    }
+}
+
+/// the entry of the line, None for blank and comment lines
+pub fn parse_line(line: &[u8]) -> Result<Option<BankEntry>, BankErrorKind> {
+    let line = match line.iter().position(|byte| *byte == b'#') {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    let mut fields = line
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|field| !field.is_empty());
+    let Some(program) = fields.next() else {
+        return Ok(None);
+    };
+    let mut next = || fields.next().ok_or(BankErrorKind::MissingField);
+    let (wait_time, change, duration) = (next()?, next()?, next()?);
+    if fields.next().is_some() {
+        return Err(BankErrorKind::ExtraField);
+    }
+
+    let program: u32 = parse_number(program)?;
+    if program > 127 {
+        return Err(BankErrorKind::ProgramOutOfRange);
+    }
+    let duration = match duration {
+        b"-" => None,
+        [b'-', ..] => return Err(BankErrorKind::NegativeDuration),
+        duration => Some(parse_number(duration)?),
+    };
+    Ok(Some(BankEntry {
+        program: program as u8,
+        wait_time: parse_number(wait_time)?,
+        wait_change_per_key: parse_number(change)?,
+        duration,
+    }))
+}
+
+fn parse_number<T: TryFrom<u32>>(field: &[u8]) -> Result<T, BankErrorKind> {
+    let mut value: u32 = 0;
+    for digit in field {
+        if !digit.is_ascii_digit() {
+            return Err(BankErrorKind::BadNumber);
+        }
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add((digit - b'0') as u32))
+            .ok_or(BankErrorKind::BadNumber)?;
+    }
+    T::try_from(value).map_err(|_| BankErrorKind::BadNumber)
+}
+
+/// writes the line of a profile in the bank format
+pub fn write_line(out: &mut impl fmt::Write, tweak: &ProfileTweak) -> fmt::Result {
+    write!(
+        out,
+        "{:<10} {:<10} {:<15} ",
+        tweak.program, tweak.wait_time, tweak.wait_change_per_key
+    )?;
+    match tweak.duration {
+        Some(duration) => write!(out, "{}", duration),
+        None => write!(out, "-"),
+    }
+}
+
+/// writes the built in sounds as a bank to start tuning from
+pub fn write_built_in_bank(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "# program  wait_time  change_per_key  duration")?;
+    for (program, name) in PROGRAM_NAMES.iter().enumerate() {
+        write_line(out, &ProfileTweak::from_instrument(program as u8))?;
+        writeln!(out, "  # {}", name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{string::String, vec::Vec};
+
+    use super::*;
+    use crate::{settings::Settings, vibrato::Vibrato};
+
+    const fn entry(program: u8, wait_time: u16, change: u16, duration: Option<i32>) -> BankEntry {
+        BankEntry {
+            program,
+            wait_time,
+            wait_change_per_key: change,
+            duration,
+        }
+    }
+
+    // same as the line buffer of the firmware
+    const LINE_LEN: usize = 128;
+
+    /// the entries of the bank fed in pieces of the size
+    fn read(bank: &[u8], piece: usize) -> Result<Vec<BankEntry>, BankError> {
+        let mut entries = Vec::new();
+        let mut found = |entry| entries.push(entry);
+        let mut reader = BankReader::<LINE_LEN>::new();
+        for bytes in bank.chunks(piece) {
+            reader.feed(bytes, &mut found)?;
+        }
+        reader.finish(&mut found)?;
+        Ok(entries)
+    }
+
+    #[test]
+    fn the_fields_of_a_line_become_an_entry() {
+        assert_eq!(
+            parse_line(b"40 3600 126 -"),
+            Ok(Some(entry(40, 3600, 126, None)))
+        );
+        assert_eq!(
+            parse_line(b"\t45   5100 76\t150000  # flute"),
+            Ok(Some(entry(45, 5100, 76, Some(150000))))
+        );
+        assert_eq!(parse_line(b""), Ok(None));
+        assert_eq!(parse_line(b"   "), Ok(None));
+        assert_eq!(parse_line(b"# 40 3600 126 -"), Ok(None));
+    }
+
+    #[test]
+    fn every_broken_line_tells_what_is_wrong() {
+        let broken: [(&[u8], BankErrorKind); 9] = [
+            (b"40 3600 126", BankErrorKind::MissingField),
+            (b"40 # 3600 126 -", BankErrorKind::MissingField),
+            (b"40 3600 126 - 5", BankErrorKind::ExtraField),
+            (b"forty 3600 126 -", BankErrorKind::BadNumber),
+            (b"40 3600 +126 -", BankErrorKind::BadNumber),
+            (b"40 70000 126 -", BankErrorKind::BadNumber),
+            (b"40 3600 126 99999999999", BankErrorKind::BadNumber),
+            (b"128 3600 126 -", BankErrorKind::ProgramOutOfRange),
+            (b"40 3600 126 -5", BankErrorKind::NegativeDuration),
+        ];
+        for (line, kind) in broken {
+            assert_eq!(parse_line(line), Err(kind), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn pieces_of_any_size_read_the_same_bank() {
+        // windows line breaks, comments, blank lines and no line break at the end
+        let bank = b"# program  wait_time  change_per_key  duration\r\n\
+                     40 3600 126 -  # violin\r\n\
+                     \r\n\
+                     45 5100 76 150000\n\
+                     \n\
+                     # the end\n\
+                     0 1000 10 2000";
+        let expected = [
+            entry(40, 3600, 126, None),
+            entry(45, 5100, 76, Some(150000)),
+            entry(0, 1000, 10, Some(2000)),
+        ];
+        for piece in 1..=bank.len() {
+            assert_eq!(read(bank, piece), Ok(expected.to_vec()), "{} bytes", piece);
+        }
+    }
+
+    #[test]
+    fn an_error_tells_its_line() {
+        let mut long = b"40 3600 126 -\n".to_vec();
+        long.extend_from_slice(&[b'#'; LINE_LEN + 1]);
+        long.extend_from_slice(b"\n45 5100 76 -\n");
+        for piece in [1, 7, long.len()] {
+            assert_eq!(
+                read(&long, piece),
+                Err(BankError {
+                    line: 2,
+                    kind: BankErrorKind::LineTooLong
+                })
+            );
+        }
+        // a line just as long as the buffer fits
+        assert_eq!(read(&[b' '; LINE_LEN], 5), Ok(Vec::new()));
+        assert_eq!(
+            read(&[b' '; LINE_LEN + 1], 5).map_err(|err| err.kind),
+            Err(BankErrorKind::LineTooLong)
+        );
+
+        let bad = b"40 3600 126 -\r\n\r\n45 5100 76\r\n";
+        assert_eq!(
+            read(bad, 4),
+            Err(BankError {
+                line: 3,
+                kind: BankErrorKind::MissingField
+            })
+        );
+    }
+
+    #[test]
+    fn an_entry_keeps_the_key_range_and_the_vibrato() {
+        let tuned = ProfileTweak {
+            lowest_key: 48,
+            highest_key: 72,
+            vibrato: Vibrato::new(600, 30, 200),
+            ..ProfileTweak::from_instrument(40)
+        };
+        let tweak = entry(40, 3000, 100, Some(90000)).apply(tuned);
+        assert_eq!(
+            tweak,
+            ProfileTweak {
+                wait_time: 3000,
+                wait_change_per_key: 100,
+                duration: Some(90000),
+                ..tuned
+            }
+        );
+    }
+
+    #[test]
+    fn the_exported_bank_reads_back_as_the_built_in_sounds() {
+        let mut bank = String::new();
+        write_built_in_bank(&mut bank).unwrap();
+        let entries = read(bank.as_bytes(), 64).unwrap();
+        assert_eq!(entries.len(), 128);
+
+        let mut settings = Settings::default();
+        for (program, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.program as usize, program);
+            let tweak = entry.apply(settings.tweak(entry.program));
+            assert_eq!(tweak, ProfileTweak::from_instrument(entry.program));
+            settings.set_tweak(tweak).unwrap();
+        }
+        assert_eq!(settings, Settings::default());
+    }
+}
@@ -0,0 +1,439 @@
+// =============================================================================================
+//                          TUNING THE INSTRUMENT SOUNDS BY EAR
+// =============================================================================================
+
+// edits the sound of one program at a time and plays it after every change:
+//   button: moves to the next field, the fields are the ones of SoundProfile
+//   turning: changes the field, on the program field it picks the program to tune,
+//            on the save field right saves the sound and left goes back to the built in one
+//
+// the test notes go through the voice sink like the notes of a song, on a channel of their own
+
+use core::fmt;
+
+use midly::num::{u4, u7};
+
+use crate::settings::{ProfileTweak, Settings};
+use crate::sound_profiles::{PROGRAM_NAMES, SoundProfile};
+use crate::voice_queue::VoiceSink;
+
+const TEST_CHANNEL: u4 = u4::new(15);
+
+const WAIT_TIME_STEP: u16 = 20;
+const CHANGE_PER_KEY_STEP: u16 = 1;
+const DURATION_STEP_MICROS: i32 = 10_000;
+const MAX_DURATION_MICROS: i32 = 2_000_000;
+const VIBRATO_DEPTH_STEP_CENTS: u8 = 2;
+const MAX_VIBRATO_DEPTH_CENTS: u8 = 100;
+const VIBRATO_RATE_STEP_CENTIHERTZ: u16 = 25;
+const MIN_VIBRATO_RATE_CENTIHERTZ: u16 = 100;
+const MAX_VIBRATO_RATE_CENTIHERTZ: u16 = 1200;
+const VIBRATO_DELAY_STEP_MILLIS: u16 = 50;
+const MAX_VIBRATO_DELAY_MILLIS: u16 = 2000;
+
+// key 64 is where the wait time is the period, the scale goes an octave up from middle C
+const TEST_NOTE: [u8; 1] = [64];
+const TEST_SCALE: [u8; 8] = [60, 62, 64, 65, 67, 69, 71, 72];
+const NOTE_MICROS: u64 = 300_000;
+const NOTE_GAP_MICROS: u64 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TunerField {
+    Program,
+    WaitTime,
+    ChangePerKey,
+    Duration,
+    LowestKey,
+    HighestKey,
+    VibratoDepth,
+    VibratoRate,
+    VibratoDelay,
+    TestPhrase,
+    Save,
+}
+
+impl TunerField {
+    const fn next(self) -> Self {
+        match self {
+            TunerField::Program => TunerField::WaitTime,
+            TunerField::WaitTime => TunerField::ChangePerKey,
+            TunerField::ChangePerKey => TunerField::Duration,
+            TunerField::Duration => TunerField::LowestKey,
+            TunerField::LowestKey => TunerField::HighestKey,
+            TunerField::HighestKey => TunerField::VibratoDepth,
+            TunerField::VibratoDepth => TunerField::VibratoRate,
+            TunerField::VibratoRate => TunerField::VibratoDelay,
+            TunerField::VibratoDelay => TunerField::TestPhrase,
+            TunerField::TestPhrase => TunerField::Save,
+            TunerField::Save => TunerField::Program,
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            TunerField::Program => "program",
+            TunerField::WaitTime => "wait time",
+            TunerField::ChangePerKey => "change per key",
+            TunerField::Duration => "duration",
+            TunerField::LowestKey => "lowest key",
+            TunerField::HighestKey => "highest key",
+            TunerField::VibratoDepth => "vibrato",
+            TunerField::VibratoRate => "rate",
+            TunerField::VibratoDelay => "delay",
+            TunerField::TestPhrase => "test",
+            TunerField::Save => "save",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TestPhrase {
+    Note,
+    Scale,
+}
+
+impl TestPhrase {
+    const fn keys(self) -> &'static [u8] {
+        match self {
+            TestPhrase::Note => &TEST_NOTE,
+            TestPhrase::Scale => &TEST_SCALE,
+        }
+    }
+}
+
+pub struct ProfileTuner {
+    field: TunerField,
+    tweak: ProfileTweak,
+    phrase: TestPhrase,
+    // the next note of the test phrase and when it starts, None when the phrase is over
+    next_note: Option<(usize, u64)>,
+    sounding: Option<(u7, u64)>, // key and when it ends
+}
+
+impl ProfileTuner {
+    /// starts with the sound program 0 has in the settings
+    pub fn new(settings: &Settings) -> Self {
+        ProfileTuner {
+            field: TunerField::Program,
+            tweak: settings.tweak(0),
+            phrase: TestPhrase::Note,
+            next_note: None,
+            sounding: None,
+        }
+    }
+
+    pub const fn next_field(&mut self) {
+        self.field = self.field.next();
+    }
+
+    /// changes the field a step to the right or left and plays the sound again,
+    /// gives the tweak to save when turned on the save field
+    pub fn turn(&mut self, right: bool, settings: &Settings, now: u64) -> Option<ProfileTweak> {
+        let tweak = &mut self.tweak;
+        match self.field {
+            TunerField::Program => {
+                let program = match right {
+                    true => (tweak.program + 1) % 128,
+                    false => (tweak.program + 127) % 128,
+                };
+                *tweak = settings.tweak(program);
+            }
+            TunerField::WaitTime => tweak.wait_time = step(tweak.wait_time, WAIT_TIME_STEP, right),
+            TunerField::ChangePerKey => {
+                tweak.wait_change_per_key =
+                    step(tweak.wait_change_per_key, CHANGE_PER_KEY_STEP, right)
+            }
+            // held notes are below the shortest duration
+            TunerField::Duration => {
+                tweak.duration = match (tweak.duration, right) {
+                    (None, true) => Some(DURATION_STEP_MICROS),
+                    (None, false) => None,
+                    (Some(duration), true) => {
+                        Some((duration + DURATION_STEP_MICROS).min(MAX_DURATION_MICROS))
+                    }
+                    (Some(duration), false) => Some(duration - DURATION_STEP_MICROS)
+                        .filter(|duration| *duration >= DURATION_STEP_MICROS),
+                }
+            }
+            // the range can't turn inside out, notes outside it are moved by octaves
+            TunerField::LowestKey => {
+                tweak.lowest_key = match right {
+                    true => (tweak.lowest_key + 1).min(tweak.highest_key),
+                    false => tweak.lowest_key.saturating_sub(1),
+                }
+            }
+            TunerField::HighestKey => {
+                tweak.highest_key = match right {
+                    true => (tweak.highest_key + 1).min(127),
+                    false => tweak.highest_key.saturating_sub(1).max(tweak.lowest_key),
+                }
+            }
+            TunerField::VibratoDepth => {
+                let depth = &mut tweak.vibrato.depth_cents;
+                *depth = match right {
+                    true => depth
+                        .saturating_add(VIBRATO_DEPTH_STEP_CENTS)
+                        .min(MAX_VIBRATO_DEPTH_CENTS),
+                    false => depth.saturating_sub(VIBRATO_DEPTH_STEP_CENTS),
+                }
+            }
+            TunerField::VibratoRate => {
+                let rate = &mut tweak.vibrato.rate_centihertz;
+                *rate = step(*rate, VIBRATO_RATE_STEP_CENTIHERTZ, right)
+                    .clamp(MIN_VIBRATO_RATE_CENTIHERTZ, MAX_VIBRATO_RATE_CENTIHERTZ)
+            }
+            TunerField::VibratoDelay => {
+                let delay = &mut tweak.vibrato.delay_millis;
+                *delay =
+                    step(*delay, VIBRATO_DELAY_STEP_MILLIS, right).min(MAX_VIBRATO_DELAY_MILLIS)
+            }
+            TunerField::TestPhrase => {
+                self.phrase = match self.phrase {
+                    TestPhrase::Note => TestPhrase::Scale,
+                    TestPhrase::Scale => TestPhrase::Note,
+                }
+            }
+            TunerField::Save => {
+                if !right {
+                    *tweak = ProfileTweak::from_instrument(tweak.program);
+                }
+                return Some(*tweak);
+            }
+        }
+        self.next_note = Some((0, now));
+        None
+    }
+
+    /// plays the test phrase, called often from the tuning loop
+    pub fn update(&mut self, now: u64, voices: &mut impl VoiceSink<(u4, u7), SoundProfile>) {
+        if let Some((key, ends_at)) = self.sounding
+            && (now >= ends_at || self.next_note.is_some_and(|(index, _)| index == 0))
+        {
+            voices.note_off((TEST_CHANNEL, key));
+            self.sounding = None;
+        }
+
+        let Some((index, starts_at)) = self.next_note else {
+            return;
+        };
+        if now < starts_at || self.sounding.is_some() {
+            return;
+        }
+        let keys = self.phrase.keys();
+        let key = u7::new(keys[index]);
+        voices.note_on((TEST_CHANNEL, key), self.tweak.profile());
+        let ends_at = now + NOTE_MICROS;
+        self.sounding = Some((key, ends_at));
+        self.next_note = (index + 1 < keys.len()).then_some((index + 1, ends_at + NOTE_GAP_MICROS));
+    }
+
+    /// stops the test phrase
+    pub fn stop(&mut self, voices: &mut impl VoiceSink<(u4, u7), SoundProfile>) {
+        if let Some((key, _)) = self.sounding.take() {
+            voices.note_off((TEST_CHANNEL, key));
+        }
+        self.next_note = None;
+    }
+}
+
+fn step(value: u16, step: u16, up: bool) -> u16 {
+    match up {
+        true => value.saturating_add(step),
+        false => value.saturating_sub(step),
+    }
+}
+
+/// one line with the program, its sound and the selected field in brackets
+impl fmt::Display for ProfileTuner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tweak = &self.tweak;
+        let mark = |field: TunerField| match field == self.field {
+            true => ('[', ']'),
+            false => (' ', ' '),
+        };
+
+        let (open, close) = mark(TunerField::Program);
+        write!(
+            f,
+            "{}{} {}{}",
+            open, tweak.program, PROGRAM_NAMES[tweak.program as usize], close
+        )?;
+        for field in [TunerField::WaitTime, TunerField::ChangePerKey] {
+            let value = match field {
+                TunerField::WaitTime => tweak.wait_time,
+                _ => tweak.wait_change_per_key,
+            };
+            let (open, close) = mark(field);
+            write!(f, " {}{} {}us{}", open, field.name(), value, close)?;
+        }
+
+        let (open, close) = mark(TunerField::Duration);
+        match tweak.duration {
+            Some(duration) => write!(f, " {}duration {}ms{}", open, duration / 1000, close)?,
+            None => write!(f, " {}duration held{}", open, close)?,
+        }
+        for field in [TunerField::LowestKey, TunerField::HighestKey] {
+            let key = match field {
+                TunerField::LowestKey => tweak.lowest_key,
+                _ => tweak.highest_key,
+            };
+            let (open, close) = mark(field);
+            write!(f, " {}{} {}{}", open, field.name(), key, close)?;
+        }
+
+        let vibrato = &tweak.vibrato;
+        let (open, close) = mark(TunerField::VibratoDepth);
+        write!(f, " {}vibrato {} cents{}", open, vibrato.depth_cents, close)?;
+        let (open, close) = mark(TunerField::VibratoRate);
+        write!(
+            f,
+            " {}rate {}.{:02}Hz{}",
+            open,
+            vibrato.rate_centihertz / 100,
+            vibrato.rate_centihertz % 100,
+            close
+        )?;
+        let (open, close) = mark(TunerField::VibratoDelay);
+        write!(f, " {}delay {}ms{}", open, vibrato.delay_millis, close)?;
+
+        let (open, close) = mark(TunerField::TestPhrase);
+        let phrase = match self.phrase {
+            TestPhrase::Note => "note",
+            TestPhrase::Scale => "scale",
+        };
+        write!(f, " {}test {}{}", open, phrase, close)?;
+        let (open, close) = mark(TunerField::Save);
+        write!(f, " {}save{}", open, close)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::vibrato::Vibrato;
+
+    /// the profiles of the test notes
+    #[derive(Default)]
+    struct Played(Vec<SoundProfile>);
+
+    impl VoiceSink<(u4, u7), SoundProfile> for Played {
+        fn note_on(&mut self, _key: (u4, u7), profile: SoundProfile) {
+            self.0.push(profile);
+        }
+
+        fn note_off(&mut self, _key: (u4, u7)) {}
+
+        fn all_off(&mut self) {}
+    }
+
+    fn tuner_on(field: TunerField) -> ProfileTuner {
+        let mut tuner = ProfileTuner::new(&Settings::default());
+        while tuner.field != field {
+            tuner.next_field();
+        }
+        tuner
+    }
+
+    fn turn(tuner: &mut ProfileTuner, right: bool, times: usize) {
+        for _ in 0..times {
+            assert_eq!(tuner.turn(right, &Settings::default(), 0), None);
+        }
+    }
+
+    fn turn_on(tuner: &mut ProfileTuner, field: TunerField, right: bool, times: usize) {
+        tuner.field = field;
+        turn(tuner, right, times);
+    }
+
+    /// the tweak the save field gives
+    fn saved(tuner: &mut ProfileTuner) -> ProfileTweak {
+        while tuner.field != TunerField::Save {
+            tuner.next_field();
+        }
+        tuner.turn(true, &Settings::default(), 0).unwrap()
+    }
+
+    #[test]
+    fn the_button_goes_through_every_field() {
+        let mut tuner = ProfileTuner::new(&Settings::default());
+        let mut names = Vec::new();
+        for _ in 0..11 {
+            names.push(tuner.field.name());
+            tuner.next_field();
+        }
+        assert_eq!(
+            names,
+            [
+                "program",
+                "wait time",
+                "change per key",
+                "duration",
+                "lowest key",
+                "highest key",
+                "vibrato",
+                "rate",
+                "delay",
+                "test",
+                "save",
+            ]
+        );
+        assert_eq!(tuner.field, TunerField::Program);
+
+        let tuner = tuner_on(TunerField::LowestKey);
+        let line = tuner.to_string();
+        assert!(line.contains(" [lowest key 0]  highest key 127 "), "{line}");
+        assert!(
+            line.contains(" vibrato 0 cents   rate 5.50Hz   delay 0ms "),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn the_key_range_cant_turn_inside_out() {
+        let mut tuner = tuner_on(TunerField::HighestKey);
+        turn(&mut tuner, false, 57);
+        // the lowest key stops at the highest one
+        tuner.field = TunerField::LowestKey;
+        turn(&mut tuner, true, 100);
+        assert_eq!(saved(&mut tuner).lowest_key, 70);
+
+        // and the highest key at the lowest one, or at 127
+        tuner.field = TunerField::HighestKey;
+        turn(&mut tuner, false, 10);
+        assert_eq!(saved(&mut tuner).highest_key, 70);
+        turn_on(&mut tuner, TunerField::HighestKey, true, 100);
+        turn_on(&mut tuner, TunerField::LowestKey, false, 100);
+        let tweak = saved(&mut tuner);
+        assert_eq!((tweak.lowest_key, tweak.highest_key), (0, 127));
+    }
+
+    #[test]
+    fn the_vibrato_is_tuned_and_played() {
+        let mut tuner = tuner_on(TunerField::VibratoDepth);
+        turn(&mut tuner, true, 100);
+        turn_on(&mut tuner, TunerField::VibratoRate, false, 100);
+        turn(&mut tuner, true, 2);
+        turn_on(&mut tuner, TunerField::VibratoDelay, true, 100);
+        turn(&mut tuner, false, 1);
+        let vibrato = Vibrato::new(
+            MIN_VIBRATO_RATE_CENTIHERTZ + 2 * VIBRATO_RATE_STEP_CENTIHERTZ,
+            MAX_VIBRATO_DEPTH_CENTS,
+            MAX_VIBRATO_DELAY_MILLIS - VIBRATO_DELAY_STEP_MILLIS,
+        );
+
+        // every turn plays the sound again
+        let mut played = Played::default();
+        tuner.update(0, &mut played);
+        assert_eq!(played.0.len(), 1);
+        assert_eq!(played.0[0].vibrato, vibrato);
+
+        // the saved tweak keeps it, left on the save field goes back to the built in sound
+        let mut settings = Settings::default();
+        settings.set_tweak(saved(&mut tuner)).unwrap();
+        assert_eq!(settings.profile(0).vibrato, vibrato);
+        let built_in = tuner.turn(false, &settings, 0).unwrap();
+        assert_eq!(built_in, ProfileTweak::from_instrument(0));
+    }
+}
@@ -0,0 +1,259 @@
+// =============================================================================================
+//                                  SD CARD OVER SPI
+// =============================================================================================
+
+// the SPI mode of SD cards, only what's needed for reading single blocks:
+// CMD0 to go idle, CMD8 and ACMD41 to start the card, CMD58 to see whether the card
+// is addressed by blocks (SDHC / SDXC) or bytes (SDSC) and CMD17 to read a block
+//
+// the card starts at 400 kHz and is read at full speed once it's ready
+
+use esp_hal::{
+    Blocking,
+    gpio::Output,
+    spi::{
+        Error as SpiError,
+        master::{Config, Spi},
+    },
+    time::Rate,
+};
+
+use crate::block_device::{BLOCK_LEN, Block, BlockDevice};
+use crate::now_micros;
+
+const INIT_RATE_KHZ: u32 = 400;
+const READ_RATE_MHZ: u32 = 20;
+
+const INIT_TIMEOUT_MICROS: u64 = 1_000_000;
+const READ_TIMEOUT_MICROS: u64 = 100_000;
+
+const CMD0_GO_IDLE: u8 = 0;
+const CMD8_SEND_IF_COND: u8 = 8;
+const CMD16_SET_BLOCKLEN: u8 = 16;
+const CMD17_READ_BLOCK: u8 = 17;
+const CMD55_APP_CMD: u8 = 55;
+const CMD58_READ_OCR: u8 = 58;
+const ACMD41_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const DATA_START_TOKEN: u8 = 0xFE;
+
+#[derive(Debug)]
+pub enum SdError {
+    Spi(SpiError),
+    SpiConfig,
+    NoCard,
+    Command { command: u8, response: u8 },
+    VoltageNotSupported,
+    Timeout,
+    ReadToken(u8),
+}
+
+impl From<SpiError> for SdError {
+    fn from(err: SpiError) -> Self {
+        SdError::Spi(err)
+    }
+}
+
+pub struct SdCard<'d> {
+    spi: Spi<'d, Blocking>,
+    cs: Output<'d>,
+    block_addressed: bool,
+}
+
+impl<'d> SdCard<'d> {
+    /// wakes up the card, the SPI pins are set up by the caller
+    pub fn new(spi: Spi<'d, Blocking>, cs: Output<'d>) -> Result<Self, SdError> {
+        let mut card = SdCard {
+            spi,
+            cs,
+            block_addressed: false,
+        };
+        card.init()?;
+        Ok(card)
+    }
+
+    fn init(&mut self) -> Result<(), SdError> {
+        self.set_rate(Rate::from_khz(INIT_RATE_KHZ))?;
+
+        // at least 74 clocks with chip select high put the card into SPI mode
+        self.cs.set_high();
+        self.spi.write(&[0xFF; 10])?;
+
+        // a card that was busy with an earlier transfer may need a few tries
+        let mut idle = false;
+        for _ in 0..10 {
+            let response = self.command(CMD0_GO_IDLE, 0);
+            self.deselect()?;
+            if response.is_ok_and(|response| response == R1_IDLE) {
+                idle = true;
+                break;
+            }
+        }
+        if !idle {
+            return Err(SdError::NoCard);
+        }
+
+        // version 2 cards answer CMD8 and echo the check pattern
+        let response = self.command(CMD8_SEND_IF_COND, 0x1AA)?;
+        let version_2 = response & R1_ILLEGAL_COMMAND == 0;
+        if version_2 {
+            let mut r7 = [0xFF; 4];
+            self.spi.transfer(&mut r7)?;
+            if r7[2] & 0x0F != 0x01 || r7[3] != 0xAA {
+                self.deselect()?;
+                return Err(SdError::VoltageNotSupported);
+            }
+        }
+        self.deselect()?;
+
+        // the card leaves the idle state once it has finished starting up
+        let start = now_micros();
+        let high_capacity = if version_2 { 1 << 30 } else { 0 };
+        loop {
+            self.command(CMD55_APP_CMD, 0)?;
+            self.deselect()?;
+            let response = self.command(ACMD41_SEND_OP_COND, high_capacity)?;
+            self.deselect()?;
+            if response == 0 {
+                break;
+            }
+            if now_micros() - start > INIT_TIMEOUT_MICROS {
+                return Err(SdError::Timeout);
+            }
+        }
+
+        if version_2 {
+            let response = self.command(CMD58_READ_OCR, 0)?;
+            let mut ocr = [0xFF; 4];
+            self.spi.transfer(&mut ocr)?;
+            self.deselect()?;
+            if response != 0 {
+                return Err(SdError::Command {
+                    command: CMD58_READ_OCR,
+                    response,
+                });
+            }
+            // card capacity status
+            self.block_addressed = ocr[0] & 0x40 != 0;
+        }
+        if !self.block_addressed {
+            let response = self.command(CMD16_SET_BLOCKLEN, BLOCK_LEN as u32)?;
+            self.deselect()?;
+            if response != 0 {
+                return Err(SdError::Command {
+                    command: CMD16_SET_BLOCKLEN,
+                    response,
+                });
+            }
+        }
+
+        self.set_rate(Rate::from_mhz(READ_RATE_MHZ))
+    }
+
+    fn set_rate(&mut self, rate: Rate) -> Result<(), SdError> {
+        self.spi
+            .apply_config(&Config::default().with_frequency(rate))
+            .map_err(|_| SdError::SpiConfig)
+    }
+
+    /// sends the command and returns the R1 response, the card stays selected
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, SdError> {
+        self.cs.set_low();
+        if let Err(err) = self.wait_ready() {
+            self.deselect()?;
+            return Err(err);
+        }
+
+        let [a, b, c, d] = argument.to_be_bytes();
+        // only CMD0 and CMD8 are checked before the card is in SPI mode
+        let crc = match command {
+            CMD0_GO_IDLE => 0x95,
+            CMD8_SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        self.spi.write(&[0x40 | command, a, b, c, d, crc])?;
+
+        // the response comes within 8 bytes and starts with a 0 bit
+        for _ in 0..8 {
+            let response = self.read_byte()?;
+            if response & 0x80 == 0 {
+                return Ok(response);
+            }
+        }
+        self.deselect()?;
+        Err(SdError::Timeout)
+    }
+
+    fn wait_ready(&mut self) -> Result<(), SdError> {
+        let start = now_micros();
+        while self.read_byte()? != 0xFF {
+            if now_micros() - start > READ_TIMEOUT_MICROS {
+                return Err(SdError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> Result<(), SdError> {
+        self.cs.set_high();
+        // the card lets go of MISO on the next clocks
+        self.spi.write(&[0xFF])?;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn read_byte(&mut self) -> Result<u8, SdError> {
+        let mut byte = [0xFF];
+        self.spi.transfer(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_data(&mut self, block: &mut Block) -> Result<(), SdError> {
+        let start = now_micros();
+        let token = loop {
+            let token = self.read_byte()?;
+            if token != 0xFF {
+                break token;
+            }
+            if now_micros() - start > READ_TIMEOUT_MICROS {
+                return Err(SdError::Timeout);
+            }
+        };
+        if token != DATA_START_TOKEN {
+            return Err(SdError::ReadToken(token));
+        }
+
+        block.fill(0xFF);
+        self.spi.transfer(block)?;
+        // the CRC isn't checked in SPI mode
+        let mut crc = [0xFF; 2];
+        self.spi.transfer(&mut crc)?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for SdCard<'_> {
+    type Error = SdError;
+
+    fn read_block(&mut self, index: u32, block: &mut Block) -> Result<(), Self::Error> {
+        let address = if self.block_addressed {
+            index
+        } else {
+            index * BLOCK_LEN as u32
+        };
+
+        let response = self.command(CMD17_READ_BLOCK, address)?;
+        let result = if response == 0 {
+            self.read_data(block)
+        } else {
+            Err(SdError::Command {
+                command: CMD17_READ_BLOCK,
+                response,
+            })
+        };
+        self.deselect()?;
+        result
+    }
+}
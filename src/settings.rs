@@ -0,0 +1,470 @@
+// =============================================================================================
+//                              SETTINGS KEPT OVER RESETS
+// =============================================================================================
+
+// the settings are saved as fixed size records into a small flash area, every save goes to
+// the next free slot instead of overwriting the last one, so the sectors wear evenly and
+// an interrupted save leaves the previous record in place
+//
+// the newest record with a valid checksum wins, when there is none the defaults are used
+//
+// a sector is erased only when the writes reach it again, which also wipes the oldest records,
+// so the area needs at least two sectors to always keep one good record
+//
+// record layout, all numbers little endian:
+//
+//   0..4      magic "MSET"
+//   4         format version
+//   5         reserved, 0
+//   6..8      playback speed in percent
+//   8..12     sequence number, counts up with every save
+//   12        volume
+//   13        live transpose in semitones
+//   14        1 if the song is moved to the target key, else 0
+//   15        profile tweak count
+//   16..288   profile tweaks, 17 bytes each
+//     0         program
+//     1         1 if the note has a duration, else 0
+//     2..4      wait time
+//     4..6      wait change per key
+//     6..10     duration
+//     10        lowest key
+//     11        highest key
+//     12..14    vibrato rate in centihertz
+//     14        vibrato depth in cents
+//     15..17    vibrato delay in milli seconds
+//   288..316  reserved, 0
+//   316..320  CRC-32 of bytes 0..316
+//
+// version 1 records were 192 bytes and their tweaks had no key range and vibrato, the slots
+// don't line up with them any more, so they read as corrupted and the defaults are used
+// until the next save
+
+use core::fmt::{self, Debug};
+
+use heapless::Vec;
+
+use crate::sound_profiles::{INSTRUMENTS, SoundProfile};
+use crate::vibrato::Vibrato;
+
+pub const SETTINGS_MAGIC: [u8; 4] = *b"MSET";
+pub const SETTINGS_VERSION: u8 = 2;
+pub const RECORD_LEN: usize = 320;
+pub const MAX_PROFILE_TWEAKS: usize = 16;
+
+const TWEAKS_START: usize = 16;
+const TWEAK_LEN: usize = 17;
+const CRC_START: usize = RECORD_LEN - 4;
+
+/// the flash the settings are kept in, offsets are counted from the start of the settings area
+/// and reads and writes are whole records at offsets that are multiples of RECORD_LEN
+pub trait SettingsFlash {
+    type Error: Debug;
+
+    /// bytes erased at once
+    const SECTOR_LEN: u32;
+
+    /// bytes in the settings area, a whole number of sectors
+    fn size(&self) -> u32;
+
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// can only clear bits, the sector has to be erased before
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// sets every byte of the sector at the offset to 0xFF
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error>;
+}
+
+/// a sound that replaces the built in one of a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileTweak {
+    pub program: u8,
+    pub wait_time: u16,
+    pub wait_change_per_key: u16,
+    pub duration: Option<i32>,
+    // the keys the sound plays, narrowed further to the keys the buzzers can play
+    pub lowest_key: u8,
+    pub highest_key: u8,
+    pub vibrato: Vibrato,
+}
+
+impl ProfileTweak {
+    /// the program's built in sound, as a starting point for a tweak
+    ///
+    /// the built in sounds play every key the buzzers can, so the range is left open
+    pub const fn from_instrument(program: u8) -> Self {
+        let profile = INSTRUMENTS[program as usize & 0x7F];
+        ProfileTweak {
+            program,
+            wait_time: profile.wait_time,
+            wait_change_per_key: profile.wait_change_per_key,
+            duration: profile.duration,
+            lowest_key: 0,
+            highest_key: 127,
+            vibrato: profile.vibrato,
+        }
+    }
+
+    /// a range that leaves no playable key is ignored, the sound plays where it can
+    pub const fn profile(&self) -> SoundProfile {
+        let mut profile = SoundProfile::from_change_per_key(
+            self.wait_time,
+            self.duration,
+            self.wait_change_per_key,
+        )
+        .with_vibrato(self.vibrato);
+        let lowest_key = if self.lowest_key > profile.lowest_key {
+            self.lowest_key
+        } else {
+            profile.lowest_key
+        };
+        let highest_key = if self.highest_key < profile.highest_key {
+            self.highest_key
+        } else {
+            profile.highest_key
+        };
+        if lowest_key <= highest_key {
+            profile.lowest_key = lowest_key;
+            profile.highest_key = highest_key;
+        }
+        profile
+    }
+}
+
+/// the tweaked sound of the program, or the built in one when it isn't tweaked
+pub fn program_sound(tweaks: &[ProfileTweak], program: u8) -> SoundProfile {
+    match tweaks.iter().find(|tweak| tweak.program == program) {
+        Some(tweak) => tweak.profile(),
+        None => INSTRUMENTS[program as usize & 0x7F],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub volume: u8, // level of the DAC on GPIO 25
+    pub transpose: i8,
+    pub transpose_to_key: bool,
+    pub speed_pct: u16,
+    pub profile_tweaks: Vec<ProfileTweak, MAX_PROFILE_TWEAKS>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            volume: 0,
+            transpose: 0,
+            transpose_to_key: false,
+            speed_pct: 100,
+            profile_tweaks: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// the sound of the program, tweaked or built in
+    pub fn profile(&self, program: u8) -> SoundProfile {
+        program_sound(&self.profile_tweaks, program)
+    }
+
+    /// the tweak of the program, or its built in sound when it isn't tweaked
+    pub fn tweak(&self, program: u8) -> ProfileTweak {
+        self.profile_tweaks
+            .iter()
+            .find(|tweak| tweak.program == program)
+            .copied()
+            .unwrap_or(ProfileTweak::from_instrument(program))
+    }
+
+    /// replaces the tweak of the same program, gives the tweak back when there's no room for it
+    ///
+    /// a tweak that is the same as the built in sound only removes the old tweak,
+    /// so it doesn't take up room
+    pub fn set_tweak(&mut self, tweak: ProfileTweak) -> Result<(), ProfileTweak> {
+        let old = self
+            .profile_tweaks
+            .iter()
+            .position(|old| old.program == tweak.program);
+        match old {
+            _ if tweak == ProfileTweak::from_instrument(tweak.program) => {
+                if let Some(old) = old {
+                    self.profile_tweaks.remove(old);
+                }
+                Ok(())
+            }
+            Some(old) => {
+                self.profile_tweaks[old] = tweak;
+                Ok(())
+            }
+            None => self.profile_tweaks.push(tweak),
+        }
+    }
+
+    fn encode(&self, sequence: u32) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[0..4].copy_from_slice(&SETTINGS_MAGIC);
+        record[4] = SETTINGS_VERSION;
+        record[6..8].copy_from_slice(&self.speed_pct.to_le_bytes());
+        record[8..12].copy_from_slice(&sequence.to_le_bytes());
+        record[12] = self.volume;
+        record[13] = self.transpose as u8;
+        record[14] = self.transpose_to_key as u8;
+        record[15] = self.profile_tweaks.len() as u8;
+
+        let tweaks = record[TWEAKS_START..].chunks_exact_mut(TWEAK_LEN);
+        for (bytes, tweak) in tweaks.zip(&self.profile_tweaks) {
+            bytes[0] = tweak.program;
+            bytes[1] = tweak.duration.is_some() as u8;
+            bytes[2..4].copy_from_slice(&tweak.wait_time.to_le_bytes());
+            bytes[4..6].copy_from_slice(&tweak.wait_change_per_key.to_le_bytes());
+            bytes[6..10].copy_from_slice(&tweak.duration.unwrap_or(0).to_le_bytes());
+            bytes[10] = tweak.lowest_key;
+            bytes[11] = tweak.highest_key;
+            bytes[12..14].copy_from_slice(&tweak.vibrato.rate_centihertz.to_le_bytes());
+            bytes[14] = tweak.vibrato.depth_cents;
+            bytes[15..17].copy_from_slice(&tweak.vibrato.delay_millis.to_le_bytes());
+        }
+
+        let crc = crc32(&record[..CRC_START]);
+        record[CRC_START..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// the settings and sequence number of a valid record of this version
+    fn decode(record: &[u8; RECORD_LEN]) -> Result<(Self, u32), RecordError> {
+        if record.iter().all(|byte| *byte == 0xFF) {
+            return Err(RecordError::Blank);
+        }
+        let crc = u32::from_le_bytes([
+            record[CRC_START],
+            record[CRC_START + 1],
+            record[CRC_START + 2],
+            record[CRC_START + 3],
+        ]);
+        if record[0..4] != SETTINGS_MAGIC || crc != crc32(&record[..CRC_START]) {
+            return Err(RecordError::Corrupted);
+        }
+        if record[4] != SETTINGS_VERSION {
+            return Err(RecordError::OtherVersion(record[4]));
+        }
+
+        let tweak_count = (record[15] as usize).min(MAX_PROFILE_TWEAKS);
+        let mut profile_tweaks = Vec::new();
+        for bytes in record[TWEAKS_START..]
+            .chunks_exact(TWEAK_LEN)
+            .take(tweak_count)
+        {
+            let duration = i32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+            let _ = profile_tweaks.push(ProfileTweak {
+                program: bytes[0] & 0x7F,
+                wait_time: u16::from_le_bytes([bytes[2], bytes[3]]),
+                wait_change_per_key: u16::from_le_bytes([bytes[4], bytes[5]]),
+                duration: (bytes[1] != 0).then_some(duration),
+                lowest_key: bytes[10] & 0x7F,
+                highest_key: bytes[11] & 0x7F,
+                vibrato: Vibrato::new(
+                    u16::from_le_bytes([bytes[12], bytes[13]]),
+                    bytes[14],
+                    u16::from_le_bytes([bytes[15], bytes[16]]),
+                ),
+            });
+        }
+
+        let settings = Settings {
+            volume: record[12],
+            transpose: record[13] as i8,
+            transpose_to_key: record[14] != 0,
+            speed_pct: u16::from_le_bytes([record[6], record[7]]),
+            profile_tweaks,
+        };
+        let sequence = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+        Ok((settings, sequence))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordError {
+    Blank,
+    Corrupted,
+    OtherVersion(u8),
+}
+
+#[derive(Debug)]
+pub enum SettingsError<E> {
+    Flash(E),
+    /// the area has less than two sectors or a sector can't hold a record
+    AreaTooSmall,
+    /// no slot kept the record, the flash is worn out
+    WriteFailed,
+}
+
+impl<E: Debug> fmt::Display for SettingsError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Flash(err) => write!(f, "flash error: {err:?}"),
+            SettingsError::AreaTooSmall => write!(f, "the settings area is too small"),
+            SettingsError::WriteFailed => write!(f, "the settings don't stay in the flash"),
+        }
+    }
+}
+
+/// what was found in the flash when the store was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loaded {
+    Record {
+        sequence: u32,
+    },
+    /// nothing saved yet, the defaults are used
+    Empty,
+    /// no record could be read, the defaults are used
+    Corrupted {
+        records: usize,
+    },
+    /// only records of another format version, the defaults are used
+    OtherVersion(u8),
+}
+
+pub struct SettingsStore<F: SettingsFlash> {
+    flash: F,
+    slots_per_sector: u32,
+    slot_count: u32,
+    next_slot: u32,
+    sequence: u32, // of the newest record
+    saved: Settings,
+    loaded: Loaded,
+}
+
+impl<F: SettingsFlash> SettingsStore<F> {
+    /// reads every slot and keeps the newest valid record
+    pub fn open(mut flash: F) -> Result<Self, SettingsError<F::Error>> {
+        let slots_per_sector = F::SECTOR_LEN / RECORD_LEN as u32;
+        let sectors = flash.size() / F::SECTOR_LEN;
+        if slots_per_sector == 0 || sectors < 2 {
+            return Err(SettingsError::AreaTooSmall);
+        }
+        let slot_count = slots_per_sector * sectors;
+
+        let mut newest: Option<(Settings, u32, u32)> = None;
+        let mut corrupted = 0;
+        let mut other_version = None;
+        let mut record = [0; RECORD_LEN];
+        for slot in 0..slot_count {
+            flash
+                .read(slot_offset::<F>(slot, slots_per_sector), &mut record)
+                .map_err(SettingsError::Flash)?;
+            match Settings::decode(&record) {
+                Ok((settings, sequence)) => {
+                    if newest.as_ref().is_none_or(|newest| sequence > newest.1) {
+                        newest = Some((settings, sequence, slot));
+                    }
+                }
+                Err(RecordError::Blank) => {}
+                Err(RecordError::Corrupted) => corrupted += 1,
+                Err(RecordError::OtherVersion(version)) => other_version = Some(version),
+            }
+        }
+
+        let (saved, sequence, next_slot, loaded) = match newest {
+            Some((settings, sequence, slot)) => (
+                settings,
+                sequence,
+                (slot + 1) % slot_count,
+                Loaded::Record { sequence },
+            ),
+            None => {
+                let loaded = match other_version {
+                    Some(version) => Loaded::OtherVersion(version),
+                    None if corrupted > 0 => Loaded::Corrupted { records: corrupted },
+                    None => Loaded::Empty,
+                };
+                (Settings::default(), 0, 0, loaded)
+            }
+        };
+        Ok(SettingsStore {
+            flash,
+            slots_per_sector,
+            slot_count,
+            next_slot,
+            sequence,
+            saved,
+            loaded,
+        })
+    }
+
+    /// the newest saved settings, or the defaults
+    pub fn settings(&self) -> &Settings {
+        &self.saved
+    }
+
+    pub const fn loaded(&self) -> Loaded {
+        self.loaded
+    }
+
+    /// writes the settings into the next free slot, false if they were already saved
+    pub fn save(&mut self, settings: &Settings) -> Result<bool, SettingsError<F::Error>> {
+        if *settings == self.saved && matches!(self.loaded, Loaded::Record { .. }) {
+            return Ok(false);
+        }
+        let record = settings.encode(self.sequence.wrapping_add(1));
+        let mut written = [0; RECORD_LEN];
+
+        for _ in 0..self.slot_count {
+            let slot = self.next_slot;
+            self.next_slot = (slot + 1) % self.slot_count;
+            let offset = slot_offset::<F>(slot, self.slots_per_sector);
+
+            if slot.is_multiple_of(self.slots_per_sector) {
+                self.flash
+                    .erase_sector(offset)
+                    .map_err(SettingsError::Flash)?;
+            } else {
+                // a slot left half written by a reset can't be written again before the erase
+                self.flash
+                    .read(offset, &mut written)
+                    .map_err(SettingsError::Flash)?;
+                if written.iter().any(|byte| *byte != 0xFF) {
+                    continue;
+                }
+            }
+
+            self.flash
+                .write(offset, &record)
+                .map_err(SettingsError::Flash)?;
+            self.flash
+                .read(offset, &mut written)
+                .map_err(SettingsError::Flash)?;
+            if written == record {
+                self.sequence = self.sequence.wrapping_add(1);
+                self.saved = settings.clone();
+                self.loaded = Loaded::Record {
+                    sequence: self.sequence,
+                };
+                return Ok(true);
+            }
+        }
+        Err(SettingsError::WriteFailed)
+    }
+
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+}
+
+/// the records don't cross sectors, the end of a sector that doesn't fit one is left unused
+#[inline(always)]
+fn slot_offset<F: SettingsFlash>(slot: u32, slots_per_sector: u32) -> u32 {
+    slot / slots_per_sector * F::SECTOR_LEN + slot % slots_per_sector * RECORD_LEN as u32
+}
+
+/// the CRC-32 of zip files and ethernet, bit by bit since it only runs on a save or a boot
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = crc >> 1 ^ 0xEDB8_8320 & mask;
+        }
+    }
+    !crc
+}
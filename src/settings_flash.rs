@@ -0,0 +1,52 @@
+// =============================================================================================
+//                              SETTINGS IN THE ESP32 FLASH
+// =============================================================================================
+
+// the settings are kept in the nvs partition of the default partition table that espflash
+// flashes, the firmware doesn't use the ESP-IDF NVS library so the partition is written raw
+//
+// the flash is shared with the code, so the other core is parked while a sector is written
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_hal::peripherals::FLASH;
+use esp_storage::{FlashStorage, FlashStorageError};
+
+use crate::settings::SettingsFlash;
+
+const PARTITION_START: u32 = 0x9000;
+const PARTITION_LEN: u32 = 0x6000;
+
+pub struct SettingsPartition<'d> {
+    flash: FlashStorage<'d>,
+}
+
+impl<'d> SettingsPartition<'d> {
+    pub fn new(flash: FLASH<'d>) -> Self {
+        SettingsPartition {
+            flash: FlashStorage::new(flash).multicore_auto_park(),
+        }
+    }
+}
+
+impl SettingsFlash for SettingsPartition<'_> {
+    type Error = FlashStorageError;
+
+    const SECTOR_LEN: u32 = FlashStorage::ERASE_SIZE as u32;
+
+    fn size(&self) -> u32 {
+        PARTITION_LEN
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        ReadNorFlash::read(&mut self.flash, PARTITION_START + offset, buffer)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        NorFlash::write(&mut self.flash, PARTITION_START + offset, data)
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error> {
+        let start = PARTITION_START + offset;
+        self.flash.erase(start, start + Self::SECTOR_LEN)
+    }
+}
@@ -0,0 +1,146 @@
+// =============================================================================================
+//                           CHECKING A MIDI FILE BEFORE PLAYING IT
+// =============================================================================================
+
+// midly reads the tracks lazily and without the "strict" feature a broken event just ends
+// its track early, so a truncated or corrupted file would play partially without notice
+//
+// the check walks every track once before the song starts, a track that runs out before its
+// EndOfTrack event or is missing completely is broken, the player then decides what to do
+// with the song from the ErrorPolicy
+
+use core::fmt;
+
+use midly::{Error, Header, MetaMessage, Timing, TrackEventKind, parse};
+
+#[derive(Debug, Clone)]
+pub enum SongError {
+    /// not a midi file at all
+    Header(Error),
+    TimecodeTiming,
+    /// the file ends before all the tracks the header promised
+    MissingTrack {
+        track: usize,
+        declared: usize,
+    },
+    /// the track chunk can't be read
+    Track {
+        track: usize,
+        error: Error,
+    },
+    /// more tracks than the player has room for
+    TooManyTracks {
+        tracks: usize,
+        capacity: usize,
+    },
+    /// the track breaks after the event at the tick, it is truncated or has a corrupted event
+    Event {
+        track: usize,
+        tick: u64,
+    },
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SongError::Header(err) => write!(f, "not a valid midi file: {err}"),
+            SongError::TimecodeTiming => write!(f, "SMPTE timecode timing is not supported"),
+            SongError::MissingTrack { track, declared } => write!(
+                f,
+                "track {track} is missing, the header declares {declared} tracks"
+            ),
+            SongError::Track { track, error } => write!(f, "track {track} can't be read: {error}"),
+            SongError::TooManyTracks { tracks, capacity } => write!(
+                f,
+                "the song has {tracks} tracks, the player merges at most {capacity}"
+            ),
+            SongError::Event { track, tick } => {
+                write!(f, "track {track} is broken after tick {tick}")
+            }
+        }
+    }
+}
+
+impl From<Error> for SongError {
+    fn from(err: Error) -> Self {
+        SongError::Header(err)
+    }
+}
+
+impl SongError {
+    /// the broken track, None if the whole file is broken
+    pub const fn track(&self) -> Option<usize> {
+        match self {
+            SongError::MissingTrack { track, .. }
+            | SongError::Track { track, .. }
+            | SongError::Event { track, .. } => Some(*track),
+            _ => None,
+        }
+    }
+
+    /// how far the song plays correctly, a broken header or track breaks it from the start
+    pub const fn playable_ticks(&self) -> u64 {
+        match self {
+            SongError::Event { tick, .. } => *tick,
+            _ => 0,
+        }
+    }
+}
+
+/// what the player does with a song that has broken tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// leave the broken tracks out and play the rest
+    SkipTrack,
+    /// play every track until the first point where one breaks
+    Stop,
+    /// don't play the song at all and go on to the next one
+    NextSong,
+}
+
+/// the player keeps time in ticks per quarter note, files timed in SMPTE frames are refused
+pub fn ticks_per_quarter(header: &Header) -> Result<u16, SongError> {
+    match header.timing {
+        Timing::Metrical(ticks) => Ok(ticks.as_int()),
+        Timing::Timecode(_, _) => Err(SongError::TimecodeTiming),
+    }
+}
+
+/// reads every event of every track and passes each broken track to `broken_track`,
+/// only a header the player can't use is an error, returns the number of tracks
+pub fn check_song(
+    midi: &[u8],
+    mut broken_track: impl FnMut(SongError),
+) -> Result<usize, SongError> {
+    let (header, tracks) = parse(midi)?;
+    ticks_per_quarter(&header)?;
+
+    let declared = tracks.size_hint().0;
+    let mut track_count = 0;
+    for (track, events) in tracks.enumerate() {
+        track_count += 1;
+        let events = match events {
+            Ok(events) => events,
+            Err(error) => {
+                broken_track(SongError::Track { track, error });
+                continue;
+            }
+        };
+
+        let mut tick = 0u64;
+        let mut ended = false;
+        for event in events {
+            let Ok(event) = event else { break };
+            tick += event.delta.as_int() as u64;
+            ended = matches!(event.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+        }
+        if !ended {
+            broken_track(SongError::Event { track, tick });
+        }
+    }
+
+    for track in track_count..declared {
+        broken_track(SongError::MissingTrack { track, declared });
+    }
+    Ok(track_count)
+}
@@ -0,0 +1,105 @@
+// =============================================================================================
+//                                  SONGS ON THE SD CARD
+// =============================================================================================
+
+// the midi files in the root directory of the card make up the playlist, sorted by name,
+// the library only keeps their directory entries, the songs are read from the card
+// while they play
+//
+// a PROFILES.TXT next to the songs holds instrument sounds in the profile bank format
+
+use heapless::Vec;
+
+use crate::block_device::BlockDevice;
+use crate::fat::{DirEntry, FatError, FatFile, FatVolume};
+use crate::midi_reader::SongSource;
+
+const SONG_EXTENSION: &[u8] = b"MID";
+const PROFILE_BANK_NAME: [u8; 11] = *b"PROFILESTXT";
+
+pub struct SongLibrary<D: BlockDevice, const SONGS: usize> {
+    volume: FatVolume<D>,
+    songs: Vec<DirEntry, SONGS>,
+    left_out: usize, // songs that didn't fit in the playlist
+    profile_bank: Option<DirEntry>,
+}
+
+impl<D: BlockDevice, const SONGS: usize> SongLibrary<D, SONGS> {
+    pub fn open(device: D) -> Result<Self, FatError<D::Error>> {
+        let mut volume = FatVolume::mount(device)?;
+        let mut songs = Vec::new();
+        let mut left_out = 0;
+        let mut profile_bank = None;
+        volume.list_root(|entry| {
+            // 8.3 names are stored in upper case
+            if !entry.is_dir && entry.name == PROFILE_BANK_NAME {
+                profile_bank = Some(entry);
+            }
+            let is_song = !entry.is_dir && entry.extension().eq_ignore_ascii_case(SONG_EXTENSION);
+            if is_song && songs.push(entry).is_err() {
+                left_out += 1;
+            }
+        })?;
+        songs.sort_unstable_by_key(|song| song.name);
+
+        Ok(SongLibrary {
+            volume,
+            songs,
+            left_out,
+            profile_bank,
+        })
+    }
+
+    pub fn songs(&self) -> &[DirEntry] {
+        &self.songs
+    }
+
+    pub const fn left_out(&self) -> usize {
+        self.left_out
+    }
+
+    /// the song for the MidiReader, None if there's no song at the index
+    pub fn song(&mut self, index: usize) -> Option<LibrarySong<'_, D>> {
+        let entry = self.songs.get(index)?;
+        Some(LibrarySong {
+            file: self.volume.open(entry),
+            volume: &mut self.volume,
+        })
+    }
+
+    /// the profile bank file, read the same way as a song
+    pub fn profile_bank(&mut self) -> Option<LibrarySong<'_, D>> {
+        let entry = self.profile_bank.as_ref()?;
+        Some(LibrarySong {
+            file: self.volume.open(entry),
+            volume: &mut self.volume,
+        })
+    }
+}
+
+/// a file on the card, every track of a song reads it with its own copy of the file position
+pub struct LibrarySong<'v, D: BlockDevice> {
+    volume: &'v mut FatVolume<D>,
+    file: FatFile,
+}
+
+impl<D: BlockDevice> SongSource for LibrarySong<'_, D> {
+    type Error = FatError<D::Error>;
+    type Cursor = FatFile;
+
+    fn size(&self) -> u32 {
+        self.file.size()
+    }
+
+    fn cursor(&self) -> FatFile {
+        self.file
+    }
+
+    fn seek(&mut self, cursor: &mut FatFile, position: u32) {
+        self.volume.seek(cursor, position)
+    }
+
+    fn read(&mut self, cursor: &mut FatFile, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        self.volume.read(cursor, buffer)
+    }
+}
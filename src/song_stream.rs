@@ -0,0 +1,153 @@
+// =============================================================================================
+//                          PRECOMPILED COMPACT SONG EVENT STREAM
+// =============================================================================================
+
+// a midi file flattened on the host into one time sorted list of channel voice events,
+// tempo changes are already baked into the absolute micro second timestamps
+//
+// layout, all numbers little endian:
+//
+//   header, 16 bytes
+//     0..4    magic "MSNG"
+//     4       format version
+//     5       sharps of the first key signature, negative for flats
+//     6       1 if that key is minor, else 0
+//     7       reserved, 0
+//     8..12   event count
+//     12..16  song length in micro seconds
+//
+//   events, 7 bytes each
+//     0..4    absolute time in micro seconds
+//     4       status byte, 0x80 - 0xEF
+//     5       first data byte
+//     6       second data byte, 0 for messages with only one
+//
+// the reader only borrows the bytes, so the stream can be played straight from flash
+
+use midly::{MidiMessage, live::LiveEvent, num::u4};
+
+pub const STREAM_MAGIC: [u8; 4] = *b"MSNG";
+pub const STREAM_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+pub const EVENT_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SongStreamError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamEvent {
+    pub micros: u32,
+    pub channel: u4,
+    pub message: MidiMessage,
+}
+
+impl StreamEvent {
+    /// the status and data bytes of a channel voice message, as stored in the stream
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let (status, data_1, data_2) = match self.message {
+            MidiMessage::NoteOff { key, vel } => (0x80, key.as_int(), vel.as_int()),
+            MidiMessage::NoteOn { key, vel } => (0x90, key.as_int(), vel.as_int()),
+            MidiMessage::Aftertouch { key, vel } => (0xA0, key.as_int(), vel.as_int()),
+            MidiMessage::Controller { controller, value } => {
+                (0xB0, controller.as_int(), value.as_int())
+            }
+            MidiMessage::ProgramChange { program } => (0xC0, program.as_int(), 0),
+            MidiMessage::ChannelAftertouch { vel } => (0xD0, vel.as_int(), 0),
+            MidiMessage::PitchBend { bend } => {
+                let bend = bend.0.as_int();
+                (0xE0, (bend & 0x7F) as u8, (bend >> 7) as u8)
+            }
+        };
+        [status | self.channel.as_int(), data_1, data_2]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SongStream<'a> {
+    events: &'a [u8],
+    event_count: usize,
+    length_micros: u32,
+    key_signature: (i8, bool),
+}
+
+impl<'a> SongStream<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, SongStreamError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(SongStreamError::Truncated);
+        }
+        if bytes[0..4] != STREAM_MAGIC {
+            return Err(SongStreamError::BadMagic);
+        }
+        if bytes[4] != STREAM_VERSION {
+            return Err(SongStreamError::UnsupportedVersion(bytes[4]));
+        }
+
+        let event_count = read_u32(bytes, 8) as usize;
+        let length_micros = read_u32(bytes, 12);
+        let key_signature = (bytes[5] as i8, bytes[6] != 0);
+
+        let events = &bytes[HEADER_LEN..];
+        if events.len() < event_count * EVENT_LEN {
+            return Err(SongStreamError::Truncated);
+        }
+
+        Ok(Self {
+            events: &events[..event_count * EVENT_LEN],
+            event_count,
+            length_micros,
+            key_signature,
+        })
+    }
+
+    pub const fn length_micros(&self) -> u32 {
+        self.length_micros
+    }
+
+    /// (sharps, minor) like in the midi KeySignature event, C major if the song has none
+    pub const fn key_signature(&self) -> (i8, bool) {
+        self.key_signature
+    }
+
+    /// the event at index, None if it is out of range or not a channel voice message
+    pub fn get(&self, index: usize) -> Option<StreamEvent> {
+        let record = self
+            .events
+            .get(index * EVENT_LEN..(index + 1) * EVENT_LEN)?;
+
+        let micros = read_u32(record, 0);
+        let status = record[4];
+        let message_len = match status & 0xF0 {
+            0xC0 | 0xD0 => 2,
+            _ => 3,
+        };
+
+        match LiveEvent::parse(&record[4..4 + message_len]) {
+            Ok(LiveEvent::Midi { channel, message }) => Some(StreamEvent {
+                micros,
+                channel,
+                message,
+            }),
+            _ => None,
+        }
+    }
+
+    /// all events in time order, broken records are skipped
+    pub fn iter(&self) -> impl Iterator<Item = StreamEvent> + 'a {
+        let stream = *self;
+        (0..self.event_count).filter_map(move |index| stream.get(index))
+    }
+}
+
+#[inline(always)]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
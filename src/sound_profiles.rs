@@ -2,21 +2,77 @@
 //                                SOUND PROFILE FOR INSTRUMENTS
 // =============================================================================================
 
+use crate::vibrato::Vibrato;
+
+// toggle periods the buzzers can play, shorter ones are too high and longer ones too low to hear
+pub const MIN_PERIOD_MICROS: u16 = 100;
+pub const MAX_PERIOD_MICROS: u16 = 20000;
+
+/// the sound of the channels that haven't picked an instrument
+pub const DEFAULT_SOUND: SoundProfile = SoundProfile::from_change_per_key(3800, None, 50);
+
 #[derive(Debug, Clone, Copy)]
 pub struct SoundProfile {
     pub wait_time: u16,
-    pub duration: Option<i32>,
+    pub duration: Option<i32>, // micro seconds the note sounds, None holds it until the note off
     pub wait_change_per_key: u16,
+    pub lowest_key: u8, // playable range, notes outside it are moved by octaves
+    pub highest_key: u8,
+    pub vibrato: Vibrato,
 }
 
 impl SoundProfile {
     const fn new(wait_time: u16, duration: Option<i32>, change_percentage: f32) -> Self {
         let change = (wait_time as f32 * change_percentage / 100.0) as u32;
 
-        SoundProfile {
+        Self::from_change_per_key(wait_time, duration, change as u16)
+    }
+
+    pub const fn from_change_per_key(
+        wait_time: u16,
+        duration: Option<i32>,
+        wait_change_per_key: u16,
+    ) -> Self {
+        let mut profile = SoundProfile {
             wait_time,
             duration,
-            wait_change_per_key: change as u16,
+            wait_change_per_key,
+            lowest_key: 0,
+            highest_key: 127,
+            vibrato: Vibrato::NONE,
+        };
+
+        // the keys whose period stays inside the playable periods
+        while profile.lowest_key < 127
+            && profile.period_for_key(profile.lowest_key) > MAX_PERIOD_MICROS
+        {
+            profile.lowest_key += 1;
+        }
+        while profile.highest_key > profile.lowest_key
+            && profile.period_for_key(profile.highest_key) < MIN_PERIOD_MICROS
+        {
+            profile.highest_key -= 1;
+        }
+        profile
+    }
+
+    /// the same sound with its own vibrato
+    pub const fn with_vibrato(self, vibrato: Vibrato) -> Self {
+        SoundProfile { vibrato, ..self }
+    }
+
+    /// micro seconds between pin toggles for a key
+    pub const fn period_for_key(&self, key: u8) -> u16 {
+        // key between 0 and 127, so 64 is the middle point
+        // less than 64 = note goes down, so wait time goes up
+        // more than 64 = note goes up, so wait time goes down
+        let period = self.wait_time as i32 - self.wait_change_per_key as i32 * (key as i32 - 64);
+        if period < 1 {
+            1
+        } else if period > u16::MAX as i32 {
+            u16::MAX
+        } else {
+            period as u16
         }
     }
 }
@@ -25,6 +81,10 @@ impl SoundProfile {
 //                        SOUND PROFILE COLLECTION FOR ALL INSTURMENTS
 // =============================================================================================
 
+// bowed strings and flutes start plain and their vibrato comes in after a moment
+const STRING_VIBRATO: Vibrato = Vibrato::new(550, 15, 350);
+const FLUTE_VIBRATO: Vibrato = Vibrato::new(500, 12, 250);
+
 pub const INSTRUMENTS: [SoundProfile; 128] = [
     //  ======== Piano ========
 
@@ -119,13 +179,13 @@ pub const INSTRUMENTS: [SoundProfile; 128] = [
     //  ======== Solo Strings ========
 
     // 40. Violin
-    SoundProfile::new(3600, None, 3.5),
+    SoundProfile::new(3600, None, 3.5).with_vibrato(STRING_VIBRATO),
     // 41. Viola
-    SoundProfile::new(4100, None, 2.0),
+    SoundProfile::new(4100, None, 2.0).with_vibrato(STRING_VIBRATO),
     // 42. Cello
-    SoundProfile::new(3800, None, 2.5),
+    SoundProfile::new(3800, None, 2.5).with_vibrato(STRING_VIBRATO),
     // 43. Contrabass
-    SoundProfile::new(3600, None, 3.5),
+    SoundProfile::new(3600, None, 3.5).with_vibrato(STRING_VIBRATO),
     // 44. Tremolo Strings
     SoundProfile::new(4600, None, 1.5),
     // 45. Pizzicato Strings
@@ -191,21 +251,21 @@ pub const INSTRUMENTS: [SoundProfile; 128] = [
     //  ======== Pipe ========
 
     // 72. Piccolo
-    SoundProfile::new(5900, None, 2.5),
+    SoundProfile::new(5900, None, 2.5).with_vibrato(FLUTE_VIBRATO),
     // 73. Flute
-    SoundProfile::new(5700, None, 3.5),
+    SoundProfile::new(5700, None, 3.5).with_vibrato(FLUTE_VIBRATO),
     // 74. Recorder
-    SoundProfile::new(5500, None, 3.5),
+    SoundProfile::new(5500, None, 3.5).with_vibrato(FLUTE_VIBRATO),
     // 75. Pan Flute
-    SoundProfile::new(6100, None, 1.0),
+    SoundProfile::new(6100, None, 1.0).with_vibrato(FLUTE_VIBRATO),
     // 76. Blown Bottle
     SoundProfile::new(6100, None, 1.0),
     // 77. Shakuhachi
-    SoundProfile::new(5200, None, 2.5),
+    SoundProfile::new(5200, None, 2.5).with_vibrato(FLUTE_VIBRATO),
     // 78. Whistle
-    SoundProfile::new(6100, None, 3.5),
+    SoundProfile::new(6100, None, 3.5).with_vibrato(FLUTE_VIBRATO),
     // 79. Ocarina
-    SoundProfile::new(6100, None, 1.5),
+    SoundProfile::new(6100, None, 1.5).with_vibrato(FLUTE_VIBRATO),
     //  ======== Synth Lead ========
 
     // 80. Square Wave
@@ -275,7 +335,7 @@ pub const INSTRUMENTS: [SoundProfile; 128] = [
     // 109. Bagpipe
     SoundProfile::new(4000, None, 2.5),
     // 110. Fiddle
-    SoundProfile::new(4400, None, 1.5),
+    SoundProfile::new(4400, None, 1.5).with_vibrato(STRING_VIBRATO),
     // 111. Shanai
     SoundProfile::new(4000, None, 2.5),
     //  ======== Percussive ========
@@ -315,3 +375,135 @@ pub const INSTRUMENTS: [SoundProfile; 128] = [
     // 127. Gunshot
     SoundProfile::new(6500, None, 1.5),
 ];
+
+/// the General MIDI names of the programs, in the spelling of the table above
+pub const PROGRAM_NAMES: [&str; 128] = [
+    "Acoustic Grand",
+    "Bright Acoustic",
+    "Electric Grand",
+    "Honky-Tonk",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavinet",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordian",
+    "Harmonica",
+    "Tango Accordian",
+    "Nylon String Guitar",
+    "Steel String Guitar",
+    "Electric Jazz Guitar",
+    "Electric Clean Guitar",
+    "Electric Muted Guitar",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Strings",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "SynthStrings 1",
+    "SynthStrings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Voice",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "SynthBrass 1",
+    "SynthBrass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Square Wave",
+    "Saw Wave",
+    "Syn. Calliope",
+    "Chiffer Lead",
+    "Charang",
+    "Solo Vox",
+    "5th Saw Wave",
+    "Bass & Lead",
+    "Fantasia",
+    "Warm Pad",
+    "Polysynth",
+    "Space Voice",
+    "Bowed Glass",
+    "Metal Pad",
+    "Halo Pad",
+    "Sweep Pad",
+    "Ice Rain",
+    "Soundtrack",
+    "Crystal",
+    "Atmosphere",
+    "Brightness",
+    "Goblin",
+    "Echo Drops",
+    "Star Theme",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bagpipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
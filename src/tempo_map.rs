@@ -0,0 +1,308 @@
+// =============================================================================================
+//                       TEMPO MAP FOR CONVERTING BETWEEN TICKS AND TIME
+// =============================================================================================
+
+// every tempo and time signature change of all tracks, collected before the song plays,
+// so any tick can be turned into micro seconds or a bar and beat, and back
+//
+// the changes are kept in fixed size heapless storage, songs with more of them are rejected
+//
+// the patterns of a format 2 file play one after another, so each track starts where
+// the previous played one ended, a pattern that is left out doesn't take any time
+
+use core::fmt;
+
+use heapless::Vec;
+use midly::{Error, Format, Header, MetaMessage, Timing, TrackEventKind, parse};
+
+use crate::midi_reader::{MidiReader, SongSource};
+
+pub const DEFAULT_TEMPO: u32 = 500_000; // micro seconds per quarter note, 120 BPM
+const DEFAULT_METER: (u8, u8) = (4, 2); // 4/4, the denominator is a power of 2
+
+#[derive(Debug)]
+pub enum TempoMapError {
+    Parse(Error),
+    TimecodeTiming,
+    TooManyTempoChanges,
+    TooManyTimeSignatures,
+}
+
+impl fmt::Display for TempoMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempoMapError::Parse(err) => write!(f, "not a valid midi file: {err}"),
+            TempoMapError::TimecodeTiming => write!(f, "SMPTE timecode timing is not supported"),
+            TempoMapError::TooManyTempoChanges => write!(f, "too many tempo changes"),
+            TempoMapError::TooManyTimeSignatures => write!(f, "too many time signature changes"),
+        }
+    }
+}
+
+impl From<Error> for TempoMapError {
+    fn from(err: Error) -> Self {
+        TempoMapError::Parse(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TempoChange {
+    tick: u64,
+    micros: u64, // time of the change
+    tempo: u32,  // micro seconds per quarter note
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MeterChange {
+    tick: u64,
+    bar: u32, // bar that starts at the change, counted from 0
+    numerator: u8,
+    denominator_pow2: u8, // 2 = quarter notes, 3 = eighth notes
+}
+
+/// a position in the song as a musician counts it, bars and beats start from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarBeat {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick_in_beat: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TempoMap<const TEMPOS: usize, const METERS: usize> {
+    ticks_per_quarter: u16,
+    tempos: Vec<TempoChange, TEMPOS>,
+    meters: Vec<MeterChange, METERS>,
+    length_ticks: u64,
+}
+
+impl<const TEMPOS: usize, const METERS: usize> TempoMap<TEMPOS, METERS> {
+    /// a song without any changes, 120 BPM in 4/4
+    pub fn new(ticks_per_quarter: u16) -> Self {
+        let mut tempo_map = TempoMap {
+            ticks_per_quarter: ticks_per_quarter.max(1),
+            tempos: Vec::new(),
+            meters: Vec::new(),
+            length_ticks: 0,
+        };
+        // the defaults at tick 0 are overridden by any change on the same tick
+        let _ = tempo_map.tempos.push(TempoChange {
+            tick: 0,
+            micros: 0,
+            tempo: DEFAULT_TEMPO,
+        });
+        let _ = tempo_map.meters.push(MeterChange {
+            tick: 0,
+            bar: 0,
+            numerator: DEFAULT_METER.0,
+            denominator_pow2: DEFAULT_METER.1,
+        });
+        tempo_map
+    }
+
+    /// scans every track of a midi file for tempo and time signature changes, `plays_track`
+    /// picks the patterns of a format 2 file that are played, the tracks of other formats are
+    /// all mapped since their changes hold for the whole song
+    pub fn from_midi(
+        midi: &[u8],
+        plays_track: impl Fn(usize) -> bool,
+    ) -> Result<Self, TempoMapError> {
+        let (header, tracks) = parse(midi)?;
+        let mut tempo_map = Self::for_header(header)?;
+        let sequential = header.format == Format::Sequential;
+
+        for (i, track) in tracks.enumerate() {
+            if sequential && !plays_track(i) {
+                continue;
+            }
+            let mut tick = tempo_map.track_start(sequential);
+            for event in track? {
+                let event = event?;
+                tick += event.delta.as_int() as u64;
+                tempo_map.add_event(tick, &event.kind)?;
+            }
+            tempo_map.length_ticks = tempo_map.length_ticks.max(tick);
+        }
+
+        tempo_map.recalculate();
+        Ok(tempo_map)
+    }
+
+    /// like from_midi, for a song read a piece at a time, the reader is rewound afterwards
+    pub fn from_reader<S: SongSource, const TRACKS: usize, const BUFFER: usize>(
+        reader: &mut MidiReader<S, TRACKS, BUFFER>,
+        plays_track: impl Fn(usize) -> bool,
+    ) -> Result<Self, TempoMapError> {
+        let header = reader.header();
+        let mut tempo_map = Self::for_header(header)?;
+        let sequential = header.format == Format::Sequential;
+
+        for i in 0..reader.track_count() {
+            if sequential && !plays_track(i) {
+                continue;
+            }
+            let mut tick = tempo_map.track_start(sequential);
+            while let Some(event) = reader.next_event(i) {
+                tick += event.delta.as_int() as u64;
+                tempo_map.add_event(tick, &event.kind)?;
+            }
+            tempo_map.length_ticks = tempo_map.length_ticks.max(tick);
+        }
+        reader.rewind();
+
+        tempo_map.recalculate();
+        Ok(tempo_map)
+    }
+
+    fn for_header(header: Header) -> Result<Self, TempoMapError> {
+        match header.timing {
+            Timing::Metrical(ticks) => Ok(Self::new(ticks.as_int())),
+            Timing::Timecode(_, _) => Err(TempoMapError::TimecodeTiming),
+        }
+    }
+
+    /// the patterns of a format 2 file start where the previous one ended
+    const fn track_start(&self, sequential: bool) -> u64 {
+        if sequential { self.length_ticks } else { 0 }
+    }
+
+    fn add_event(&mut self, tick: u64, kind: &TrackEventKind) -> Result<(), TempoMapError> {
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                self.insert_tempo(tick, tempo.as_int())
+            }
+            TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                self.insert_meter(tick, *numerator, *denominator)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // after the changes already on the same tick, so the later track wins like when playing
+    fn insert_tempo(&mut self, tick: u64, tempo: u32) -> Result<(), TempoMapError> {
+        let index = self.tempos.partition_point(|change| change.tick <= tick);
+        let change = TempoChange {
+            tick,
+            micros: 0,
+            tempo: tempo.max(1),
+        };
+        self.tempos
+            .insert(index, change)
+            .map_err(|_| TempoMapError::TooManyTempoChanges)
+    }
+
+    fn insert_meter(
+        &mut self,
+        tick: u64,
+        numerator: u8,
+        denominator_pow2: u8,
+    ) -> Result<(), TempoMapError> {
+        let index = self.meters.partition_point(|change| change.tick <= tick);
+        let change = MeterChange {
+            tick,
+            bar: 0,
+            numerator: numerator.max(1),
+            denominator_pow2: denominator_pow2.min(6), // up to 64th notes
+        };
+        self.meters
+            .insert(index, change)
+            .map_err(|_| TempoMapError::TooManyTimeSignatures)
+    }
+
+    /// fills in the time and bar of every change
+    fn recalculate(&mut self) {
+        for i in 1..self.tempos.len() {
+            let previous = self.tempos[i - 1];
+            self.tempos[i].micros = previous.micros
+                + self.ticks_to_micros(self.tempos[i].tick - previous.tick, previous.tempo);
+        }
+
+        for i in 1..self.meters.len() {
+            let previous = self.meters[i - 1];
+            let ticks_per_bar = self.ticks_per_bar(&previous);
+            // a change in the middle of a bar starts a new bar
+            let bars = (self.meters[i].tick - previous.tick).div_ceil(ticks_per_bar);
+            self.meters[i].bar = previous.bar + bars as u32;
+        }
+    }
+
+    #[inline(always)]
+    fn ticks_to_micros(&self, ticks: u64, tempo: u32) -> u64 {
+        ticks * tempo as u64 / self.ticks_per_quarter as u64
+    }
+
+    fn ticks_per_beat(&self, meter: &MeterChange) -> u64 {
+        // a quarter note is 2^2, so the beat is 4 / 2^n quarter notes
+        ((self.ticks_per_quarter as u64 * 4) >> meter.denominator_pow2).max(1)
+    }
+
+    fn ticks_per_bar(&self, meter: &MeterChange) -> u64 {
+        self.ticks_per_beat(meter) * meter.numerator as u64
+    }
+
+    /// the tick of the last event in any track
+    pub const fn length_ticks(&self) -> u64 {
+        self.length_ticks
+    }
+
+    pub fn length_micros(&self) -> u64 {
+        self.tick_to_micros(self.length_ticks)
+    }
+
+    pub fn tick_to_micros(&self, tick: u64) -> u64 {
+        let index = self.tempos.partition_point(|change| change.tick <= tick);
+        let change = self.tempos[index.saturating_sub(1)];
+        change.micros + self.ticks_to_micros(tick - change.tick, change.tempo)
+    }
+
+    /// the tick closest to the time, so rounded times of events still land on their tick
+    pub fn micros_to_tick(&self, micros: u64) -> u64 {
+        let index = self
+            .tempos
+            .partition_point(|change| change.micros <= micros);
+        // several changes can share the same time, the last of them is the one in effect
+        let change = self.tempos[index.saturating_sub(1)];
+        let tempo = change.tempo as u64;
+        change.tick + ((micros - change.micros) * self.ticks_per_quarter as u64 + tempo / 2) / tempo
+    }
+
+    /// the first beat at or after the tick, a time signature change always starts a beat
+    pub fn next_beat(&self, tick: u64) -> u64 {
+        let index = self.meters.partition_point(|change| change.tick <= tick);
+        let meter = self.meters[index.saturating_sub(1)];
+
+        let ticks_per_beat = self.ticks_per_beat(&meter);
+        let ticks_into_beat = (tick - meter.tick) % ticks_per_beat;
+        let beat = if ticks_into_beat == 0 {
+            tick
+        } else {
+            tick + ticks_per_beat - ticks_into_beat
+        };
+
+        match self.meters.get(index) {
+            Some(next_meter) if next_meter.tick < beat => next_meter.tick,
+            _ => beat,
+        }
+    }
+
+    /// beats per bar at the tick
+    pub fn beats_per_bar(&self, tick: u64) -> u8 {
+        let index = self.meters.partition_point(|change| change.tick <= tick);
+        self.meters[index.saturating_sub(1)].numerator
+    }
+
+    pub fn tick_to_bar_beat(&self, tick: u64) -> BarBeat {
+        let index = self.meters.partition_point(|change| change.tick <= tick);
+        let meter = self.meters[index.saturating_sub(1)];
+
+        let ticks_per_beat = self.ticks_per_beat(&meter);
+        let ticks_per_bar = self.ticks_per_bar(&meter);
+        let ticks_in_meter = tick - meter.tick;
+
+        BarBeat {
+            bar: meter.bar + (ticks_in_meter / ticks_per_bar) as u32 + 1,
+            beat: (ticks_in_meter % ticks_per_bar / ticks_per_beat) as u32 + 1,
+            tick_in_beat: (ticks_in_meter % ticks_per_beat) as u32,
+        }
+    }
+}
@@ -0,0 +1,192 @@
+// =============================================================================================
+//                                TICK ACCOUNTING FOR TONES
+// =============================================================================================
+
+// kept free of any hardware access so the toggling logic can be driven by a simulated tick counter
+//
+// note durations are in micro seconds and count down by the length of every tick
+
+/// time between two tone timer interrupts in micro seconds
+pub const TICK_MICROS: u16 = 20;
+
+/// an even square wave, the loudest a buzzer gets
+pub const FULL_DUTY_PCT: u8 = 50;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToneClock {
+    pub period_micros: u16,    // micro seconds between pin toggles
+    pub remaining_micros: i32, // before the note stops, negative when finished
+    pub duty_pct: u8,          // of the wave the pin is high, FULL_DUTY_PCT at most
+    elapsed_micros: u16,       // micro seconds since the last toggle
+}
+
+impl ToneClock {
+    pub const fn new(period_micros: u16) -> Self {
+        Self {
+            period_micros,
+            remaining_micros: i32::MAX,
+            duty_pct: FULL_DUTY_PCT,
+            elapsed_micros: 0,
+        }
+    }
+
+    pub fn start(&mut self, period_micros: u16, duration: Option<i32>) {
+        self.period_micros = period_micros;
+        self.remaining_micros = duration.unwrap_or(i32::MAX);
+        self.elapsed_micros = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.remaining_micros = i32::MAX;
+        self.elapsed_micros = 0;
+    }
+
+    #[inline(always)]
+    pub const fn is_finished(&self) -> bool {
+        self.remaining_micros < 0
+    }
+
+    /// micro seconds the pin stays high or low, with the full duty both are the period
+    #[inline(always)]
+    const fn half_micros(&self, high: bool) -> u16 {
+        if self.duty_pct >= FULL_DUTY_PCT {
+            return self.period_micros;
+        }
+        let wave = self.period_micros as u32 * 2;
+        let high_micros = wave * self.duty_pct as u32 / 100;
+        let half = if high {
+            high_micros
+        } else {
+            wave - high_micros
+        };
+        if half > u16::MAX as u32 {
+            u16::MAX
+        } else {
+            half as u16
+        }
+    }
+
+    /// advances the clock by one tick, returns true when the pin should be toggled,
+    /// high is the level the pin is at
+    #[inline(always)]
+    pub fn tick(&mut self, tick_micros: u16, high: bool) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        self.remaining_micros = self.remaining_micros.saturating_sub(tick_micros as i32);
+        // a silent note only brings the pin down
+        if self.duty_pct == 0 {
+            return high;
+        }
+        self.elapsed_micros = self.elapsed_micros.saturating_add(tick_micros);
+
+        let half_micros = self.half_micros(high);
+        if self.elapsed_micros < half_micros {
+            return false;
+        }
+        // keep the leftover micros so the average frequency stays correct
+        // even when the period is not a multiple of the tick
+        self.elapsed_micros -= half_micros;
+        if self.elapsed_micros >= self.half_micros(!high) {
+            self.elapsed_micros = 0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// runs the clock for the ticks like the timer interrupt does, returns the micros the pin
+    /// spent high and how many times it was toggled
+    fn run(clock: &mut ToneClock, ticks: u32, high: &mut bool) -> (u32, u32) {
+        let (mut high_micros, mut toggles) = (0, 0);
+        for _ in 0..ticks {
+            if *high {
+                high_micros += TICK_MICROS as u32;
+            }
+            if clock.tick(TICK_MICROS, *high) {
+                *high = !*high;
+                toggles += 1;
+            }
+        }
+        (high_micros, toggles)
+    }
+
+    #[test]
+    fn a_period_of_whole_ticks_toggles_on_time() {
+        let mut clock = ToneClock::new(100);
+        let mut high = false;
+        for _ in 0..20 {
+            let toggled = (0..5)
+                .map(|_| clock.tick(TICK_MICROS, high))
+                .collect::<Vec<_>>();
+            assert_eq!(toggled, [false, false, false, false, true]);
+            high = !high;
+        }
+    }
+
+    #[test]
+    fn the_frequency_stays_right_between_ticks() {
+        for period_micros in [110, 130, 1136, 2273, 9999] {
+            let mut clock = ToneClock::new(period_micros);
+            let ticks = period_micros as u32 * 50; // a thousand toggles at 20 us a tick
+            let (_, toggles) = run(&mut clock, ticks, &mut false);
+            assert!(
+                (999..=1000).contains(&toggles),
+                "{period_micros}: {toggles}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_duration_stops_the_note_in_micro_seconds() {
+        let mut clock = ToneClock::new(100);
+        clock.start(100, Some(1000));
+        let mut high = false;
+        run(&mut clock, 1000 / TICK_MICROS as u32, &mut high);
+        assert!(!clock.is_finished());
+        run(&mut clock, 1, &mut high);
+        assert!(clock.is_finished());
+        assert_eq!(run(&mut clock, 100, &mut high).1, 0);
+
+        // held notes don't run out
+        clock.start(100, None);
+        run(&mut clock, 1_000_000, &mut high);
+        assert!(!clock.is_finished());
+
+        clock.start(100, Some(0));
+        run(&mut clock, 1, &mut high);
+        assert!(clock.is_finished());
+        clock.reset();
+        assert!(!clock.is_finished());
+    }
+
+    #[test]
+    fn the_duty_cycle_sets_the_time_the_pin_is_high() {
+        for duty_pct in [1, 10, 25, 40, FULL_DUTY_PCT, 80] {
+            let mut clock = ToneClock::new(1000);
+            clock.duty_pct = duty_pct;
+            let mut high = false;
+            // two thousand waves of 2000 us
+            let ticks = 2000 * 2000 / TICK_MICROS as u32;
+            let (high_micros, toggles) = run(&mut clock, ticks, &mut high);
+            let high_pct = high_micros as f32 * 100.0 / (ticks * TICK_MICROS as u32) as f32;
+            let expected = duty_pct.min(FULL_DUTY_PCT) as f32;
+            assert!(
+                (high_pct - expected).abs() < 0.5,
+                "{duty_pct}%: {high_pct}%"
+            );
+            assert!((3999..=4000).contains(&toggles), "{duty_pct}%: {toggles}");
+        }
+    }
+
+    #[test]
+    fn a_silent_note_only_brings_the_pin_down() {
+        let mut clock = ToneClock::new(100);
+        clock.duty_pct = 0;
+        assert!(clock.tick(TICK_MICROS, true));
+        assert_eq!(run(&mut clock, 1000, &mut false), (0, 0));
+    }
+}
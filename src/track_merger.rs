@@ -0,0 +1,160 @@
+// =============================================================================================
+//                          MERGING THE TRACKS OF A SONG INTO ONE ORDER
+// =============================================================================================
+
+// all tracks of a midi file play at the same time, the merger keeps the absolute tick of the
+// next event of every track in a min heap and always hands out the one that comes first,
+// the heap is ordered by (tick, track) so events on the same tick come in track order
+// like the song was written, and each event costs O(log tracks) instead of a scan
+//
+// the patterns of a format 2 file are independent and play one after another instead,
+// a track starts on the tick the previous one ran out
+//
+// the capacity is a const generic so it's known how much memory the merger takes,
+// a song with more tracks is rejected instead of losing the extra tracks
+
+use heapless::{
+    Vec,
+    binary_heap::{BinaryHeap, Min, PeekMut},
+};
+use midly::{EventIter, TrackEventKind};
+
+use crate::song_check::SongError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergedEvent<'a> {
+    pub tick: u64, // from the start of the song
+    pub track: usize,
+    pub kind: TrackEventKind<'a>,
+}
+
+struct TrackCursor<'a> {
+    track: usize,
+    events: EventIter<'a>,
+    // the next event of the track and its tick
+    tick: u64,
+    pending: Option<TrackEventKind<'a>>,
+}
+
+impl<'a> TrackCursor<'a> {
+    /// false once the track has ended, a broken event ends it too,
+    /// the song check has already reported those
+    fn read_next(&mut self) -> bool {
+        self.pending = match self.events.next() {
+            Some(Ok(event)) => {
+                self.tick += event.delta.as_int() as u64;
+                Some(event.kind)
+            }
+            _ => None,
+        };
+        self.pending.is_some()
+    }
+}
+
+pub struct TrackMerger<'a, const TRACKS: usize> {
+    tracks: Vec<TrackCursor<'a>, TRACKS>,
+    // (tick, index in tracks) of every track with an event left
+    queue: BinaryHeap<(u64, usize), Min, TRACKS>,
+    tick: u64, // of the last merged event
+    // the track to start when the queue runs out, None when all tracks start together
+    next_pattern: Option<usize>,
+}
+
+impl<'a, const TRACKS: usize> TrackMerger<'a, TRACKS> {
+    /// the tracks with their index in the file, in file order
+    pub fn new(tracks: impl Iterator<Item = (usize, EventIter<'a>)>) -> Result<Self, SongError> {
+        let mut merger = Self::with_tracks(tracks)?;
+        for i in 0..merger.tracks.len() {
+            merger.start_track(i, 0);
+        }
+        Ok(merger)
+    }
+
+    /// the tracks play one after another, like the patterns of a format 2 file
+    pub fn sequential(
+        tracks: impl Iterator<Item = (usize, EventIter<'a>)>,
+    ) -> Result<Self, SongError> {
+        let mut merger = Self::with_tracks(tracks)?;
+        merger.next_pattern = Some(0);
+        merger.start_next_pattern();
+        Ok(merger)
+    }
+
+    fn with_tracks(
+        tracks: impl Iterator<Item = (usize, EventIter<'a>)>,
+    ) -> Result<Self, SongError> {
+        let mut merger = TrackMerger {
+            tracks: Vec::new(),
+            queue: BinaryHeap::new(),
+            tick: 0,
+            next_pattern: None,
+        };
+        let mut track_count = 0;
+        for (track, events) in tracks {
+            track_count += 1;
+            // keep counting, so the error tells how many tracks the song has
+            let _ = merger.tracks.push(TrackCursor {
+                track,
+                events,
+                tick: 0,
+                pending: None,
+            });
+        }
+
+        if track_count > TRACKS {
+            return Err(SongError::TooManyTracks {
+                tracks: track_count,
+                capacity: TRACKS,
+            });
+        }
+        Ok(merger)
+    }
+
+    /// queues the first event of the track, false if the track is empty
+    fn start_track(&mut self, i: usize, tick: u64) -> bool {
+        let cursor = &mut self.tracks[i];
+        cursor.tick = tick;
+        if !cursor.read_next() {
+            return false;
+        }
+        // every track is queued at most once, so the queue can't be full
+        let _ = self.queue.push((cursor.tick, i));
+        true
+    }
+
+    fn start_next_pattern(&mut self) {
+        while let Some(i) = self.next_pattern.filter(|i| *i < self.tracks.len()) {
+            self.next_pattern = Some(i + 1);
+            if self.start_track(i, self.tick) {
+                break;
+            }
+        }
+    }
+}
+
+impl<'a, const TRACKS: usize> Iterator for TrackMerger<'a, TRACKS> {
+    type Item = MergedEvent<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.queue.is_empty() {
+            self.start_next_pattern();
+        }
+        let mut first = self.queue.peek_mut()?;
+        let (tick, i) = *first;
+        let cursor = &mut self.tracks[i];
+        let kind = cursor.pending.take()?;
+        self.tick = tick;
+
+        // the track's next event takes its place, so the heap is sifted only once
+        if cursor.read_next() {
+            *first = (cursor.tick, i);
+        } else {
+            PeekMut::pop(first);
+        }
+        Some(MergedEvent {
+            tick,
+            track: self.tracks[i].track,
+            kind,
+        })
+    }
+}
@@ -0,0 +1,200 @@
+// =============================================================================================
+//                        TRANSPOSING NOTES INTO THE PLAYABLE RANGE
+// =============================================================================================
+
+// notes are first moved by the song and channel transpose, then notes outside the range of
+// the instrument are folded by whole octaves back inside it, so the melody keeps its notes
+// instead of piling up on the highest or lowest playable key
+
+use midly::num::{u4, u7};
+
+use crate::sound_profiles::SoundProfile;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transpose {
+    pub song_semitones: i8,
+    pub channel_semitones: [i8; 16],
+    pub live_semitones: i8, // changed with the encoder while playing
+    pub key_semitones: i8,  // moves the song to the target key, see Key::semitones_to
+}
+
+impl Transpose {
+    pub const fn new(song_semitones: i8) -> Self {
+        Transpose {
+            song_semitones,
+            channel_semitones: [0; 16],
+            live_semitones: 0,
+            key_semitones: 0,
+        }
+    }
+
+    /// the key that is actually played for a note of the channel
+    pub fn key_for(&self, channel: u4, key: u7, sound_profile: &SoundProfile) -> u7 {
+        let shifted = key.as_int() as i16
+            + self.song_semitones as i16
+            + self.channel_semitones[channel.as_int() as usize] as i16
+            + self.live_semitones as i16
+            + self.key_semitones as i16;
+
+        u7::new(fold_into_range(
+            shifted,
+            sound_profile.lowest_key,
+            sound_profile.highest_key,
+        ))
+    }
+}
+
+/// a key signature like in the midi KeySignature event, sharps are positive and flats negative
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl Key {
+    pub const C_MAJOR: Key = Key::new(0, false);
+
+    pub const fn new(sharps: i8, minor: bool) -> Self {
+        Key { sharps, minor }
+    }
+
+    // every sharp moves the major key up a fifth, 0 = C
+    const fn signature_tonic(&self) -> i16 {
+        (self.sharps as i16 * 7).rem_euclid(12)
+    }
+
+    /// the smallest move, -6 to +5 semitones, that gives the song the key signature of the target,
+    /// so a minor song moved to C major ends up in A minor
+    pub const fn semitones_to(&self, target: Key) -> i8 {
+        let up = (target.signature_tonic() - self.signature_tonic()).rem_euclid(12);
+        if up > 5 { up as i8 - 12 } else { up as i8 }
+    }
+}
+
+/// moves the key by octaves until it is between lowest and highest,
+/// a range narrower than an octave can't fit every note, so those end up on the closer end
+pub const fn fold_into_range(key: i16, lowest_key: u8, highest_key: u8) -> u8 {
+    let lowest = lowest_key as i16;
+    let highest = if highest_key < lowest_key {
+        lowest
+    } else {
+        highest_key as i16
+    };
+
+    let mut key = key;
+    if key < lowest {
+        key += (lowest - key + 11) / 12 * 12;
+    } else if key > highest {
+        key -= (key - highest + 11) / 12 * 12;
+    }
+
+    if key < lowest {
+        lowest as u8
+    } else if key > highest {
+        highest as u8
+    } else {
+        key as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound_profiles::DEFAULT_SOUND;
+
+    /// a sound that plays the two octaves from C3 to C5
+    const TWO_OCTAVES: SoundProfile = SoundProfile {
+        lowest_key: 48,
+        highest_key: 72,
+        ..DEFAULT_SOUND
+    };
+
+    fn played(transpose: &Transpose, channel: u8, key: u8, profile: &SoundProfile) -> u8 {
+        transpose
+            .key_for(u4::new(channel), u7::new(key), profile)
+            .as_int()
+    }
+
+    #[test]
+    fn notes_outside_the_range_fold_by_octaves() {
+        for key in [48, 60, 72] {
+            assert_eq!(fold_into_range(key, 48, 72), key as u8);
+        }
+        // below the range the notes come up an octave at a time, and above it they go down
+        assert_eq!(fold_into_range(47, 48, 72), 59);
+        assert_eq!(fold_into_range(40, 48, 72), 52);
+        assert_eq!(fold_into_range(30, 48, 72), 54);
+        assert_eq!(fold_into_range(-5, 48, 72), 55);
+        assert_eq!(fold_into_range(73, 48, 72), 61);
+        assert_eq!(fold_into_range(84, 48, 72), 72);
+        assert_eq!(fold_into_range(100, 48, 72), 64);
+        assert_eq!(fold_into_range(500, 48, 72), 68);
+
+        // the folded notes keep their name
+        for key in -200..300 {
+            let folded = fold_into_range(key, 48, 72) as i16;
+            assert!((48..=72).contains(&folded), "{key}");
+            assert_eq!(folded.rem_euclid(12), key.rem_euclid(12), "{key}");
+        }
+    }
+
+    #[test]
+    fn a_range_narrower_than_an_octave_keeps_what_notes_it_can() {
+        // C to E
+        for key in -50..200 {
+            let folded = fold_into_range(key, 60, 64);
+            assert!((60..=64).contains(&folded), "{key}");
+            if key.rem_euclid(12) <= 4 {
+                assert_eq!(folded as i16 % 12, key.rem_euclid(12), "{key}");
+            }
+        }
+        // the other notes go to the end they were folded past
+        assert_eq!(fold_into_range(55, 60, 64), 64);
+        assert_eq!(fold_into_range(67, 60, 64), 60);
+
+        // one key plays everything, and so does a range that is the wrong way round
+        assert_eq!(fold_into_range(30, 62, 62), 62);
+        assert_eq!(fold_into_range(90, 62, 62), 62);
+        assert_eq!(fold_into_range(30, 62, 50), 62);
+    }
+
+    #[test]
+    fn the_transposes_add_up_before_folding() {
+        let mut transpose = Transpose::new(2);
+        transpose.channel_semitones[3] = 5;
+        transpose.live_semitones = -1;
+        assert_eq!(played(&transpose, 3, 60, &TWO_OCTAVES), 66);
+        assert_eq!(played(&transpose, 0, 60, &TWO_OCTAVES), 61);
+        transpose.key_semitones = -3;
+        assert_eq!(played(&transpose, 3, 60, &TWO_OCTAVES), 63);
+
+        // together they move past the top of the range and the note folds back down
+        let mut transpose = Transpose::new(12);
+        transpose.channel_semitones[9] = 12;
+        transpose.live_semitones = 12;
+        assert_eq!(played(&transpose, 9, 62, &TWO_OCTAVES), 62);
+        assert_eq!(played(&transpose, 0, 62, &TWO_OCTAVES), 62);
+        assert_eq!(played(&transpose, 0, 61, &TWO_OCTAVES), 61);
+
+        // and below the bottom
+        let mut transpose = Transpose::new(-24);
+        transpose.live_semitones = -24;
+        assert_eq!(played(&transpose, 0, 50, &TWO_OCTAVES), 50);
+
+        // the largest transposes stay playable
+        let transpose = Transpose {
+            song_semitones: i8::MAX,
+            channel_semitones: [i8::MAX; 16],
+            live_semitones: i8::MAX,
+            key_semitones: i8::MAX,
+        };
+        assert_eq!(played(&transpose, 0, 127, &TWO_OCTAVES), 71);
+        let transpose = Transpose {
+            song_semitones: i8::MIN,
+            channel_semitones: [i8::MIN; 16],
+            live_semitones: i8::MIN,
+            key_semitones: i8::MIN,
+        };
+        assert_eq!(played(&transpose, 0, 0, &TWO_OCTAVES), 52);
+    }
+}
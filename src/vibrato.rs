@@ -0,0 +1,204 @@
+// =============================================================================================
+//                              VIBRATO LFO FOR SOUNDING NOTES
+// =============================================================================================
+
+// a sine LFO moves the period of a sounding note up and down around the period of its key,
+// or around the period of a glide to it
+// the depth is the vibrato of the sound profile, which can start after a delay like a
+// violinist's, plus the modulation wheel (CC 1) of the channel
+//
+// all fixed point, the LFO is worked out from the time since the note started instead of
+// being stepped, so it doesn't drift however often it's asked
+
+/// the rate of the modulation wheel vibrato of profiles without their own
+pub const DEFAULT_RATE_CENTIHERTZ: u16 = 550;
+
+/// depth of the modulation wheel turned all the way up
+pub const MOD_WHEEL_DEPTH_CENTS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vibrato {
+    pub rate_centihertz: u16,
+    pub depth_cents: u8, // each way from the key
+    pub delay_millis: u16,
+}
+
+impl Vibrato {
+    /// only the modulation wheel
+    pub const NONE: Vibrato = Vibrato::new(DEFAULT_RATE_CENTIHERTZ, 0, 0);
+
+    pub const fn new(rate_centihertz: u16, depth_cents: u8, delay_millis: u16) -> Self {
+        Vibrato {
+            rate_centihertz,
+            depth_cents,
+            delay_millis,
+        }
+    }
+}
+
+/// the LFO of one sounding note
+#[derive(Debug, Clone, Copy)]
+pub struct VibratoLfo {
+    vibrato: Vibrato,
+    started_at: u64,
+}
+
+impl VibratoLfo {
+    pub const fn new(vibrato: Vibrato, started_at: u64) -> Self {
+        VibratoLfo {
+            vibrato,
+            started_at,
+        }
+    }
+
+    /// the period of the note at the time, with the modulation wheel of the channel at 0 - 127
+    pub const fn period(&self, key_period: u16, now: u64, mod_wheel: u8) -> u16 {
+        let elapsed = now.saturating_sub(self.started_at);
+        let mut depth_cents =
+            if mod_wheel > 127 { 127 } else { mod_wheel } as u32 * MOD_WHEEL_DEPTH_CENTS / 127;
+        if elapsed >= self.vibrato.delay_millis as u64 * 1000 {
+            depth_cents += self.vibrato.depth_cents as u32;
+        }
+        if depth_cents == 0 {
+            return key_period;
+        }
+
+        let sine = sine(phase(elapsed, self.vibrato.rate_centihertz));
+        // a cent is ln(2) / 1200, about 1 / 1731 of the period, close enough for a vibrato,
+        // a higher pitch is a shorter period
+        let change = key_period as i64 * depth_cents as i64 * sine as i64 / (1731 * 32768);
+        let period = key_period as i64 - change;
+        if period < 1 {
+            1
+        } else if period > u16::MAX as i64 {
+            u16::MAX
+        } else {
+            period as u16
+        }
+    }
+}
+
+/// how far into its cycle the LFO is, a whole cycle is 65536
+pub const fn phase(elapsed_micros: u64, rate_centihertz: u16) -> u16 {
+    // centihertz times micros counts hundred millionths of a cycle
+    let cycle = elapsed_micros * rate_centihertz as u64 % 100_000_000;
+    (cycle * 65536 / 100_000_000) as u16
+}
+
+// the first quarter of a sine wave in 16 steps, 32767 at the top
+const QUARTER_SINE: [i32; 17] = [
+    0, 3212, 6393, 9512, 12539, 15446, 18204, 20787, 23170, 25329, 27245, 28898, 30273, 31356,
+    32137, 32609, 32767,
+];
+
+/// sine of the phase from -32767 to 32767, between the table steps it's a straight line
+pub const fn sine(phase: u16) -> i32 {
+    let quarter = phase >> 14;
+    let within = (phase & 0x3FFF) as usize;
+    // the second and fourth quarters are the first one backwards
+    let x = if quarter & 1 == 1 {
+        0x4000 - within
+    } else {
+        within
+    };
+    let step = x >> 10;
+    let fraction = (x & 0x3FF) as i32;
+    let value = if step == 16 {
+        QUARTER_SINE[16]
+    } else {
+        QUARTER_SINE[step] + (QUARTER_SINE[step + 1] - QUARTER_SINE[step]) * fraction / 1024
+    };
+    if quarter >= 2 { -value } else { value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_sine_follows_the_real_one() {
+        for phase in (0..=u16::MAX).step_by(7) {
+            let angle = phase as f64 / 65536.0 * core::f64::consts::TAU;
+            let expected = angle.sin() * 32767.0;
+            let off = (sine(phase) as f64 - expected).abs();
+            // the straight lines between the table steps are about 0.12 % under the curve
+            assert!(
+                off < 45.0,
+                "phase {phase}: {} instead of {expected}",
+                sine(phase)
+            );
+        }
+        assert_eq!(sine(0), 0);
+        assert_eq!(sine(0x4000), 32767);
+        assert_eq!(sine(0x8000), 0);
+        assert_eq!(sine(0xC000), -32767);
+    }
+
+    #[test]
+    fn the_sine_is_odd_and_symmetric() {
+        for phase in 0..0x8000u16 {
+            assert_eq!(sine(phase), -sine(phase + 0x8000), "phase {phase}");
+            assert_eq!(
+                sine(phase),
+                sine(0x8000u16.wrapping_sub(phase)),
+                "phase {phase}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_phase_goes_round_at_the_rate() {
+        // 5 Hz is a cycle every 200 ms
+        assert_eq!(phase(0, 500), 0);
+        assert_eq!(phase(50_000, 500), 0x4000);
+        assert_eq!(phase(100_000, 500), 0x8000);
+        assert_eq!(phase(200_000, 500), 0);
+        assert_eq!(phase(250_000, 500), 0x4000);
+        // it doesn't drift after hours
+        let hours = 3 * 3600 * 1_000_000;
+        assert_eq!(phase(hours + 50_000, 500), 0x4000);
+        assert_eq!(phase(1_000_000, 0), 0);
+        // 0.01 Hz, the slowest rate, still moves
+        assert!(phase(1_000_000, 1) > 0);
+    }
+
+    #[test]
+    fn the_period_swings_by_the_depth() {
+        let lfo = VibratoLfo::new(Vibrato::new(500, 20, 0), 1_000);
+        let key_period = 2273; // A4, 440 Hz
+        let cents = |period: u16| 1200.0 * (key_period as f64 / period as f64).log2();
+
+        assert_eq!(lfo.period(key_period, 1_000, 0), key_period);
+        // a quarter of the cycle is the highest pitch and three quarters the lowest
+        let high = lfo.period(key_period, 1_000 + 50_000, 0);
+        let low = lfo.period(key_period, 1_000 + 150_000, 0);
+        assert!((cents(high) - 20.0).abs() < 0.5, "{high}");
+        assert!((cents(low) + 20.0).abs() < 0.5, "{low}");
+
+        // the modulation wheel all the way up adds its depth
+        let high = lfo.period(key_period, 1_000 + 50_000, 127);
+        assert!((cents(high) - 70.0).abs() < 1.0, "{high}");
+    }
+
+    #[test]
+    fn the_vibrato_waits_for_its_delay() {
+        let lfo = VibratoLfo::new(Vibrato::new(500, 30, 300), 0);
+        // 50 ms in is the top of the first cycle, but the delay isn't over
+        assert_eq!(lfo.period(1000, 50_000, 0), 1000);
+        assert!(lfo.period(1000, 50_000, 64) < 1000);
+        // two cycles later it is
+        assert!(lfo.period(1000, 450_000, 0) < 1000);
+    }
+
+    #[test]
+    fn without_any_depth_the_period_stays() {
+        let lfo = VibratoLfo::new(Vibrato::NONE, 0);
+        for now in (0..1_000_000).step_by(12_345) {
+            assert_eq!(lfo.period(3000, now, 0), 3000);
+        }
+        // the shortest and longest periods stay in range
+        let lfo = VibratoLfo::new(Vibrato::new(500, 255, 0), 0);
+        assert!(lfo.period(1, 50_000, 127) >= 1);
+        assert_eq!(lfo.period(u16::MAX, 150_000, 127), u16::MAX);
+    }
+}
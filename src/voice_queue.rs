@@ -0,0 +1,327 @@
+// =============================================================================================
+//                        VOICE COMMANDS FROM THE SEQUENCER TO THE SYNTH
+// =============================================================================================
+
+// the sequencer core only decides what should be played, the synthesis core owns the buzzers
+// the commands go through a lock-free single producer single consumer queue,
+// generic over the key and sound types so it doesn't depend on the buzzer backend
+
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// controller changes that reach the sounding notes of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelControl {
+    Volume(u8),         // CC 7
+    Expression(u8),     // CC 11
+    ModWheel(u8),       // CC 1, the vibrato depth
+    PortamentoTime(u8), // CC 5
+    Portamento(bool),   // CC 65
+    Legato(bool),       // CC 68
+    NotesOff,           // CC 120, 123 and the mode messages
+    ResetControllers,   // CC 121
+    Mono(bool),         // CC 126, and CC 127 back to poly
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoiceCommand<K, P> {
+    NoteOn {
+        key: K,
+        profile: P,
+    },
+    NoteOff {
+        key: K,
+    },
+    AllOff,
+    Control {
+        channel: u8,
+        control: ChannelControl,
+    },
+}
+
+impl<K, P> VoiceCommand<K, P> {
+    #[inline(always)]
+    pub fn apply(self, sink: &mut impl VoiceSink<K, P>) {
+        match self {
+            VoiceCommand::NoteOn { key, profile } => sink.note_on(key, profile),
+            VoiceCommand::NoteOff { key } => sink.note_off(key),
+            VoiceCommand::AllOff => sink.all_off(),
+            VoiceCommand::Control { channel, control } => sink.control(channel, control),
+        }
+    }
+}
+
+/// anything that can play the voices the sequencer asks for
+pub trait VoiceSink<K, P> {
+    fn note_on(&mut self, key: K, profile: P);
+
+    fn note_off(&mut self, key: K);
+
+    fn all_off(&mut self);
+
+    /// sinks that can't change a sounding note leave the controls out
+    fn control(&mut self, _channel: u8, _control: ChannelControl) {}
+
+    /// called regularly so notes with a fixed duration can end
+    fn refresh(&mut self) {}
+}
+
+/// holds N - 1 commands
+pub type VoiceQueue<K, P, const N: usize> = Queue<VoiceCommand<K, P>, N>;
+
+pub fn split_voice_queue<K, P, const N: usize>(
+    queue: &mut VoiceQueue<K, P, N>,
+) -> (VoiceSender<'_, K, P>, VoiceReceiver<'_, K, P>) {
+    let (producer, consumer) = queue.split();
+    (VoiceSender { producer }, VoiceReceiver { consumer })
+}
+
+// =============================================================================================
+//                                    SENDING AND RECEIVING
+// =============================================================================================
+
+pub struct VoiceSender<'q, K, P> {
+    producer: Producer<'q, VoiceCommand<K, P>>,
+}
+
+impl<K, P> VoiceSender<'_, K, P> {
+    /// waits for space instead of dropping, a lost note off would leave a note stuck
+    pub fn send(&mut self, command: VoiceCommand<K, P>) {
+        let mut command = command;
+        while let Err(rejected) = self.producer.enqueue(command) {
+            command = rejected;
+            core::hint::spin_loop();
+        }
+    }
+
+    /// waits until the receiver has applied every sent command
+    pub fn flush(&self) {
+        while !self.producer.is_empty() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<K, P> VoiceSink<K, P> for VoiceSender<'_, K, P> {
+    fn note_on(&mut self, key: K, profile: P) {
+        self.send(VoiceCommand::NoteOn { key, profile });
+    }
+
+    fn note_off(&mut self, key: K) {
+        self.send(VoiceCommand::NoteOff { key });
+    }
+
+    fn all_off(&mut self) {
+        self.send(VoiceCommand::AllOff);
+    }
+
+    fn control(&mut self, channel: u8, control: ChannelControl) {
+        self.send(VoiceCommand::Control { channel, control });
+    }
+}
+
+pub struct VoiceReceiver<'q, K, P> {
+    consumer: Consumer<'q, VoiceCommand<K, P>>,
+}
+
+impl<K, P> VoiceReceiver<'_, K, P> {
+    /// applies every waiting command, returns how many there were,
+    /// refreshing the voices is left to the caller
+    pub fn drain_into(&mut self, sink: &mut impl VoiceSink<K, P>) -> usize
+    where
+        K: Copy,
+        P: Copy,
+    {
+        // the command is only removed after it has been applied, so an empty queue
+        // means the sender can rely on everything being played, see VoiceSender::flush
+        let mut applied = 0;
+        while let Some(command) = self.consumer.peek().copied() {
+            command.apply(sink);
+            self.consumer.dequeue();
+            applied += 1;
+        }
+        applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    /// remembers what was played, the count is shared with the sending thread
+    struct Recorder<'a> {
+        played: Vec<VoiceCommand<u32, u8>>,
+        applied: &'a AtomicUsize,
+    }
+
+    impl VoiceSink<u32, u8> for Recorder<'_> {
+        fn note_on(&mut self, key: u32, profile: u8) {
+            self.played.push(VoiceCommand::NoteOn { key, profile });
+            self.applied.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn note_off(&mut self, key: u32) {
+            self.played.push(VoiceCommand::NoteOff { key });
+            self.applied.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn all_off(&mut self) {
+            self.played.push(VoiceCommand::AllOff);
+            self.applied.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn control(&mut self, channel: u8, control: ChannelControl) {
+            self.played.push(VoiceCommand::Control { channel, control });
+            self.applied.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn song(notes: u32) -> Vec<VoiceCommand<u32, u8>> {
+        let mut commands = Vec::new();
+        for key in 0..notes {
+            commands.push(VoiceCommand::NoteOn {
+                key,
+                profile: key as u8,
+            });
+            if key % 7 == 0 {
+                commands.push(VoiceCommand::Control {
+                    channel: (key % 16) as u8,
+                    control: ChannelControl::ModWheel(key as u8 & 0x7F),
+                });
+            }
+            commands.push(VoiceCommand::NoteOff { key });
+        }
+        commands.push(VoiceCommand::AllOff);
+        commands
+    }
+
+    #[test]
+    fn commands_arrive_in_order_across_threads() {
+        let mut queue: VoiceQueue<u32, u8, 8> = VoiceQueue::new();
+        let (mut sender, mut receiver) = split_voice_queue(&mut queue);
+        let commands = song(1000);
+        let applied = AtomicUsize::new(0);
+        let done = AtomicBool::new(false);
+
+        let played = thread::scope(|scope| {
+            let consumer = scope.spawn(|| {
+                let mut recorder = Recorder {
+                    played: Vec::new(),
+                    applied: &applied,
+                };
+                while !done.load(Ordering::SeqCst)
+                    || applied.load(Ordering::SeqCst) < commands.len()
+                {
+                    if receiver.drain_into(&mut recorder) == 0 {
+                        thread::yield_now();
+                    }
+                }
+                recorder.played
+            });
+            for command in &commands {
+                sender.send(*command);
+            }
+            done.store(true, Ordering::SeqCst);
+            consumer.join().unwrap()
+        });
+        assert_eq!(played, commands);
+    }
+
+    #[test]
+    fn a_full_queue_holds_the_sender_back_instead_of_dropping() {
+        // 4 slots hold 3 commands
+        let mut queue: VoiceQueue<u32, u8, 4> = VoiceQueue::new();
+        let (mut sender, mut receiver) = split_voice_queue(&mut queue);
+        let commands = song(10);
+        let sent = AtomicUsize::new(0);
+        let applied = AtomicUsize::new(0);
+
+        let played = thread::scope(|scope| {
+            scope.spawn(|| {
+                for command in &commands {
+                    sender.send(*command);
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+
+            // nothing is drained yet, so the sender stops once the queue is full and stays
+            // there however long it's left waiting
+            while sent.load(Ordering::SeqCst) < 3 {
+                thread::yield_now();
+            }
+            for _ in 0..1_000 {
+                thread::yield_now();
+                assert_eq!(sent.load(Ordering::SeqCst), 3);
+            }
+
+            let mut recorder = Recorder {
+                played: Vec::new(),
+                applied: &applied,
+            };
+            while applied.load(Ordering::SeqCst) < commands.len() {
+                if receiver.drain_into(&mut recorder) == 0 {
+                    thread::yield_now();
+                }
+            }
+            recorder.played
+        });
+        assert_eq!(sent.load(Ordering::SeqCst), commands.len());
+        assert_eq!(played, commands);
+    }
+
+    #[test]
+    fn flush_returns_once_everything_is_applied() {
+        let mut queue: VoiceQueue<u32, u8, 4> = VoiceQueue::new();
+        let (mut sender, mut receiver) = split_voice_queue(&mut queue);
+        let commands = song(100);
+        let applied = AtomicUsize::new(0);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut recorder = Recorder {
+                    played: Vec::new(),
+                    applied: &applied,
+                };
+                while !done.load(Ordering::SeqCst) {
+                    receiver.drain_into(&mut recorder);
+                    // a slow synth, so the flush has something to wait for
+                    thread::sleep(Duration::from_micros(200));
+                }
+            });
+
+            let mut sent = 0;
+            for chunk in commands.chunks(9) {
+                for command in chunk {
+                    sender.send(*command);
+                }
+                sent += chunk.len();
+                sender.flush();
+                // the receiver applies a command before taking it out of the queue
+                assert_eq!(applied.load(Ordering::SeqCst), sent);
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn draining_counts_the_applied_commands() {
+        let mut queue: VoiceQueue<u32, u8, 8> = VoiceQueue::new();
+        let (mut sender, mut receiver) = split_voice_queue(&mut queue);
+        let applied = AtomicUsize::new(0);
+        let mut recorder = Recorder {
+            played: Vec::new(),
+            applied: &applied,
+        };
+
+        assert_eq!(receiver.drain_into(&mut recorder), 0);
+        sender.note_on(60, 1);
+        sender.note_off(60);
+        assert_eq!(receiver.drain_into(&mut recorder), 2);
+        assert_eq!(receiver.drain_into(&mut recorder), 0);
+    }
+}
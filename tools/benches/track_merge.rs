@@ -0,0 +1,192 @@
+// =============================================================================================
+//                      TRACK MERGER AGAINST THE SCAN IT REPLACED
+// =============================================================================================
+
+// usage: cargo bench --bench track_merge
+//
+// merges the embedded song and generated songs with many busy tracks, once with the heap
+// based TrackMerger and once with the linear scan the player used before it, checks that
+// both give the same event order and prints the time per event
+
+#[path = "../../src/data.rs"]
+mod data;
+
+use std::{hint::black_box, time::Instant};
+
+use midi_tools::track_merger::TrackMerger;
+use midly::{
+    EventIter, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u4, u7, u15, u28},
+    parse,
+};
+
+const MAX_TRACKS: usize = 64;
+const EVENTS_PER_TRACK: usize = 2000;
+const MIN_BENCH_EVENTS: usize = 2_000_000;
+
+fn main() {
+    println!(
+        "{:<22} {:>8} {:>12} {:>12} {:>8}",
+        "song", "events", "scan ns/ev", "heap ns/ev", "speedup"
+    );
+    bench("MIDI_DATA", data::MIDI_DATA);
+    for tracks in [1, 4, 16, 32, 64] {
+        let song = generated_song(tracks);
+        bench(&format!("{tracks} busy tracks"), &song);
+    }
+}
+
+fn bench(name: &str, midi: &[u8]) {
+    let heap_order: Vec<(u64, usize)> = TrackMerger::<MAX_TRACKS>::new(song_tracks(midi))
+        .expect("the song fits")
+        .map(|event| (event.tick, event.track))
+        .collect();
+    let scan_order: Vec<(u64, usize)> = ScanMerger::new(song_tracks(midi))
+        .map(|(tick, track, _)| (tick, track))
+        .collect();
+    assert_eq!(heap_order, scan_order, "{name}: the mergers disagree");
+
+    let event_count = heap_order.len();
+    let rounds = MIN_BENCH_EVENTS.div_ceil(event_count.max(1));
+
+    let scan_nanos = time_rounds(rounds, || {
+        ScanMerger::new(song_tracks(midi)).map(black_box).count()
+    });
+    let heap_nanos = time_rounds(rounds, || {
+        TrackMerger::<MAX_TRACKS>::new(song_tracks(midi))
+            .expect("the song fits")
+            .map(black_box)
+            .count()
+    });
+
+    let per_event = |nanos: u128| nanos as f64 / (rounds * event_count) as f64;
+    println!(
+        "{name:<22} {event_count:>8} {:>12.1} {:>12.1} {:>7.2}x",
+        per_event(scan_nanos),
+        per_event(heap_nanos),
+        scan_nanos as f64 / heap_nanos as f64
+    );
+}
+
+fn time_rounds(rounds: usize, mut merge: impl FnMut() -> usize) -> u128 {
+    // one round to warm up the caches
+    black_box(merge());
+    let start = Instant::now();
+    for _ in 0..rounds {
+        black_box(merge());
+    }
+    start.elapsed().as_nanos()
+}
+
+fn song_tracks(midi: &[u8]) -> impl Iterator<Item = (usize, EventIter<'_>)> {
+    let (_, tracks) = parse(midi).expect("valid midi file");
+    tracks
+        .enumerate()
+        .filter_map(|(i, track)| Some((i, track.ok()?)))
+}
+
+/// notes on every track with short and often zero deltas, so many events share a tick
+fn generated_song(track_count: usize) -> Vec<u8> {
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(96)),
+    ));
+    let mut seed: u32 = 0x1234_5678;
+    for track in 0..track_count {
+        let channel = u4::new((track % 16) as u8);
+        let mut events = Vec::with_capacity(EVENTS_PER_TRACK + 1);
+        for i in 0..EVENTS_PER_TRACK {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let delta = (seed >> 24) % 4 * 12;
+            let key = u7::new(48 + (seed >> 8) as u8 % 24);
+            let message = if i % 2 == 0 {
+                MidiMessage::NoteOn {
+                    key,
+                    vel: u7::new(64),
+                }
+            } else {
+                MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                }
+            };
+            events.push(TrackEvent {
+                delta: u28::new(delta),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+        }
+        events.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        smf.tracks.push(events);
+    }
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)
+        .expect("writing to a Vec can't fail");
+    bytes
+}
+
+// ---------------- the scan the player used before the heap ----------------
+
+// every event looks through all tracks for the smallest pending delta
+// and then subtracts the picked delta from every other track
+
+type Pending<'a> = Option<(u32, TrackEventKind<'a>)>;
+
+struct ScanMerger<'a> {
+    tracks: Vec<(usize, EventIter<'a>, Pending<'a>)>,
+    tick: u64,
+}
+
+impl<'a> ScanMerger<'a> {
+    fn new(tracks: impl Iterator<Item = (usize, EventIter<'a>)>) -> Self {
+        let tracks = tracks
+            .map(|(track, mut events)| {
+                let pending = Self::read_pending(&mut events);
+                (track, events, pending)
+            })
+            .collect();
+        ScanMerger { tracks, tick: 0 }
+    }
+
+    fn read_pending(events: &mut EventIter<'a>) -> Pending<'a> {
+        match events.next() {
+            Some(Ok(event)) => Some((event.delta.as_int(), event.kind)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for ScanMerger<'a> {
+    type Item = (u64, usize, TrackEventKind<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut first: Option<(usize, u32)> = None;
+        for (i, (_, _, pending)) in self.tracks.iter().enumerate() {
+            if let Some((delta, _)) = *pending {
+                if delta == 0 {
+                    first = Some((i, 0));
+                    break;
+                }
+                if first.is_none_or(|(_, first_delta)| delta < first_delta) {
+                    first = Some((i, delta));
+                }
+            }
+        }
+        let (first, delta) = first?;
+
+        if delta != 0 {
+            self.tracks
+                .iter_mut()
+                .filter_map(|(_, _, pending)| pending.as_mut())
+                .for_each(|(pending_delta, _)| *pending_delta -= delta);
+        }
+        self.tick += delta as u64;
+        let (track, events, pending) = &mut self.tracks[first];
+        let (_, kind) = pending.take()?;
+        *pending = Self::read_pending(events);
+        Some((self.tick, *track, kind))
+    }
+}
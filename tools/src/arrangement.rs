@@ -0,0 +1,246 @@
+// =============================================================================================
+//                       ARRANGEMENT REDUCER FOR A LIMITED NUMBER OF BUZZERS
+// =============================================================================================
+
+// rewrites a song so that it never needs more than the given number of voices,
+// instead of the firmware dropping whatever note happens to arrive when the buzzers run out
+//
+// when a note would go over the limit, one note of the chord has to go:
+//   - the highest note is the melody and the lowest is the bass, they are kept
+//   - an inner voice that doubles another note in a different octave goes first
+//   - then the new inner note is not started
+//   - then the oldest sounding inner note is cut short
+// with a single voice only the melody is kept
+
+use std::collections::HashSet;
+
+use midly::{
+    MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    num::{u4, u7, u28},
+};
+
+use crate::song_analysis::{note_off_key, note_on_key};
+use crate::song_compiler::{CompileError, TimedEvent, Timeline};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reduction {
+    pub timeline: Timeline,
+    pub dropped_notes: usize,
+    pub shortened_notes: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SoundingNote {
+    channel: u4,
+    key: u7,
+}
+
+impl SoundingNote {
+    fn same_note(&self, channel: u4, key: u7) -> bool {
+        self.channel == channel && self.key == key
+    }
+}
+
+/// the song with notes removed or shortened so at most max_voices sound at once
+pub fn reduce_voices(timeline: &Timeline, max_voices: usize) -> Reduction {
+    // oldest first
+    let mut sounding: Vec<SoundingNote> = Vec::new();
+    // notes that were not started or were cut, their own note off has to go too
+    let mut silenced: HashSet<(u4, u7)> = HashSet::new();
+
+    let mut events = Vec::with_capacity(timeline.events.len());
+    let mut dropped_notes = 0;
+    let mut shortened_notes = 0;
+
+    for event in &timeline.events {
+        if let Some(key) = note_on_key(event) {
+            // playing the same note again reuses its buzzer
+            if sounding
+                .iter()
+                .any(|note| note.same_note(event.channel, key))
+            {
+                events.push(*event);
+                continue;
+            }
+            silenced.remove(&(event.channel, key));
+
+            let new_note = SoundingNote {
+                channel: event.channel,
+                key,
+            };
+            if sounding.len() < max_voices {
+                sounding.push(new_note);
+                events.push(*event);
+                continue;
+            }
+
+            match choose_victim(&sounding, key) {
+                Some(victim) => {
+                    let note = sounding.remove(victim);
+                    // in the track of the new note, so the cut stays in front of it when the
+                    // file is read back and the events on the tick are ordered by track
+                    events.push(TimedEvent {
+                        track: event.track,
+                        channel: note.channel,
+                        message: MidiMessage::NoteOff {
+                            key: note.key,
+                            vel: u7::new(0),
+                        },
+                        ..*event
+                    });
+                    silenced.insert((note.channel, note.key));
+                    shortened_notes += 1;
+
+                    sounding.push(new_note);
+                    events.push(*event);
+                }
+                None => {
+                    silenced.insert((event.channel, key));
+                    dropped_notes += 1;
+                }
+            }
+        } else if let Some(key) = note_off_key(event) {
+            if let Some(index) = sounding
+                .iter()
+                .position(|note| note.same_note(event.channel, key))
+            {
+                sounding.remove(index);
+                events.push(*event);
+            } else if !silenced.remove(&(event.channel, key)) {
+                events.push(*event);
+            }
+        } else {
+            events.push(*event);
+        }
+    }
+
+    Reduction {
+        timeline: Timeline {
+            events,
+            ..timeline.clone()
+        },
+        dropped_notes,
+        shortened_notes,
+    }
+}
+
+/// the index of the sounding note to cut for the new key, None if the new note should be dropped
+fn choose_victim(sounding: &[SoundingNote], new_key: u7) -> Option<usize> {
+    if sounding.is_empty() {
+        return None;
+    }
+
+    // None stands for the new note
+    let mut candidates: Vec<(Option<usize>, u7)> = sounding
+        .iter()
+        .enumerate()
+        .map(|(index, note)| (Some(index), note.key))
+        .collect();
+    candidates.push((None, new_key));
+
+    let highest = candidates.iter().map(|(_, key)| *key).max()?;
+    let lowest = candidates.iter().map(|(_, key)| *key).min()?;
+
+    // melody over bass when there is no room for both
+    let inner: Vec<(Option<usize>, u7)> = candidates
+        .iter()
+        .copied()
+        .filter(|(_, key)| *key != highest && *key != lowest)
+        .collect();
+    if inner.is_empty() {
+        return candidates
+            .iter()
+            .find(|(_, key)| *key == lowest)
+            .and_then(|(index, _)| *index);
+    }
+
+    let is_doubled = |(index, key): &(Option<usize>, u7)| {
+        candidates.iter().any(|(other_index, other_key)| {
+            other_index != index && other_key.as_int() % 12 == key.as_int() % 12
+        })
+    };
+
+    let doubled: Vec<(Option<usize>, u7)> = inner.iter().copied().filter(is_doubled).collect();
+    let pick_from = if doubled.is_empty() { inner } else { doubled };
+
+    // the new note is the last candidate, the oldest sounding note is the first
+    if pick_from.iter().any(|(index, _)| index.is_none()) {
+        None
+    } else {
+        pick_from[0].0
+    }
+}
+
+// =============================================================================================
+//                                 WRITING THE RESULT AS MIDI
+// =============================================================================================
+
+/// the original midi file with its voice events replaced by the reduced ones,
+/// meta events such as tempo and track names are kept where they were
+pub fn write_reduced_midi(midi: &[u8], reduced: &Timeline) -> Result<Vec<u8>, CompileError> {
+    let smf = Smf::parse(midi)?;
+    if let Timing::Timecode(_, _) = smf.header.timing {
+        return Err(CompileError::TimecodeTiming);
+    }
+
+    let mut tracks = Vec::with_capacity(smf.tracks.len());
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut timed_kinds: Vec<(u64, TrackEventKind)> = Vec::new();
+
+        let track_start = reduced.track_starts[track_index];
+        let mut tick = track_start;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Midi { .. } => {}
+                // end of track is added back after the last event
+                TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {}
+                kind => timed_kinds.push((tick, kind)),
+            }
+        }
+        let end_tick = tick;
+
+        timed_kinds.extend(
+            reduced
+                .events
+                .iter()
+                .filter(|event| event.track == track_index)
+                .map(|event| {
+                    (
+                        event.tick,
+                        TrackEventKind::Midi {
+                            channel: event.channel,
+                            message: event.message,
+                        },
+                    )
+                }),
+        );
+        // stable, meta events stay in front of the notes on the same tick
+        timed_kinds.sort_by_key(|(tick, _)| *tick);
+        timed_kinds.push((
+            end_tick.max(timed_kinds.last().map_or(track_start, |(tick, _)| *tick)),
+            TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        ));
+
+        let mut previous_tick = track_start;
+        let mut events = Vec::with_capacity(timed_kinds.len());
+        for (tick, kind) in timed_kinds {
+            let delta = u32::try_from(tick - previous_tick)
+                .ok()
+                .and_then(u28::try_from)
+                .ok_or(CompileError::PauseTooLong { track: track_index })?;
+            events.push(TrackEvent { delta, kind });
+            previous_tick = tick;
+        }
+        tracks.push(events);
+    }
+
+    let mut reduced_smf = Smf::new(smf.header);
+    reduced_smf.tracks = tracks;
+
+    let mut bytes = Vec::new();
+    reduced_smf
+        .write_std(&mut bytes)
+        .expect("writing to a vec can't fail");
+    Ok(bytes)
+}
@@ -0,0 +1,152 @@
+// =============================================================================================
+//                         MIDI FILES TO RUST BYTE ARRAYS FOR data.rs
+// =============================================================================================
+
+// usage: midi2rs [--name CONST_NAME] <song.mid>... > src/data.rs
+//
+// every file becomes a `pub const` byte array named after the file,
+// with the song details as comments above it
+// --name overrides the name of the first song, the firmware plays MIDI_DATA
+
+use std::{env, fmt::Write as _, fs, path::Path, process::ExitCode};
+
+use midi_tools::song_analysis::{SongSummary, summarize};
+
+// MAX_TRACKS of the firmware, the precompiled stream has no limit
+const MAX_TRACKS: usize = 32;
+
+const BYTES_PER_LINE: usize = 16;
+
+const SEPARATOR: &str = "// =============================================================================================";
+
+fn main() -> ExitCode {
+    let mut paths: Vec<String> = env::args().skip(1).collect();
+    let mut first_name = None;
+    if let Some(flag) = paths.iter().position(|arg| arg == "--name") {
+        if flag + 1 >= paths.len() {
+            eprintln!("midi2rs: --name needs a constant name");
+            return ExitCode::FAILURE;
+        }
+        first_name = Some(paths.remove(flag + 1));
+        paths.remove(flag);
+    }
+    if paths.is_empty() || paths.iter().any(|arg| arg == "-h" || arg == "--help") {
+        eprintln!("usage: midi2rs [--name CONST_NAME] <song.mid>... > src/data.rs");
+        return ExitCode::FAILURE;
+    }
+
+    let names: Vec<String> = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| match &first_name {
+            Some(name) if i == 0 => name.clone(),
+            _ => const_name(Path::new(path)),
+        })
+        .collect();
+    // two consts with the same name don't compile, the same file twice or `a-b.mid` and
+    // `a_b.mid` would give them
+    for (i, name) in names.iter().enumerate() {
+        if let Some(first) = names[..i].iter().position(|other| other == name) {
+            eprintln!(
+                "midi2rs: {} and {} both become {name}, rename one of them",
+                paths[first], paths[i]
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut output = String::new();
+    writeln!(output, "{SEPARATOR}").unwrap();
+    writeln!(output, "//                                      SONG HEX").unwrap();
+    writeln!(output, "{SEPARATOR}").unwrap();
+
+    for (path, name) in paths.iter().zip(&names) {
+        let path = Path::new(path);
+        match song_to_rust(path, name) {
+            Ok(song) => {
+                output.push('\n');
+                output.push_str(&song);
+            }
+            Err(err) => {
+                eprintln!("midi2rs: {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    print!("{output}");
+    ExitCode::SUCCESS
+}
+
+fn song_to_rust(path: &Path, name: &str) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|err| format!("could not read the file: {err}"))?;
+    let summary = summarize(&bytes).map_err(|err| err.to_string())?;
+
+    if summary.track_names.len() > MAX_TRACKS {
+        eprintln!(
+            "midi2rs: {}: the song has {} tracks, it only plays with PLAY_PRECOMPILED \
+             unless MAX_TRACKS is raised to match",
+            path.display(),
+            summary.track_names.len()
+        );
+    }
+
+    let mut song = String::new();
+    write_summary(&mut song, path, &summary);
+    writeln!(song, "pub const {name}: &[u8] = &[").unwrap();
+    for line in bytes.chunks(BYTES_PER_LINE) {
+        let hex: Vec<String> = line.iter().map(|byte| format!("0x{byte:02x},")).collect();
+        writeln!(song, "    {}", hex.join(" ")).unwrap();
+    }
+    writeln!(song, "];").unwrap();
+    Ok(song)
+}
+
+fn write_summary(song: &mut String, path: &Path, summary: &SongSummary) {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let seconds = summary.length_micros as f64 / 1_000_000.0;
+
+    writeln!(song, "// file: {file_name}").unwrap();
+    writeln!(
+        song,
+        "// format: {:?}, {} ticks per quarter note",
+        summary.format, summary.ticks_per_quarter
+    )
+    .unwrap();
+    writeln!(
+        song,
+        "// length: {}:{:04.1}",
+        (seconds / 60.0) as u32,
+        seconds % 60.0
+    )
+    .unwrap();
+    writeln!(
+        song,
+        "// notes: {}, max polyphony: {}",
+        summary.note_count, summary.max_polyphony
+    )
+    .unwrap();
+    writeln!(song, "// tracks:").unwrap();
+    for (i, name) in summary.track_names.iter().enumerate() {
+        writeln!(song, "//   {i}: {}", name.as_deref().unwrap_or("(unnamed)")).unwrap();
+    }
+}
+
+/// `songs/My Song-2.mid` becomes `MY_SONG_2`
+fn const_name(path: &Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert_str(0, "SONG_");
+    }
+    name
+}
@@ -0,0 +1,175 @@
+// =============================================================================================
+//                     POLYPHONY REPORT AND ARRANGEMENT REDUCER FOR THE BUZZERS
+// =============================================================================================
+
+// usage: midi_reduce <song.mid> [--voices N] [--midi out.mid] [--stream out.song]
+//
+// prints how many notes the song plays at once, per channel and over time,
+// with --midi or --stream the song is rewritten to fit the given number of buzzers
+
+use std::{env, fs, process::ExitCode};
+
+use midi_tools::{
+    arrangement::{reduce_voices, write_reduced_midi},
+    song_analysis::{PolyphonySpan, channel_polyphony, max_polyphony, polyphony_over_time},
+    song_compiler::{Timeline, compile_timeline, song_timeline},
+};
+
+const DEFAULT_VOICES: usize = 8; // 4 high speed + 4 low speed LEDC channels
+
+// the report lists only the first passages that need too many voices
+const MAX_PASSAGES: usize = 20;
+
+const USAGE: &str =
+    "usage: midi_reduce <song.mid> [--voices N] [--midi out.mid] [--stream out.song]";
+
+struct Options {
+    input: String,
+    voices: usize,
+    midi_output: Option<String>,
+    stream_output: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("midi_reduce: {err}");
+            }
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("midi_reduce: {}: {err}", options.input);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut voices = DEFAULT_VOICES;
+    let mut midi_output = None;
+    let mut stream_output = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or(format!("{flag} needs a value"));
+        match arg.as_str() {
+            "--voices" => {
+                voices = value("--voices")?
+                    .parse()
+                    .ok()
+                    .filter(|voices| *voices > 0)
+                    .ok_or("--voices needs a number above 0")?
+            }
+            "--midi" => midi_output = Some(value("--midi")?),
+            "--stream" => stream_output = Some(value("--stream")?),
+            "-h" | "--help" => return Err(String::new()),
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg),
+            _ => return Err(format!("unexpected argument {arg}")),
+        }
+    }
+
+    Ok(Options {
+        input: input.ok_or("no midi file given")?,
+        voices,
+        midi_output,
+        stream_output,
+    })
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let midi = fs::read(&options.input).map_err(|err| format!("could not read the file: {err}"))?;
+    let timeline = song_timeline(&midi).map_err(|err| err.to_string())?;
+
+    println!("original song");
+    print_report(&timeline, options.voices);
+
+    if options.midi_output.is_none() && options.stream_output.is_none() {
+        return Ok(());
+    }
+
+    let reduction = reduce_voices(&timeline, options.voices);
+    println!();
+    println!("reduced to {} voices", options.voices);
+    println!("  notes left out: {}", reduction.dropped_notes);
+    println!("  notes cut short: {}", reduction.shortened_notes);
+    print_report(&reduction.timeline, options.voices);
+
+    if let Some(path) = &options.midi_output {
+        let bytes =
+            write_reduced_midi(&midi, &reduction.timeline).map_err(|err| err.to_string())?;
+        fs::write(path, bytes).map_err(|err| format!("could not write {path}: {err}"))?;
+        println!("wrote {path}");
+    }
+    if let Some(path) = &options.stream_output {
+        let bytes = compile_timeline(&reduction.timeline).map_err(|err| err.to_string())?;
+        fs::write(path, bytes).map_err(|err| format!("could not write {path}: {err}"))?;
+        println!("wrote {path}");
+    }
+    Ok(())
+}
+
+fn print_report(timeline: &Timeline, voices: usize) {
+    println!("  peak polyphony: {}", max_polyphony(&timeline.events));
+
+    for (channel, peak) in channel_polyphony(&timeline.events).iter().enumerate() {
+        if *peak > 0 {
+            println!("    channel {channel:>2}: {peak}");
+        }
+    }
+
+    let spans = polyphony_over_time(&timeline.events, timeline.length_micros);
+    let length = timeline.length_micros.max(1) as f64;
+
+    // time spent at every polyphony, then the passages that need more buzzers than there are
+    let most_voices = spans.iter().map(|span| span.voices).max().unwrap_or(0);
+    println!("  time with n notes sounding:");
+    for count in 0..=most_voices {
+        let micros: u64 = spans
+            .iter()
+            .filter(|span| span.voices == count)
+            .map(|span| span.end_micros - span.start_micros)
+            .sum();
+        if micros > 0 {
+            println!("    {count:>2}: {:5.1} %", micros as f64 * 100.0 / length);
+        }
+    }
+
+    // touching spans over the limit are one passage, with the most notes in it
+    let mut passages: Vec<PolyphonySpan> = Vec::new();
+    for span in spans.iter().filter(|span| span.voices > voices) {
+        match passages.last_mut() {
+            Some(passage) if passage.end_micros == span.start_micros => {
+                passage.end_micros = span.end_micros;
+                passage.voices = passage.voices.max(span.voices);
+            }
+            _ => passages.push(*span),
+        }
+    }
+
+    if !passages.is_empty() {
+        println!("  passages over {voices} voices: {}", passages.len());
+    }
+    for passage in passages.iter().take(MAX_PASSAGES) {
+        println!(
+            "    {} - {}: up to {} notes",
+            format_time(passage.start_micros),
+            format_time(passage.end_micros),
+            passage.voices
+        );
+    }
+    if passages.len() > MAX_PASSAGES {
+        println!("    ...");
+    }
+}
+
+fn format_time(micros: u64) -> String {
+    let seconds = micros as f64 / 1_000_000.0;
+    format!("{}:{:06.3}", (seconds / 60.0) as u32, seconds % 60.0)
+}
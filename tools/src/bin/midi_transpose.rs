@@ -0,0 +1,63 @@
+// =============================================================================================
+//                      TRANSPOSE SUGGESTION FOR THE PLAYABLE BUZZER RANGE
+// =============================================================================================
+
+// usage: midi_transpose <song.mid> [--max N]
+//
+// counts the notes that fall outside the playable range of their instrument for every
+// transpose up to N semitones, and suggests the one to put into SONG_TRANSPOSE in main.rs
+
+use std::{env, fs, process::ExitCode};
+
+use midi_tools::{
+    song_analysis::{best_transpose, transpose_fit},
+    song_compiler::song_timeline,
+};
+
+const DEFAULT_MAX_SEMITONES: i8 = 24;
+
+const USAGE: &str = "usage: midi_transpose <song.mid> [--max N]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, max_semitones) = match args.as_slice() {
+        [path] => (path, DEFAULT_MAX_SEMITONES),
+        [path, flag, max] if flag == "--max" => match max.parse::<i8>() {
+            Ok(max) if max >= 0 => (path, max),
+            _ => {
+                eprintln!("midi_transpose: --max needs a number from 0 to 127\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let timeline = match fs::read(path)
+        .map_err(|err| format!("could not read the file: {err}"))
+        .and_then(|midi| song_timeline(&midi).map_err(|err| err.to_string()))
+    {
+        Ok(timeline) => timeline,
+        Err(err) => {
+            eprintln!("midi_transpose: {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("semitones  notes outside the range");
+    for semitones in -max_semitones..=max_semitones {
+        let fit = transpose_fit(&timeline.events, semitones);
+        println!("{:>+9}  {}", fit.semitones, fit.folded_notes);
+    }
+
+    let original = transpose_fit(&timeline.events, 0);
+    let best = best_transpose(&timeline.events, max_semitones);
+    println!();
+    println!(
+        "suggested SONG_TRANSPOSE: {} ({} notes outside the range, {} without transposing)",
+        best.semitones, best.folded_notes, original.folded_notes
+    );
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,127 @@
+// =============================================================================================
+//                         EXPORTS AND CHECKS INSTRUMENT PROFILE BANKS
+// =============================================================================================
+
+// `export` writes the built in INSTRUMENTS table as a bank to start tuning from,
+// `check` reads a bank like the firmware does and tells what it would change
+
+use std::{env, fs, process::ExitCode};
+
+use midi_tools::{
+    profile_bank::{BankReader, write_built_in_bank},
+    settings::{MAX_PROFILE_TWEAKS, Settings},
+    sound_profiles::{INSTRUMENTS, MAX_PERIOD_MICROS, MIN_PERIOD_MICROS, PROGRAM_NAMES},
+};
+
+const USAGE: &str = "usage: profile_bank export > PROFILES.TXT
+       profile_bank check PROFILES.TXT";
+
+// same as the line buffer of the firmware
+const LINE_LEN: usize = 128;
+
+// keys a profile should be able to play without moving notes by octaves
+const MIN_PLAYABLE_KEYS: u8 = 24;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["export"] => {
+            print!("{}", export());
+            ExitCode::SUCCESS
+        }
+        ["check", path] => check(path),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn export() -> String {
+    let mut out = String::new();
+    write_built_in_bank(&mut out).expect("writing to a string can't fail");
+    out
+}
+
+fn check(path: &str) -> ExitCode {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("profile_bank: {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut entries = Vec::new();
+    let mut reader = BankReader::<LINE_LEN>::new();
+    let result = reader
+        .feed(&bytes, &mut |entry| entries.push(entry))
+        .and_then(|()| reader.finish(&mut |entry| entries.push(entry)));
+    if let Err(err) = result {
+        eprintln!("profile_bank: {path}: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut settings = Settings::default();
+    let mut seen = [false; 128];
+    let mut left_out = Vec::new();
+    for entry in &entries {
+        let program = entry.program as usize;
+        let name = PROGRAM_NAMES[program];
+        if seen[program] {
+            println!("warning: {program} {name} is in the bank more than once, the last one wins");
+        }
+        seen[program] = true;
+
+        // like on the firmware the key range and the vibrato stay as they are
+        let tweak = entry.apply(settings.tweak(entry.program));
+        let profile = tweak.profile();
+        let playable = profile.highest_key.saturating_sub(profile.lowest_key);
+        if playable < MIN_PLAYABLE_KEYS {
+            println!(
+                "warning: {program} {name} only plays keys {} - {}, periods outside \
+                 {MIN_PERIOD_MICROS} - {MAX_PERIOD_MICROS} us can't be heard",
+                profile.lowest_key, profile.highest_key
+            );
+        }
+        if settings.set_tweak(tweak).is_err() {
+            left_out.push(program);
+        }
+    }
+
+    let changed = settings.profile_tweaks.len();
+    println!(
+        "{} profiles, {} differ from the built in sounds",
+        entries.len(),
+        changed + left_out.len()
+    );
+    for tweak in &settings.profile_tweaks {
+        let program = tweak.program as usize;
+        let built_in = INSTRUMENTS[program];
+        println!(
+            "  {program} {}: wait time {} -> {}, change per key {} -> {}, duration {} -> {}",
+            PROGRAM_NAMES[program],
+            built_in.wait_time,
+            tweak.wait_time,
+            built_in.wait_change_per_key,
+            tweak.wait_change_per_key,
+            duration_text(built_in.duration),
+            duration_text(tweak.duration),
+        );
+    }
+    if !left_out.is_empty() {
+        println!(
+            "error: only {MAX_PROFILE_TWEAKS} programs can have their own sound, \
+             these are left out: {left_out:?}"
+        );
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn duration_text(duration: Option<i32>) -> String {
+    match duration {
+        Some(duration) => format!("{duration} us"),
+        None => String::from("held"),
+    }
+}
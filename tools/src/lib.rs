@@ -0,0 +1,82 @@
+// =============================================================================================
+//                           HOST SIDE TOOLS FOR THE MIDI SYNTHESIZER
+// =============================================================================================
+
+// shared with the firmware so both sides agree on the formats and on the played notes
+#[path = "../../src/song_stream.rs"]
+pub mod song_stream;
+
+#[path = "../../src/song_check.rs"]
+pub mod song_check;
+
+#[path = "../../src/sound_profiles.rs"]
+pub mod sound_profiles;
+
+#[path = "../../src/vibrato.rs"]
+pub mod vibrato;
+
+#[path = "../../src/glide.rs"]
+pub mod glide;
+
+#[path = "../../src/tone_clock.rs"]
+pub mod tone_clock;
+
+#[path = "../../src/voice_queue.rs"]
+pub mod voice_queue;
+
+#[path = "../../src/ledc_timing.rs"]
+pub mod ledc_timing;
+
+#[path = "../../src/arpeggio.rs"]
+pub mod arpeggio;
+
+#[path = "../../src/mixer.rs"]
+pub mod mixer;
+
+#[path = "../../src/channel_mode.rs"]
+pub mod channel_mode;
+
+#[path = "../../src/transpose.rs"]
+pub mod transpose;
+
+#[path = "../../src/playback_clock.rs"]
+pub mod playback_clock;
+
+#[path = "../../src/tempo_map.rs"]
+pub mod tempo_map;
+
+#[path = "../../src/track_merger.rs"]
+pub mod track_merger;
+
+#[path = "../../src/block_device.rs"]
+pub mod block_device;
+
+#[path = "../../src/fat.rs"]
+pub mod fat;
+
+#[path = "../../src/song_library.rs"]
+pub mod song_library;
+
+#[path = "../../src/midi_reader.rs"]
+pub mod midi_reader;
+
+#[path = "../../src/settings.rs"]
+pub mod settings;
+
+#[path = "../../src/profile_bank.rs"]
+pub mod profile_bank;
+
+#[path = "../../src/profile_tuner.rs"]
+pub mod profile_tuner;
+
+pub mod song_compiler;
+
+pub mod song_analysis;
+
+pub mod arrangement;
+
+pub mod ram_disk;
+
+pub mod ram_flash;
+
+pub mod test_midi;
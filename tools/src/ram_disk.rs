@@ -0,0 +1,40 @@
+// =============================================================================================
+//                             DISK IMAGES AS BLOCK DEVICES
+// =============================================================================================
+
+// an SD card image in memory, so the song library can be tried on the host
+// with an image made by mkfs.fat or copied off a card with dd
+
+use crate::block_device::{BLOCK_LEN, Block, BlockDevice};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange(pub u32);
+
+pub struct RamDisk<'a> {
+    image: &'a [u8],
+}
+
+impl<'a> RamDisk<'a> {
+    pub const fn new(image: &'a [u8]) -> Self {
+        RamDisk { image }
+    }
+
+    pub const fn block_count(&self) -> u32 {
+        self.image.len().div_ceil(BLOCK_LEN) as u32
+    }
+}
+
+impl BlockDevice for RamDisk<'_> {
+    type Error = OutOfRange;
+
+    fn read_block(&mut self, index: u32, block: &mut Block) -> Result<(), Self::Error> {
+        let start = index as usize * BLOCK_LEN;
+        let bytes = self.image.get(start..).filter(|bytes| !bytes.is_empty());
+        let bytes = bytes.ok_or(OutOfRange(index))?;
+        // an image cut in the middle of a block reads as zeros after the end
+        let len = bytes.len().min(BLOCK_LEN);
+        block[..len].copy_from_slice(&bytes[..len]);
+        block[len..].fill(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+// =============================================================================================
+//                               NOR FLASH IN MEMORY
+// =============================================================================================
+
+// behaves like the ESP32 flash for the settings store: an erase sets a whole sector to 0xFF
+// and a write can only clear bits, so the saving can be tried on the host, including a
+// reset in the middle of a write
+
+use crate::settings::SettingsFlash;
+
+pub const RAM_SECTOR_LEN: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    OutOfRange {
+        offset: u32,
+        len: usize,
+    },
+    Unaligned {
+        offset: u32,
+        len: usize,
+    },
+    /// the write was cut short by the injected power loss
+    PowerLoss,
+}
+
+pub struct RamFlash {
+    bytes: Vec<u8>,
+    erase_counts: Vec<u32>,
+    writes_until_power_loss: Option<usize>,
+}
+
+impl RamFlash {
+    /// an erased flash of whole sectors
+    pub fn new(sectors: usize) -> Self {
+        RamFlash {
+            bytes: vec![0xFF; sectors * RAM_SECTOR_LEN as usize],
+            erase_counts: vec![0; sectors],
+            writes_until_power_loss: None,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// for corrupting the flash by hand
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    /// how many times every sector has been erased
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// the write after this many writes only writes its first half and fails
+    pub fn lose_power_after(&mut self, writes: usize) {
+        self.writes_until_power_loss = Some(writes);
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<usize, FlashError> {
+        if !offset.is_multiple_of(4) || !len.is_multiple_of(4) {
+            return Err(FlashError::Unaligned { offset, len });
+        }
+        let start = offset as usize;
+        if start + len > self.bytes.len() {
+            return Err(FlashError::OutOfRange { offset, len });
+        }
+        Ok(start)
+    }
+}
+
+impl SettingsFlash for RamFlash {
+    type Error = FlashError;
+
+    const SECTOR_LEN: u32 = RAM_SECTOR_LEN;
+
+    fn size(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let start = self.check(offset, buffer.len())?;
+        buffer.copy_from_slice(&self.bytes[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let start = self.check(offset, data.len())?;
+        let (data, result) = match &mut self.writes_until_power_loss {
+            Some(0) => {
+                self.writes_until_power_loss = None;
+                (&data[..data.len() / 2], Err(FlashError::PowerLoss))
+            }
+            Some(writes) => {
+                *writes -= 1;
+                (data, Ok(()))
+            }
+            None => (data, Ok(())),
+        };
+        for (byte, new) in self.bytes[start..].iter_mut().zip(data) {
+            *byte &= new;
+        }
+        result
+    }
+
+    fn erase_sector(&mut self, offset: u32) -> Result<(), Self::Error> {
+        if !offset.is_multiple_of(RAM_SECTOR_LEN) {
+            return Err(FlashError::Unaligned {
+                offset,
+                len: RAM_SECTOR_LEN as usize,
+            });
+        }
+        let start = self.check(offset, RAM_SECTOR_LEN as usize)?;
+        self.bytes[start..start + RAM_SECTOR_LEN as usize].fill(0xFF);
+        self.erase_counts[start / RAM_SECTOR_LEN as usize] += 1;
+        Ok(())
+    }
+}
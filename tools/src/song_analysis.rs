@@ -0,0 +1,200 @@
+// =============================================================================================
+//                              SONG STATISTICS FOR THE HOST TOOLS
+// =============================================================================================
+
+use std::collections::HashSet;
+
+use midly::{Format, MetaMessage, MidiMessage, Smf, TrackEventKind, num::u4, num::u7};
+
+use crate::song_compiler::{CompileError, TimedEvent, song_timeline};
+use crate::sound_profiles::{DEFAULT_SOUND, INSTRUMENTS, SoundProfile};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongSummary {
+    pub format: Format,
+    pub ticks_per_quarter: u16,
+    pub track_names: Vec<Option<String>>,
+    pub length_micros: u64,
+    pub note_count: usize,
+    pub max_polyphony: usize,
+}
+
+pub fn summarize(midi: &[u8]) -> Result<SongSummary, CompileError> {
+    let timeline = song_timeline(midi)?;
+    let smf = Smf::parse(midi)?;
+
+    let track_names = smf
+        .tracks
+        .iter()
+        .map(|track| {
+            track.iter().find_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    Some(String::from_utf8_lossy(name).trim().to_string())
+                }
+                _ => None,
+            })
+        })
+        .collect();
+
+    Ok(SongSummary {
+        format: smf.header.format,
+        ticks_per_quarter: timeline.ticks_per_quarter,
+        track_names,
+        length_micros: timeline.length_micros,
+        note_count: timeline
+            .events
+            .iter()
+            .filter(|event| note_on_key(event).is_some())
+            .count(),
+        max_polyphony: max_polyphony(&timeline.events),
+    })
+}
+
+/// the key of a note on, a note on with zero velocity is a note off
+pub fn note_on_key(event: &TimedEvent) -> Option<u7> {
+    match event.message {
+        MidiMessage::NoteOn { key, vel } if vel > 0 => Some(key),
+        _ => None,
+    }
+}
+
+/// the key of a note off, including note ons with zero velocity
+pub fn note_off_key(event: &TimedEvent) -> Option<u7> {
+    match event.message {
+        MidiMessage::NoteOff { key, .. } => Some(key),
+        MidiMessage::NoteOn { key, vel } if vel == 0 => Some(key),
+        _ => None,
+    }
+}
+
+/// the most notes sounding at once, counted the same way the firmware takes buzzers:
+/// events are handled in playing order and a repeated key reuses its buzzer
+pub fn max_polyphony(events: &[TimedEvent]) -> usize {
+    let mut sounding: HashSet<(u4, u7)> = HashSet::new();
+    let mut max_polyphony = 0;
+
+    for event in events {
+        if let Some(key) = note_on_key(event) {
+            sounding.insert((event.channel, key));
+            max_polyphony = max_polyphony.max(sounding.len());
+        } else if let Some(key) = note_off_key(event) {
+            sounding.remove(&(event.channel, key));
+        }
+    }
+    max_polyphony
+}
+
+/// the most notes sounding at once on every channel
+pub fn channel_polyphony(events: &[TimedEvent]) -> [usize; 16] {
+    let mut sounding: [HashSet<u7>; 16] = Default::default();
+    let mut max_polyphony = [0; 16];
+
+    for event in events {
+        let channel = event.channel.as_int() as usize;
+        if let Some(key) = note_on_key(event) {
+            sounding[channel].insert(key);
+            max_polyphony[channel] = max_polyphony[channel].max(sounding[channel].len());
+        } else if let Some(key) = note_off_key(event) {
+            sounding[channel].remove(&key);
+        }
+    }
+    max_polyphony
+}
+
+/// a stretch of the song where the same number of notes is sounding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolyphonySpan {
+    pub start_micros: u64,
+    pub end_micros: u64,
+    pub voices: usize,
+}
+
+/// the number of sounding notes over the whole song, as back to back spans
+pub fn polyphony_over_time(events: &[TimedEvent], length_micros: u64) -> Vec<PolyphonySpan> {
+    let mut sounding: HashSet<(u4, u7)> = HashSet::new();
+    let mut spans: Vec<PolyphonySpan> = Vec::new();
+    let mut span = PolyphonySpan {
+        start_micros: 0,
+        end_micros: 0,
+        voices: 0,
+    };
+
+    for event in events {
+        if let Some(key) = note_on_key(event) {
+            sounding.insert((event.channel, key));
+        } else if let Some(key) = note_off_key(event) {
+            sounding.remove(&(event.channel, key));
+        } else {
+            continue;
+        }
+
+        if sounding.len() != span.voices {
+            if event.micros > span.start_micros {
+                span.end_micros = event.micros;
+                spans.push(span);
+                span.start_micros = event.micros;
+            }
+            span.voices = sounding.len();
+        }
+    }
+
+    span.end_micros = length_micros.max(span.start_micros);
+    spans.push(span);
+
+    // simultaneous changes can leave neighbours with the same count
+    spans.dedup_by(|next, previous| {
+        let same = next.voices == previous.voices;
+        if same {
+            previous.end_micros = next.end_micros;
+        }
+        same
+    });
+    spans
+}
+
+// =============================================================================================
+//                             TRANSPOSE FOR THE PLAYABLE RANGES
+// =============================================================================================
+
+/// how well a global transpose fits the song into the playable ranges of its instruments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransposeFit {
+    pub semitones: i8,
+    pub folded_notes: usize, // notes outside the range, the player moves them by octaves
+}
+
+/// the instrument of every note on, following the program changes like the player does
+fn notes_with_sounds(events: &[TimedEvent]) -> impl Iterator<Item = (u7, SoundProfile)> + '_ {
+    let mut instrument_sounds = [DEFAULT_SOUND; 16];
+    events.iter().filter_map(move |event| {
+        let channel = event.channel.as_int() as usize;
+        if let MidiMessage::ProgramChange { program } = event.message {
+            instrument_sounds[channel] = INSTRUMENTS[program.as_int() as usize];
+        }
+        note_on_key(event).map(|key| (key, instrument_sounds[channel]))
+    })
+}
+
+pub fn transpose_fit(events: &[TimedEvent], semitones: i8) -> TransposeFit {
+    let folded_notes = notes_with_sounds(events)
+        .filter(|(key, sound)| {
+            let key = key.as_int() as i16 + semitones as i16;
+            key < sound.lowest_key as i16 || key > sound.highest_key as i16
+        })
+        .count();
+
+    TransposeFit {
+        semitones,
+        folded_notes,
+    }
+}
+
+/// the transpose within max_semitones that needs the least folding,
+/// the smallest one wins a tie so the song stays as close to the original as it can
+pub fn best_transpose(events: &[TimedEvent], max_semitones: i8) -> TransposeFit {
+    let max_semitones = max_semitones.clamp(0, 127);
+    (-max_semitones..=max_semitones)
+        .map(|semitones| transpose_fit(events, semitones))
+        .min_by_key(|fit| (fit.folded_notes, fit.semitones.unsigned_abs()))
+        .expect("the range always has 0")
+}
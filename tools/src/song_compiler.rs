@@ -0,0 +1,385 @@
+// =============================================================================================
+//                         MIDI FILE TO COMPACT EVENT STREAM COMPILER
+// =============================================================================================
+
+// flattens all tracks into one time sorted list of channel voice events
+// and bakes the tempo map into absolute micro second timestamps
+
+use core::fmt;
+
+use midly::{Format, MetaMessage, MidiMessage, Smf, Timing, TrackEventKind, num::u4};
+
+use crate::song_check::{SongError, check_song};
+use crate::song_stream::{EVENT_LEN, HEADER_LEN, STREAM_MAGIC, STREAM_VERSION, StreamEvent};
+use crate::tempo_map::{TempoMap, TempoMapError};
+
+// the same tempo map the firmware plays from, with room for far more changes, the stream
+// carries the baked times so the firmware never has to hold them
+type CompilerTempoMap = TempoMap<4096, 256>;
+
+#[derive(Debug)]
+pub enum CompileError {
+    Parse(midly::Error),
+    TimecodeTiming,
+    TooLong(u64),
+    PauseTooLong { track: usize },
+    Broken(SongError),
+    TempoMap(TempoMapError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Parse(err) => write!(f, "not a valid midi file: {err}"),
+            CompileError::TimecodeTiming => {
+                write!(
+                    f,
+                    "SMPTE timecode timing is not supported, only ticks per quarter note"
+                )
+            }
+            CompileError::TooLong(micros) => write!(
+                f,
+                "song is {} seconds long, the stream format fits at most {} seconds",
+                micros / 1_000_000,
+                u32::MAX / 1_000_000
+            ),
+            CompileError::PauseTooLong { track } => write!(
+                f,
+                "track {track} has a pause longer than a midi file can store"
+            ),
+            CompileError::Broken(err) => write!(f, "{err}"),
+            CompileError::TempoMap(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<midly::Error> for CompileError {
+    fn from(err: midly::Error) -> Self {
+        CompileError::Parse(err)
+    }
+}
+
+impl From<TempoMapError> for CompileError {
+    fn from(err: TempoMapError) -> Self {
+        match err {
+            TempoMapError::Parse(err) => CompileError::Parse(err),
+            TempoMapError::TimecodeTiming => CompileError::TimecodeTiming,
+            err => CompileError::TempoMap(err),
+        }
+    }
+}
+
+impl From<SongError> for CompileError {
+    fn from(err: SongError) -> Self {
+        match err {
+            SongError::Header(err) => CompileError::Parse(err),
+            SongError::TimecodeTiming => CompileError::TimecodeTiming,
+            err => CompileError::Broken(err),
+        }
+    }
+}
+
+/// a channel voice event with both its position in ticks and in real time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedEvent {
+    pub tick: u64,
+    pub micros: u64,
+    pub track: usize,
+    pub channel: u4,
+    pub message: MidiMessage,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeline {
+    pub ticks_per_quarter: u16,
+    pub key_signature: Option<(i8, bool)>, // the first one in the song
+    pub events: Vec<TimedEvent>,
+    // the tick each track starts on, the patterns of a format 2 file play one after another
+    pub track_starts: Vec<u64>,
+    pub length_ticks: u64,
+    pub length_micros: u64,
+}
+
+/// the voice events of every track in playing order, simultaneous events keep the track order
+pub fn song_timeline(midi: &[u8]) -> Result<Timeline, CompileError> {
+    // a song for the firmware has to be whole, the lenient parser would cut broken tracks short
+    let mut first_break = None;
+    check_song(midi, |err| {
+        first_break.get_or_insert(err);
+    })?;
+    if let Some(err) = first_break {
+        return Err(err.into());
+    }
+
+    let smf = Smf::parse(midi)?;
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => return Err(CompileError::TimecodeTiming),
+    };
+
+    let mut key_signatures: Vec<(u64, (i8, bool))> = Vec::new();
+    let mut events: Vec<TimedEvent> = Vec::new();
+    let mut track_starts: Vec<u64> = Vec::with_capacity(smf.tracks.len());
+    let mut length_ticks = 0;
+
+    let sequential = smf.header.format == Format::Sequential;
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut tick = if sequential { length_ticks } else { 0 };
+        track_starts.push(tick);
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Midi { channel, message } => events.push(TimedEvent {
+                    tick,
+                    micros: 0,
+                    track: track_index,
+                    channel,
+                    message,
+                }),
+                TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                    key_signatures.push((tick, (sharps, minor)))
+                }
+                _ => {}
+            }
+        }
+        length_ticks = length_ticks.max(tick);
+    }
+
+    // a stable sort, so events on the same tick keep the track and file order
+    events.sort_by_key(|event| event.tick);
+
+    let tempo_map = CompilerTempoMap::from_midi(midi, |_| true)?;
+    for event in events.iter_mut() {
+        event.micros = tempo_map.tick_to_micros(event.tick);
+    }
+
+    Ok(Timeline {
+        ticks_per_quarter,
+        key_signature: key_signatures
+            .iter()
+            .min_by_key(|(tick, _)| *tick)
+            .map(|(_, key)| *key),
+        length_micros: tempo_map.tick_to_micros(length_ticks),
+        length_ticks,
+        events,
+        track_starts,
+    })
+}
+
+/// compiles a midi file into the stream format read by the firmware
+pub fn compile_song(midi: &[u8]) -> Result<Vec<u8>, CompileError> {
+    compile_timeline(&song_timeline(midi)?)
+}
+
+/// compiles an already flattened song, for timelines that were edited on the host
+pub fn compile_timeline(timeline: &Timeline) -> Result<Vec<u8>, CompileError> {
+    if timeline.length_micros > u32::MAX as u64 {
+        return Err(CompileError::TooLong(timeline.length_micros));
+    }
+
+    let mut stream = Vec::with_capacity(HEADER_LEN + timeline.events.len() * EVENT_LEN);
+    stream.extend_from_slice(&STREAM_MAGIC);
+    let (sharps, minor) = timeline.key_signature.unwrap_or((0, false));
+    stream.extend_from_slice(&[STREAM_VERSION, sharps as u8, minor as u8, 0]);
+    stream.extend_from_slice(&(timeline.events.len() as u32).to_le_bytes());
+    stream.extend_from_slice(&(timeline.length_micros as u32).to_le_bytes());
+
+    for event in &timeline.events {
+        let stream_event = StreamEvent {
+            micros: event.micros as u32,
+            channel: event.channel,
+            message: event.message,
+        };
+        stream.extend_from_slice(&stream_event.micros.to_le_bytes());
+        stream.extend_from_slice(&stream_event.to_bytes());
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song_stream::SongStream;
+    use crate::tempo_map::DEFAULT_TEMPO;
+    use crate::test_midi::{EOT, smf, tempo};
+
+    const TWO_TRACKS: &[u8] = include_bytes!("../tests/fixtures/two_tracks.mid");
+    const TRUNCATED: &[u8] = include_bytes!("../tests/fixtures/truncated.mid");
+    const SMPTE: &[u8] = include_bytes!("../tests/fixtures/smpte.mid");
+
+    /// the voice events and the song length from midly's own parse, timed by walking every
+    /// event of the song in order and adding up the time between them
+    fn midly_timeline(midi: &[u8]) -> (Vec<(f64, u4, MidiMessage)>, f64) {
+        let smf = Smf::parse(midi).unwrap();
+        let Timing::Metrical(ticks_per_quarter) = smf.header.timing else {
+            panic!("not metrical")
+        };
+        let sequential = smf.header.format == Format::Sequential;
+
+        let mut all = Vec::new();
+        let mut end = 0;
+        for track in &smf.tracks {
+            let mut tick = if sequential { end } else { 0 };
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                all.push((tick, event.kind));
+            }
+            end = end.max(tick);
+        }
+        all.sort_by_key(|(tick, _)| *tick);
+
+        let (mut micros, mut last_tick, mut tempo) = (0.0, 0, DEFAULT_TEMPO);
+        let mut advance = |micros: &mut f64, tick: u64, tempo: u32| {
+            *micros += (tick - last_tick) as f64 * tempo as f64 / ticks_per_quarter.as_int() as f64;
+            last_tick = tick;
+        };
+        let mut events = Vec::new();
+        for (tick, kind) in all {
+            advance(&mut micros, tick, tempo);
+            match kind {
+                TrackEventKind::Midi { channel, message } => {
+                    events.push((micros, channel, message))
+                }
+                TrackEventKind::Meta(MetaMessage::Tempo(new_tempo)) => tempo = new_tempo.as_int(),
+                _ => {}
+            }
+        }
+        advance(&mut micros, end, tempo);
+        (events, micros)
+    }
+
+    /// compiles the song, reads the stream back and compares it with midly's parse
+    fn assert_round_trip(midi: &[u8]) {
+        let stream_bytes = compile_song(midi).unwrap();
+        let stream = SongStream::new(&stream_bytes).unwrap();
+        let (expected, length) = midly_timeline(midi);
+
+        let events: Vec<StreamEvent> = stream.iter().collect();
+        assert_eq!(events.len(), expected.len());
+        for (i, (event, (micros, channel, message))) in events.iter().zip(&expected).enumerate() {
+            assert_eq!(
+                (event.channel, event.message),
+                (*channel, *message),
+                "event {i}"
+            );
+            // the compiler rounds down once per tempo segment
+            let off = event.micros as f64 - micros;
+            assert!((-1.0..=0.0).contains(&off), "event {i} is {off} us off");
+        }
+        let off = stream.length_micros() as f64 - length;
+        assert!((-1.0..=0.0).contains(&off), "the length is {off} us off");
+    }
+
+    #[test]
+    fn the_fixture_song_reads_back_like_midly_parses_it() {
+        assert_round_trip(TWO_TRACKS);
+        let stream_bytes = compile_song(TWO_TRACKS).unwrap();
+        let stream = SongStream::new(&stream_bytes).unwrap();
+        // a quarter note is 0.5 s and the tempo track is a whole 4/4 bar long
+        assert_eq!(stream.length_micros(), 2_000_000);
+        let times: Vec<u32> = stream.iter().map(|event| event.micros).collect();
+        assert_eq!(
+            times,
+            [0, 500_000, 500_000, 1_000_000, 1_000_000, 2_000_000]
+        );
+    }
+
+    #[test]
+    fn tempo_changes_in_any_track_move_the_later_events() {
+        let conductor: &[(u32, &[u8])] = &[
+            (0, &tempo(600_000)),
+            (0, &[0xFF, 0x59, 0x02, 0xFE, 0x01]), // B flat minor
+            (480, &tempo(300_000)),
+            (520, &tempo(400_000)),
+            // a second change on the same tick wins
+            (0, &tempo(450_000)),
+            (0, EOT),
+        ];
+        let melody: &[(u32, &[u8])] = &[
+            (0, &[0xC1, 0x30]),
+            (0, &[0x91, 60, 100]),
+            (240, &[0x81, 60, 0]),
+            (240, &[0x91, 62, 90]),
+            (100, &[0xE1, 0x00, 0x50]),
+            (900, &[0xB1, 7, 80]),
+            (1, &[0x81, 62, 0]),
+            (0, EOT),
+        ];
+        let bass: &[(u32, &[u8])] = &[
+            (480, &[0x92, 36, 100]),
+            (520, &[0x82, 36, 0]),
+            (0, &tempo(350_000)),
+            (777, &[0xA2, 36, 10]),
+            (3000, EOT),
+        ];
+        let midi = smf(1, 480, &[conductor, melody, bass]);
+        assert_round_trip(&midi);
+
+        let timeline = song_timeline(&midi).unwrap();
+        assert_eq!(timeline.key_signature, Some((-2, true)));
+        // events on the same tick keep the order of their tracks
+        let at_480: Vec<usize> = timeline
+            .events
+            .iter()
+            .filter(|e| e.tick == 480)
+            .map(|e| e.track)
+            .collect();
+        assert_eq!(at_480, [1, 2]);
+        assert_eq!(timeline.length_ticks, 480 + 520 + 777 + 3000);
+    }
+
+    #[test]
+    fn format_2_patterns_follow_each_other() {
+        let first: &[(u32, &[u8])] = &[(0, &[0x90, 60, 100]), (96, &[0x80, 60, 0]), (0, EOT)];
+        let second: &[(u32, &[u8])] = &[
+            (0, &tempo(250_000)),
+            (48, &[0x90, 64, 100]),
+            (48, &[0x80, 64, 0]),
+            (0, EOT),
+        ];
+        let midi = smf(2, 96, &[first, second, first]);
+        assert_round_trip(&midi);
+
+        let timeline = song_timeline(&midi).unwrap();
+        assert_eq!(timeline.track_starts, [0, 96, 192]);
+        assert_eq!(timeline.length_ticks, 288);
+        // half a second for the first pattern and a quarter for each after the tempo change
+        assert_eq!(timeline.length_micros, 1_000_000);
+    }
+
+    #[test]
+    fn a_song_with_more_tempo_changes_than_the_firmware_holds_compiles() {
+        // an accelerando of 300 small steps, the firmware's tempo map keeps 64
+        let steps: Vec<Vec<u8>> = (0..300).map(|i| tempo(500_000 - i * 1000)).collect();
+        let mut conductor: Vec<(u32, &[u8])> = steps.iter().map(|step| (4, &step[..])).collect();
+        conductor.push((0, EOT));
+        let notes: &[(u32, &[u8])] = &[(600, &[0x90, 60, 100]), (600, &[0x80, 60, 0]), (0, EOT)];
+        let midi = smf(1, 96, &[&conductor, notes]);
+
+        let stream_bytes = compile_song(&midi).unwrap();
+        let stream = SongStream::new(&stream_bytes).unwrap();
+        let (expected, length) = midly_timeline(&midi);
+        let times: Vec<f64> = stream.iter().map(|event| event.micros as f64).collect();
+        // rounding down once per tempo segment loses less than a micro second each time
+        for (micros, (expected, _, _)) in times.iter().zip(&expected) {
+            assert!((-300.0..=0.0).contains(&(micros - expected)), "{micros} us");
+        }
+        let off = stream.length_micros() as f64 - length;
+        assert!((-301.0..=0.0).contains(&off), "the length is {off} us off");
+    }
+
+    #[test]
+    fn broken_and_smpte_songs_are_not_compiled() {
+        assert!(matches!(
+            compile_song(TRUNCATED),
+            Err(CompileError::Broken(_))
+        ));
+        assert!(matches!(
+            compile_song(SMPTE),
+            Err(CompileError::TimecodeTiming)
+        ));
+        assert!(matches!(compile_song(b"MThd"), Err(CompileError::Parse(_))));
+    }
+}
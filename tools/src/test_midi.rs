@@ -0,0 +1,39 @@
+// =============================================================================================
+//                             SMALL MIDI FILES FOR THE TESTS
+// =============================================================================================
+
+// builds a midi file from the bytes of its events, so a test can make exactly the song it
+// needs, including broken ones, without checking in another fixture
+
+pub const EOT: &[u8] = &[0xFF, 0x2F, 0x00];
+
+/// a midi file of the tracks, each a list of delta ticks and event bytes
+pub fn smf(format: u16, ticks_per_quarter: u16, tracks: &[&[(u32, &[u8])]]) -> Vec<u8> {
+    let mut midi = b"MThd\0\0\0\x06".to_vec();
+    for number in [format, tracks.len() as u16, ticks_per_quarter] {
+        midi.extend_from_slice(&number.to_be_bytes());
+    }
+    for track in tracks {
+        let mut events = Vec::new();
+        for (delta, bytes) in track.iter() {
+            let mut delta = *delta;
+            let mut varlen = vec![(delta & 0x7F) as u8];
+            while delta > 0x7F {
+                delta >>= 7;
+                varlen.insert(0, 0x80 | (delta & 0x7F) as u8);
+            }
+            events.extend_from_slice(&varlen);
+            events.extend_from_slice(bytes);
+        }
+        midi.extend_from_slice(b"MTrk");
+        midi.extend_from_slice(&(events.len() as u32).to_be_bytes());
+        midi.extend_from_slice(&events);
+    }
+    midi
+}
+
+/// a set tempo meta event
+pub fn tempo(micros_per_quarter: u32) -> Vec<u8> {
+    let [_, bytes @ ..] = micros_per_quarter.to_be_bytes();
+    [&[0xFF, 0x51, 0x03][..], &bytes].concat()
+}
@@ -0,0 +1,85 @@
+// =============================================================================================
+//                   REDUCING THE FIXTURE SONGS AND READING THEM BACK
+// =============================================================================================
+
+// the reduced song is written as midi and analyzed again like midi_reduce would, the file has
+// to stay under the limit after the events on each tick are put back in playing order
+
+use midi_tools::{
+    arrangement::{reduce_voices, write_reduced_midi},
+    song_analysis::{max_polyphony, note_on_key},
+    song_compiler::{Timeline, song_timeline},
+};
+
+const TWO_TRACKS: &[u8] = include_bytes!("fixtures/two_tracks.mid");
+const TEMPO_CHANGES: &[u8] = include_bytes!("fixtures/tempo_changes.mid");
+const PATTERNS: &[u8] = include_bytes!("fixtures/patterns.mid");
+const RUNNING_STATUS: &[u8] = include_bytes!("fixtures/running_status.mid");
+const MIDI_TEST: &[u8] = include_bytes!("../../midi_test.mid");
+
+const SONGS: [(&str, &[u8]); 5] = [
+    ("two_tracks", TWO_TRACKS),
+    ("tempo_changes", TEMPO_CHANGES),
+    ("patterns", PATTERNS),
+    ("running_status", RUNNING_STATUS),
+    ("midi_test", MIDI_TEST),
+];
+
+fn note_count(timeline: &Timeline) -> usize {
+    let notes = timeline.events.iter().filter_map(note_on_key);
+    notes.count()
+}
+
+#[test]
+fn a_reduced_song_read_back_stays_under_the_limit() {
+    for (name, midi) in SONGS {
+        let timeline = song_timeline(midi).unwrap();
+        let voices_needed = max_polyphony(&timeline.events);
+        // the firmware has at most 16 buzzers
+        for voices in 1..=voices_needed.min(16) {
+            let reduction = reduce_voices(&timeline, voices);
+            assert!(max_polyphony(&reduction.timeline.events) <= voices);
+
+            let written = write_reduced_midi(midi, &reduction.timeline).unwrap();
+            let read_back = song_timeline(&written).unwrap();
+            let played = max_polyphony(&read_back.events);
+            assert!(played <= voices, "{name} at {voices} voices plays {played}");
+
+            // only the dropped notes are missing, the cut ones still start
+            assert_eq!(
+                note_count(&read_back),
+                note_count(&timeline) - reduction.dropped_notes,
+                "{name} at {voices} voices"
+            );
+            assert_eq!(read_back.length_ticks, timeline.length_ticks);
+        }
+    }
+}
+
+#[test]
+fn the_running_status_song_is_cut_across_its_tracks() {
+    let timeline = song_timeline(RUNNING_STATUS).unwrap();
+    assert!(max_polyphony(&timeline.events) > 2);
+
+    let reduction = reduce_voices(&timeline, 2);
+    assert!(reduction.dropped_notes + reduction.shortened_notes > 0);
+    let written = write_reduced_midi(RUNNING_STATUS, &reduction.timeline).unwrap();
+    let read_back = song_timeline(&written).unwrap();
+    assert_eq!(max_polyphony(&read_back.events), 2);
+}
+
+#[test]
+fn a_song_that_fits_is_written_back_unchanged() {
+    for (name, midi) in SONGS {
+        let timeline = song_timeline(midi).unwrap();
+        let reduction = reduce_voices(&timeline, max_polyphony(&timeline.events));
+        assert_eq!(
+            (reduction.dropped_notes, reduction.shortened_notes),
+            (0, 0),
+            "{name}"
+        );
+        let written = write_reduced_midi(midi, &reduction.timeline).unwrap();
+        let read_back = song_timeline(&written).unwrap();
+        assert_eq!(read_back.events, timeline.events, "{name}");
+    }
+}
@@ -0,0 +1,253 @@
+// =============================================================================================
+//                       STREAMING MIDI READER AGAINST MIDLY'S PARSE
+// =============================================================================================
+
+// reads every midi file of the fixtures and the embedded song once with midly's parse and
+// once with the MidiReader the firmware uses for songs on the SD card, with a few track
+// buffer sizes, and checks that both give the same events, the same merged order and the
+// same broken tracks
+//
+// meta and sysex events with more data than fits in the track buffer are cut by the reader,
+// those only have to start with the same bytes
+//
+// any .mid file put into tests/fixtures is checked too
+
+use std::{fs, mem, path::PathBuf};
+
+use midi_tools::{
+    midi_reader::MidiReader,
+    song_check::check_song,
+    track_merger::{MergedEvent, TrackMerger},
+};
+use midly::{EventIter, Format, MetaMessage, TrackEvent, TrackEventKind, parse};
+
+const MAX_TRACKS: usize = 256;
+
+#[derive(Debug, Default)]
+struct Stats {
+    tracks: usize,
+    events: usize,
+    cut_events: usize,
+}
+
+/// the midi files of the fixtures sorted by name and the song built into the firmware
+fn corpus() -> Vec<PathBuf> {
+    let manifest = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let mut songs: Vec<PathBuf> = fs::read_dir(manifest.join("tests/fixtures"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("mid"))
+        })
+        .collect();
+    songs.sort();
+    songs.push(manifest.join("../midi_test.mid"));
+    songs
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(path).unwrap()
+}
+
+/// the stats of the smallest buffer, after every buffer size read the same as midly
+fn compare_all(midi: &[u8]) -> Result<Stats, String> {
+    compare::<512>(midi)
+        .and_then(|_| compare::<64>(midi))
+        .and_then(|_| compare::<32>(midi))
+        .and_then(|_| compare::<20>(midi))
+}
+
+#[test]
+fn every_song_of_the_corpus_reads_the_same_as_midlys_parse() {
+    let songs = corpus();
+    assert!(songs.len() >= 10, "the fixtures are missing");
+    let failures: Vec<String> = songs
+        .iter()
+        .filter_map(|path| {
+            let midi = fs::read(path).unwrap();
+            let err = compare_all(&midi).err()?;
+            Some(format!("{}: {err}", path.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{failures:#?}");
+}
+
+#[test]
+fn long_events_are_cut_to_the_track_buffer() {
+    let midi = fixture("long_events.mid");
+    assert_eq!(compare::<512>(&midi).unwrap().cut_events, 0);
+    let stats = compare_all(&midi).unwrap();
+    assert_eq!((stats.tracks, stats.events), (1, 9));
+    assert_eq!(stats.cut_events, 3);
+}
+
+#[test]
+fn running_status_survives_the_buffer_refills() {
+    let stats = compare_all(&fixture("running_status.mid")).unwrap();
+    assert_eq!(stats.tracks, 4);
+    assert_eq!(stats.events, 4 * 405);
+    assert_eq!(stats.cut_events, 0);
+}
+
+#[test]
+fn broken_songs_break_the_same_tracks() {
+    for name in ["truncated.mid", "corrupted.mid", "missing_track.mid"] {
+        let midi = fixture(name);
+        let mut broken = 0;
+        check_song(&midi, |_| broken += 1).unwrap();
+        assert_eq!(broken, 1, "{name}");
+        compare_all(&midi).unwrap_or_else(|err| panic!("{name}: {err}"));
+    }
+}
+
+fn compare<const BUFFER: usize>(midi: &[u8]) -> Result<Stats, String> {
+    let buffer = format!("{BUFFER} byte buffer");
+    let mut reader = match MidiReader::<&[u8], MAX_TRACKS, BUFFER>::open(midi) {
+        Ok(reader) => reader,
+        Err(err) => {
+            // a file midly can't read either is fine
+            return match check_song(midi, |_| {}) {
+                Ok(_) => Err(format!("{buffer}: the reader refuses the file: {err}")),
+                Err(_) => Ok(Stats::default()),
+            };
+        }
+    };
+    let (header, tracks) = parse(midi).map_err(|err| format!("midly refuses the file: {err}"))?;
+    if reader.header() != header {
+        return Err(format!("{buffer}: the headers differ"));
+    }
+    let tracks: Vec<EventIter> = tracks.filter_map(Result::ok).collect();
+    if reader.track_count() != tracks.len() {
+        return Err(format!(
+            "{buffer}: {} tracks instead of {}",
+            reader.track_count(),
+            tracks.len()
+        ));
+    }
+
+    // ---------------- every track on its own ----------------
+
+    let mut stats = Stats {
+        tracks: tracks.len(),
+        ..Stats::default()
+    };
+    for (track, events) in tracks.iter().enumerate() {
+        let expected: Vec<TrackEvent> = events.clone().map_while(Result::ok).collect();
+        let mut read = 0;
+        while let Some(event) = reader.next_event(track) {
+            let Some(expected) = expected.get(read) else {
+                return Err(format!("{buffer}: track {track} has too many events"));
+            };
+            if event.delta != expected.delta {
+                return Err(format!(
+                    "{buffer}: track {track} event {read} has another delta"
+                ));
+            }
+            match same_kind(&expected.kind, &event.kind) {
+                Some(cut) => stats.cut_events += cut as usize,
+                None => {
+                    return Err(format!(
+                        "{buffer}: track {track} event {read}: {:?} instead of {:?}",
+                        event.kind, expected.kind
+                    ));
+                }
+            }
+            read += 1;
+        }
+        if read != expected.len() {
+            return Err(format!(
+                "{buffer}: track {track} ends after {read} of {} events",
+                expected.len()
+            ));
+        }
+        stats.events += read;
+    }
+    reader.rewind();
+
+    // ---------------- the broken tracks ----------------
+
+    let mut expected_broken = Vec::new();
+    let _ = check_song(midi, |err| expected_broken.push(err.to_string()));
+    let mut broken = Vec::new();
+    reader.check(|err| broken.push(err.to_string()));
+    if broken != expected_broken {
+        return Err(format!(
+            "{buffer}: the check found {broken:?} instead of {expected_broken:?}"
+        ));
+    }
+
+    // ---------------- the merged order ----------------
+
+    let track_events = tracks.into_iter().enumerate();
+    let merged: Vec<MergedEvent> = if header.format == Format::Sequential {
+        TrackMerger::<MAX_TRACKS>::sequential(track_events)
+    } else {
+        TrackMerger::<MAX_TRACKS>::new(track_events)
+    }
+    .map_err(|err| err.to_string())?
+    .collect();
+
+    reader.start_merge(|_| true);
+    let mut read = 0;
+    while let Some(event) = reader.next_merged() {
+        let Some(expected) = merged.get(read) else {
+            return Err(format!("{buffer}: too many merged events"));
+        };
+        if (event.tick, event.track) != (expected.tick, expected.track)
+            || same_kind(&expected.kind, &event.kind).is_none()
+        {
+            return Err(format!(
+                "{buffer}: merged event {read} is {:?} instead of {:?}",
+                (event.tick, event.track, event.kind),
+                (expected.tick, expected.track, expected.kind)
+            ));
+        }
+        read += 1;
+    }
+    if read != merged.len() {
+        return Err(format!(
+            "{buffer}: {read} merged events instead of {}",
+            merged.len()
+        ));
+    }
+    Ok(stats)
+}
+
+/// Some(true) if the read event is the expected one cut short, None if they differ
+fn same_kind(expected: &TrackEventKind, read: &TrackEventKind) -> Option<bool> {
+    if expected == read {
+        return Some(false);
+    }
+    let (expected, read) = match (expected, read) {
+        (TrackEventKind::SysEx(expected), TrackEventKind::SysEx(read))
+        | (TrackEventKind::Escape(expected), TrackEventKind::Escape(read)) => (*expected, *read),
+        (TrackEventKind::Meta(expected), TrackEventKind::Meta(read))
+            if mem::discriminant(expected) == mem::discriminant(read) =>
+        {
+            (meta_data(expected)?, meta_data(read)?)
+        }
+        _ => return None,
+    };
+    (read.len() < expected.len() && expected.starts_with(read)).then_some(true)
+}
+
+fn meta_data<'a>(meta: &MetaMessage<'a>) -> Option<&'a [u8]> {
+    match *meta {
+        MetaMessage::Text(data)
+        | MetaMessage::Copyright(data)
+        | MetaMessage::TrackName(data)
+        | MetaMessage::InstrumentName(data)
+        | MetaMessage::Lyric(data)
+        | MetaMessage::Marker(data)
+        | MetaMessage::CuePoint(data)
+        | MetaMessage::ProgramName(data)
+        | MetaMessage::DeviceName(data)
+        | MetaMessage::SequencerSpecific(data)
+        | MetaMessage::Unknown(_, data) => Some(data),
+        _ => None,
+    }
+}
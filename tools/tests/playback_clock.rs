@@ -0,0 +1,150 @@
+// =============================================================================================
+//                     PLAYING FIXTURE SONGS ON THE PLAYBACK CLOCK
+// =============================================================================================
+
+// the songs are walked event by event like the player does, waiting on the clock for the
+// time between events from the tempo map, the last deadline is how long the song took
+
+use midi_tools::{
+    playback_clock::{
+        CLOCK_CALIBRATION_PPM, MAX_SPEED_PCT, MIN_SPEED_PCT, NORMAL_SPEED_PCT, PlaybackClock,
+        calibrated_micros, scale_micros,
+    },
+    tempo_map::TempoMap,
+    track_merger::TrackMerger,
+};
+use midly::parse;
+
+const TWO_TRACKS: &[u8] = include_bytes!("fixtures/two_tracks.mid");
+const TEMPO_CHANGES: &[u8] = include_bytes!("fixtures/tempo_changes.mid");
+
+const START_MICROS: u64 = 7_000_000; // the clock starts at any real time
+
+type SongTempoMap = TempoMap<64, 16>;
+
+/// the song time of every event in playing order and of the end of the song
+fn event_times(midi: &[u8]) -> (Vec<u64>, u64) {
+    let tempo_map = SongTempoMap::from_midi(midi, |_| true).unwrap();
+    let (_, tracks) = parse(midi).unwrap();
+    let tracks = tracks.map(Result::unwrap).enumerate();
+    let merger = TrackMerger::<16>::new(tracks).unwrap();
+    let times = merger.map(|event| tempo_map.tick_to_micros(event.tick));
+    (times.collect(), tempo_map.length_micros())
+}
+
+/// plays the song and returns the real time it ended, the speed changes at the given events
+fn play(midi: &[u8], speed_pct: u16, speed_changes: &[(usize, u16)]) -> u64 {
+    let (times, length) = event_times(midi);
+    let mut clock = PlaybackClock::new(speed_pct);
+    clock.start(START_MICROS);
+    let mut last = 0;
+    for (i, micros) in times.into_iter().enumerate() {
+        if let Some((_, speed_pct)) = speed_changes.iter().find(|(at, _)| *at == i) {
+            clock.set_speed(*speed_pct);
+        }
+        clock.advance(micros - last);
+        last = micros;
+    }
+    clock.advance(length - last) - START_MICROS
+}
+
+#[test]
+fn the_tempo_map_times_the_fixture_songs() {
+    // 384 ticks at 96 a quarter and 120 BPM
+    let tempo_map = SongTempoMap::from_midi(TWO_TRACKS, |_| true).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 384);
+    assert_eq!(tempo_map.length_micros(), 2_000_000);
+
+    // two quarters at 100 BPM, two at 200 BPM and three at 60 BPM
+    let tempo_map = SongTempoMap::from_midi(TEMPO_CHANGES, |_| true).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 3360);
+    assert_eq!(tempo_map.tick_to_micros(960), 1_200_000);
+    assert_eq!(tempo_map.tick_to_micros(1920), 1_800_000);
+    assert_eq!(tempo_map.length_micros(), 4_800_000);
+}
+
+#[test]
+fn a_song_at_normal_speed_takes_as_long_as_the_tempo_map_says() {
+    for midi in [TWO_TRACKS, TEMPO_CHANGES] {
+        let length = SongTempoMap::from_midi(midi, |_| true)
+            .unwrap()
+            .length_micros();
+        assert_eq!(play(midi, NORMAL_SPEED_PCT, &[]), length);
+    }
+}
+
+#[test]
+fn the_speed_stretches_the_whole_song() {
+    let length = 4_800_000;
+    for speed_pct in [MIN_SPEED_PCT, 50, 75, 150, 300, MAX_SPEED_PCT] {
+        let expected = length * 100 / speed_pct as u64;
+        assert_eq!(
+            play(TEMPO_CHANGES, speed_pct, &[]),
+            expected,
+            "{speed_pct} %"
+        );
+    }
+    // speeds out of range are clamped
+    assert_eq!(play(TEMPO_CHANGES, 1, &[]), length * 4);
+    assert_eq!(play(TEMPO_CHANGES, 1000, &[]), length / 4);
+}
+
+#[test]
+fn a_speed_change_only_stretches_the_rest_of_the_song() {
+    let (times, length) = event_times(TEMPO_CHANGES);
+    // half way through the notes the song goes twice as fast
+    let at = times.len() / 2;
+    let played = times[at - 1];
+    let expected = played + (length - played) / 2;
+    assert_eq!(
+        play(TEMPO_CHANGES, NORMAL_SPEED_PCT, &[(at, 200)]),
+        expected
+    );
+
+    // going back to the normal speed only shortens the part between the changes
+    let at = [(at, 200), (at + 1, NORMAL_SPEED_PCT)];
+    let between = times[at[1].0 - 1] - played;
+    let expected = length - between / 2;
+    assert_eq!(play(TEMPO_CHANGES, NORMAL_SPEED_PCT, &at), expected);
+}
+
+#[test]
+fn rounding_doesnt_build_up_over_a_long_song() {
+    // a third of a micro second is lost on every event when the waits are scaled one by one
+    let mut clock = PlaybackClock::new(300);
+    clock.start(0);
+    let mut waited = 0;
+    for _ in 0..100_000 {
+        let before = clock.deadline_micros();
+        waited += clock.advance(1001) - before;
+    }
+    assert_eq!(clock.deadline_micros(), 100_100_000 / 3);
+    assert_eq!(waited, clock.deadline_micros());
+}
+
+#[test]
+fn the_calibration_corrects_a_clock_that_runs_off() {
+    // the built in calibration changes nothing until it's measured
+    assert_eq!(CLOCK_CALIBRATION_PPM, 0);
+    assert_eq!(scale_micros(4_800_000, NORMAL_SPEED_PCT), 4_800_000);
+    assert_eq!(calibrated_micros(4_800_000, 200, 0), 2_400_000);
+
+    // a clock 100 ppm fast counts 100 more micro seconds in a second of song
+    assert_eq!(
+        calibrated_micros(1_000_000, NORMAL_SPEED_PCT, 100),
+        1_000_100
+    );
+    assert_eq!(
+        calibrated_micros(1_000_000, NORMAL_SPEED_PCT, -250),
+        999_750
+    );
+    // the calibration is applied on top of the speed
+    assert_eq!(calibrated_micros(10_000_000, 200, 40), 5_000_200);
+    assert_eq!(calibrated_micros(10_000_000, 1, -40), 39_998_400);
+    // three hours of song don't overflow
+    let hours = 3 * 3600 * 1_000_000;
+    assert_eq!(
+        calibrated_micros(hours, MIN_SPEED_PCT, 1000),
+        hours * 4 + hours * 4 / 1000
+    );
+}
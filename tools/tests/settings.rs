@@ -0,0 +1,246 @@
+// =============================================================================================
+//                          SAVING THE SETTINGS INTO A RAM FLASH
+// =============================================================================================
+
+use midi_tools::{
+    ram_flash::{FlashError, RAM_SECTOR_LEN, RamFlash},
+    settings::{Loaded, ProfileTweak, RECORD_LEN, Settings, SettingsError, SettingsStore},
+    sound_profiles::INSTRUMENTS,
+    vibrato::Vibrato,
+};
+
+const SLOTS_PER_SECTOR: usize = RAM_SECTOR_LEN as usize / RECORD_LEN;
+
+fn settings(speed_pct: u16) -> Settings {
+    Settings {
+        speed_pct,
+        ..Settings::default()
+    }
+}
+
+/// the store after a reset
+fn reopen(store: SettingsStore<RamFlash>) -> SettingsStore<RamFlash> {
+    SettingsStore::open(store.into_flash()).unwrap()
+}
+
+fn slot_offset(slot: usize) -> usize {
+    slot / SLOTS_PER_SECTOR * RAM_SECTOR_LEN as usize + slot % SLOTS_PER_SECTOR * RECORD_LEN
+}
+
+#[test]
+fn a_blank_flash_gives_the_defaults() {
+    let store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    assert_eq!(store.loaded(), Loaded::Empty);
+    assert_eq!(*store.settings(), Settings::default());
+}
+
+#[test]
+fn saved_settings_come_back_after_a_reset() {
+    let mut saved = Settings {
+        volume: 200,
+        transpose: -5,
+        transpose_to_key: true,
+        speed_pct: 85,
+        ..Settings::default()
+    };
+    saved
+        .set_tweak(ProfileTweak {
+            program: 81,
+            wait_time: 123,
+            wait_change_per_key: 7,
+            duration: Some(-40),
+            lowest_key: 36,
+            highest_key: 96,
+            vibrato: Vibrato::new(675, 24, 1250),
+        })
+        .unwrap();
+    saved
+        .set_tweak(ProfileTweak {
+            duration: None,
+            ..ProfileTweak::from_instrument(0)
+        })
+        .unwrap();
+
+    let mut store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    assert!(store.save(&saved).unwrap());
+    // the same settings again don't wear the flash
+    assert!(!store.save(&saved).unwrap());
+
+    let store = reopen(store);
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 1 });
+    assert_eq!(*store.settings(), saved);
+}
+
+#[test]
+fn a_corrupted_record_falls_back_to_the_one_before() {
+    let mut store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    store.save(&settings(90)).unwrap();
+    store.save(&settings(110)).unwrap();
+
+    let mut flash = store.into_flash();
+    // a flipped bit in the speed of the second record
+    flash.bytes_mut()[slot_offset(1) + 6] ^= 0x04;
+    let mut store = SettingsStore::open(flash).unwrap();
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 1 });
+    assert_eq!(store.settings().speed_pct, 90);
+
+    // the next save skips the broken slot and is newer than both
+    store.save(&settings(120)).unwrap();
+    let store = reopen(store);
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 2 });
+    assert_eq!(store.settings().speed_pct, 120);
+}
+
+#[test]
+fn only_corrupted_records_give_the_defaults() {
+    let mut store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    store.save(&settings(90)).unwrap();
+    store.save(&settings(110)).unwrap();
+
+    let mut flash = store.into_flash();
+    for slot in 0..2 {
+        // the checksum itself is broken this time
+        flash.bytes_mut()[slot_offset(slot) + RECORD_LEN - 1] ^= 0xFF;
+    }
+    let store = SettingsStore::open(flash).unwrap();
+    assert_eq!(store.loaded(), Loaded::Corrupted { records: 2 });
+    assert_eq!(*store.settings(), Settings::default());
+}
+
+#[test]
+fn version_1_records_give_the_defaults_until_the_next_save() {
+    // the old 192 byte records, a whole sector of them
+    let mut flash = RamFlash::new(2);
+    for record in flash.bytes_mut()[..RAM_SECTOR_LEN as usize - 64].chunks_exact_mut(192) {
+        record.fill(0);
+        record[..4].copy_from_slice(b"MSET");
+        record[4] = 1;
+    }
+    let mut store = SettingsStore::open(flash).unwrap();
+    assert!(matches!(store.loaded(), Loaded::Corrupted { .. }));
+    assert_eq!(*store.settings(), Settings::default());
+
+    store.save(&settings(90)).unwrap();
+    let store = reopen(store);
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 1 });
+    assert_eq!(store.settings().speed_pct, 90);
+}
+
+#[test]
+fn a_tweak_narrows_the_key_range_and_brings_its_vibrato() {
+    let vibrato = Vibrato::new(400, 30, 0);
+    let tweak = ProfileTweak {
+        lowest_key: 48,
+        highest_key: 84,
+        vibrato,
+        ..ProfileTweak::from_instrument(0)
+    };
+    let profile = tweak.profile();
+    assert_eq!((profile.lowest_key, profile.highest_key), (48, 84));
+    assert_eq!(profile.vibrato, vibrato);
+
+    // the open range of the built in sound stops where the buzzers can't play
+    for program in [0, 40, 73, 127] {
+        let built_in = INSTRUMENTS[program as usize];
+        let profile = ProfileTweak::from_instrument(program).profile();
+        assert_eq!(
+            (profile.lowest_key, profile.highest_key, profile.vibrato),
+            (built_in.lowest_key, built_in.highest_key, built_in.vibrato),
+            "program {program}"
+        );
+    }
+
+    // a range with no key the buzzers can play is left out
+    let profile = ProfileTweak {
+        lowest_key: 126,
+        highest_key: 127,
+        ..ProfileTweak::from_instrument(0)
+    }
+    .profile();
+    assert_eq!(
+        (profile.lowest_key, profile.highest_key),
+        (INSTRUMENTS[0].lowest_key, INSTRUMENTS[0].highest_key)
+    );
+}
+
+#[test]
+fn saves_rotate_through_every_slot_and_wear_the_sectors_evenly() {
+    const SECTORS: usize = 3;
+    const ROUNDS: usize = 4;
+    let slots = SECTORS * SLOTS_PER_SECTOR;
+
+    let mut store = SettingsStore::open(RamFlash::new(SECTORS)).unwrap();
+    for save in 0..ROUNDS * slots {
+        store.save(&settings(save as u16)).unwrap();
+        // a reset at any point finds the newest save
+        if save % 17 == 0 {
+            store = reopen(store);
+            assert_eq!(store.settings().speed_pct, save as u16);
+        }
+    }
+
+    let flash = store.into_flash();
+    assert_eq!(flash.erase_counts(), [ROUNDS as u32; SECTORS]);
+    // every slot of the last round is written
+    for slot in 0..slots {
+        let record = &flash.bytes()[slot_offset(slot)..slot_offset(slot) + RECORD_LEN];
+        assert_eq!(&record[..4], b"MSET", "slot {slot}");
+    }
+    let store = SettingsStore::open(flash).unwrap();
+    let saves = (ROUNDS * slots) as u32;
+    assert_eq!(store.loaded(), Loaded::Record { sequence: saves });
+    assert_eq!(store.settings().speed_pct, saves as u16 - 1);
+}
+
+#[test]
+fn an_interrupted_save_keeps_the_previous_record() {
+    let mut store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    store.save(&settings(90)).unwrap();
+
+    let mut flash = store.into_flash();
+    flash.lose_power_after(0);
+    let mut store = SettingsStore::open(flash).unwrap();
+    let interrupted = store.save(&settings(110));
+    assert!(matches!(
+        interrupted,
+        Err(SettingsError::Flash(FlashError::PowerLoss))
+    ));
+
+    let mut store = reopen(store);
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 1 });
+    assert_eq!(store.settings().speed_pct, 90);
+
+    // the half written slot can't be written again before its sector is erased
+    store.save(&settings(110)).unwrap();
+    let flash = store.into_flash();
+    assert_eq!(&flash.bytes()[slot_offset(2)..slot_offset(2) + 4], b"MSET");
+    let store = SettingsStore::open(flash).unwrap();
+    assert_eq!(store.loaded(), Loaded::Record { sequence: 2 });
+    assert_eq!(store.settings().speed_pct, 110);
+}
+
+#[test]
+fn an_interrupted_save_into_a_fresh_sector_keeps_the_full_one() {
+    let mut store = SettingsStore::open(RamFlash::new(2)).unwrap();
+    for save in 0..SLOTS_PER_SECTOR {
+        store.save(&settings(save as u16)).unwrap();
+    }
+
+    let mut flash = store.into_flash();
+    flash.lose_power_after(0);
+    let mut store = SettingsStore::open(flash).unwrap();
+    assert!(store.save(&settings(500)).is_err());
+
+    let store = reopen(store);
+    let last = SLOTS_PER_SECTOR as u32;
+    assert_eq!(store.loaded(), Loaded::Record { sequence: last });
+    assert_eq!(store.settings().speed_pct, last as u16 - 1);
+}
+
+#[test]
+fn the_area_needs_two_sectors() {
+    assert!(matches!(
+        SettingsStore::open(RamFlash::new(1)),
+        Err(SettingsError::AreaTooSmall)
+    ));
+}
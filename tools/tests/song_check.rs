@@ -0,0 +1,103 @@
+// =============================================================================================
+//                           CHECKING THE BROKEN FIXTURE SONGS
+// =============================================================================================
+
+// the fixtures are small hand made files, each broken in one way, see fixtures/README
+
+use midi_tools::{
+    midi_reader::{MidiReader, ReaderError},
+    song_check::{SongError, check_song, ticks_per_quarter},
+};
+use midly::parse;
+
+const TWO_TRACKS: &[u8] = include_bytes!("fixtures/two_tracks.mid");
+const TRUNCATED: &[u8] = include_bytes!("fixtures/truncated.mid");
+const CORRUPTED: &[u8] = include_bytes!("fixtures/corrupted.mid");
+const MISSING_TRACK: &[u8] = include_bytes!("fixtures/missing_track.mid");
+const SMPTE: &[u8] = include_bytes!("fixtures/smpte.mid");
+
+/// the number of tracks and the broken ones
+fn check(midi: &[u8]) -> Result<(usize, Vec<SongError>), SongError> {
+    let mut broken = Vec::new();
+    let tracks = check_song(midi, |err| broken.push(err))?;
+    Ok((tracks, broken))
+}
+
+#[test]
+fn a_whole_song_has_no_broken_tracks() {
+    let (tracks, broken) = check(TWO_TRACKS).unwrap();
+    assert_eq!(tracks, 2);
+    assert!(broken.is_empty(), "{broken:?}");
+}
+
+#[test]
+fn a_truncated_track_plays_until_the_last_whole_event() {
+    let (tracks, broken) = check(TRUNCATED).unwrap();
+    assert_eq!(tracks, 2);
+    let [err] = broken.as_slice() else {
+        panic!("{broken:?}")
+    };
+    assert!(matches!(err, SongError::Event { track: 1, .. }), "{err}");
+    assert_eq!(err.track(), Some(1));
+    assert_eq!(err.playable_ticks(), 192);
+}
+
+#[test]
+fn a_corrupted_event_breaks_its_track() {
+    let (tracks, broken) = check(CORRUPTED).unwrap();
+    assert_eq!(tracks, 2);
+    let [err] = broken.as_slice() else {
+        panic!("{broken:?}")
+    };
+    assert!(matches!(err, SongError::Event { track: 1, .. }), "{err}");
+    assert_eq!(err.playable_ticks(), 96);
+}
+
+#[test]
+fn tracks_the_header_promises_are_missing() {
+    let (tracks, broken) = check(MISSING_TRACK).unwrap();
+    assert_eq!(tracks, 2);
+    let [err] = broken.as_slice() else {
+        panic!("{broken:?}")
+    };
+    assert!(matches!(
+        err,
+        SongError::MissingTrack {
+            track: 2,
+            declared: 3
+        }
+    ));
+    assert_eq!(err.playable_ticks(), 0);
+}
+
+#[test]
+fn smpte_timing_is_refused_instead_of_played() {
+    assert!(matches!(check(SMPTE), Err(SongError::TimecodeTiming)));
+
+    let (header, _) = parse(SMPTE).unwrap();
+    assert!(matches!(
+        ticks_per_quarter(&header),
+        Err(SongError::TimecodeTiming)
+    ));
+    // the songs on the SD card are read a piece at a time and refused the same way
+    assert!(matches!(
+        MidiReader::<&[u8], 4, 64>::open(SMPTE),
+        Err(ReaderError::Song(SongError::TimecodeTiming))
+    ));
+
+    let (header, _) = parse(TWO_TRACKS).unwrap();
+    assert_eq!(ticks_per_quarter(&header).unwrap(), 96);
+}
+
+#[test]
+fn a_file_cut_inside_the_header_is_not_a_song() {
+    for len in [0, 4, 13] {
+        let err = check(&TWO_TRACKS[..len]).unwrap_err();
+        assert!(matches!(err, SongError::Header(_)), "{len} bytes: {err}");
+        assert_eq!(err.track(), None);
+    }
+    assert!(matches!(
+        check(b"RIFF\0\0\0\x04RMID"),
+        Err(SongError::Header(_))
+    ));
+}
@@ -0,0 +1,497 @@
+// =============================================================================================
+//                        THE SONG LIBRARY ON FAT IMAGES IN MEMORY
+// =============================================================================================
+
+// the images are formatted here instead of checked in, a FAT16 volume needs at least 4085
+// clusters and a FAT32 one 65525, which are megabytes of mostly zeros
+//
+// files can be written with their clusters spread out and the boot sector and directory
+// entries can be corrupted after formatting
+
+use midi_tools::{
+    fat::{FatError, FatVolume},
+    midi_reader::{MidiReader, SongSource},
+    ram_disk::{OutOfRange, RamDisk},
+    song_library::SongLibrary,
+};
+use midly::parse;
+
+const BLOCK_LEN: usize = 512;
+const TWO_TRACKS: &[u8] = include_bytes!("fixtures/two_tracks.mid");
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Fat16,
+    Fat32,
+}
+
+/// a freshly formatted volume, files are added to the root directory
+struct FatImage {
+    bytes: Vec<u8>,
+    kind: Kind,
+    boot_block: usize,
+    blocks_per_cluster: usize,
+    fat_start: usize,
+    fat_blocks: usize,
+    root_start: usize, // the fixed FAT16 root directory
+    data_start: usize,
+    cluster_count: usize,
+    next_cluster: usize,
+    root_clusters: Vec<usize>, // the FAT32 root directory
+    root_entries: usize,
+}
+
+impl FatImage {
+    fn fat16(blocks_per_cluster: usize) -> Self {
+        Self::format(Kind::Fat16, 0, blocks_per_cluster, 4100)
+    }
+
+    fn fat32(blocks_per_cluster: usize) -> Self {
+        Self::format(Kind::Fat32, 0, blocks_per_cluster, 65600)
+    }
+
+    /// a volume in the first partition of an MBR, like cards come formatted
+    fn partitioned(kind: Kind) -> Self {
+        let clusters = match kind {
+            Kind::Fat16 => 4100,
+            Kind::Fat32 => 65600,
+        };
+        Self::format(kind, 8, 1, clusters)
+    }
+
+    fn format(kind: Kind, boot_block: usize, blocks_per_cluster: usize, clusters: usize) -> Self {
+        let (reserved, root_entries, fat_entry_len) = match kind {
+            Kind::Fat16 => (1, 512, 2),
+            Kind::Fat32 => (32, 0, 4),
+        };
+        let fat_blocks = ((clusters + 2) * fat_entry_len).div_ceil(BLOCK_LEN);
+        let fat_start = boot_block + reserved;
+        let root_start = fat_start + 2 * fat_blocks;
+        let data_start = root_start + root_entries * 32 / BLOCK_LEN;
+        let total_blocks = data_start - boot_block + clusters * blocks_per_cluster;
+
+        let mut image = FatImage {
+            bytes: vec![0; (boot_block + total_blocks) * BLOCK_LEN],
+            kind,
+            boot_block,
+            blocks_per_cluster,
+            fat_start,
+            fat_blocks,
+            root_start,
+            data_start,
+            cluster_count: clusters,
+            next_cluster: 2,
+            root_clusters: Vec::new(),
+            root_entries: 0,
+        };
+
+        if boot_block > 0 {
+            let mbr = &mut image.bytes[..BLOCK_LEN];
+            mbr[446 + 4] = match kind {
+                Kind::Fat16 => 0x06,
+                Kind::Fat32 => 0x0C,
+            };
+            mbr[446 + 8..446 + 12].copy_from_slice(&(boot_block as u32).to_le_bytes());
+            mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+        }
+
+        let boot = image.block_mut(boot_block);
+        boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[11..13].copy_from_slice(&(BLOCK_LEN as u16).to_le_bytes());
+        boot[13] = blocks_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        match u16::try_from(total_blocks) {
+            Ok(blocks) => boot[19..21].copy_from_slice(&blocks.to_le_bytes()),
+            Err(_) => boot[32..36].copy_from_slice(&(total_blocks as u32).to_le_bytes()),
+        }
+        match kind {
+            Kind::Fat16 => boot[22..24].copy_from_slice(&(fat_blocks as u16).to_le_bytes()),
+            Kind::Fat32 => boot[36..40].copy_from_slice(&(fat_blocks as u32).to_le_bytes()),
+        }
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        if kind == Kind::Fat32 {
+            let root = image.allocate();
+            image.root_clusters.push(root);
+            image.set_root_cluster(root as u32);
+        }
+        image
+    }
+
+    fn block_mut(&mut self, block: usize) -> &mut [u8] {
+        &mut self.bytes[block * BLOCK_LEN..(block + 1) * BLOCK_LEN]
+    }
+
+    fn cluster_mut(&mut self, cluster: usize) -> &mut [u8] {
+        let start = (self.data_start + (cluster - 2) * self.blocks_per_cluster) * BLOCK_LEN;
+        &mut self.bytes[start..start + self.blocks_per_cluster * BLOCK_LEN]
+    }
+
+    fn cluster_len(&self) -> usize {
+        self.blocks_per_cluster * BLOCK_LEN
+    }
+
+    /// the next free cluster, marked as the end of a chain in both FATs
+    fn allocate(&mut self) -> usize {
+        let cluster = self.next_cluster;
+        self.next_cluster += 1;
+        self.set_fat(cluster, u32::MAX);
+        cluster
+    }
+
+    fn set_fat(&mut self, cluster: usize, next: u32) {
+        for fat in 0..2 {
+            let start = (self.fat_start + fat * self.fat_blocks) * BLOCK_LEN;
+            match self.kind {
+                Kind::Fat16 => {
+                    let at = start + cluster * 2;
+                    self.bytes[at..at + 2].copy_from_slice(&(next as u16).to_le_bytes());
+                }
+                Kind::Fat32 => {
+                    let at = start + cluster * 4;
+                    self.bytes[at..at + 4].copy_from_slice(&(next & 0x0FFF_FFFF).to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// writes the data in a chain of clusters, with a free cluster between each when spread
+    fn write_chain(&mut self, data: &[u8], spread: bool) -> u32 {
+        let mut first = 0;
+        let mut previous = None;
+        for chunk in data.chunks(self.cluster_len()) {
+            let cluster = self.allocate();
+            if spread {
+                self.next_cluster += 1;
+            }
+            self.cluster_mut(cluster)[..chunk.len()].copy_from_slice(chunk);
+            match previous {
+                Some(previous) => self.set_fat(previous, cluster as u32),
+                None => first = cluster as u32,
+            }
+            previous = Some(cluster);
+        }
+        first
+    }
+
+    fn add_file(&mut self, name: &[u8; 11], data: &[u8], spread: bool) {
+        let first_cluster = self.write_chain(data, spread);
+        self.add_entry(name, ATTR_ARCHIVE, first_cluster, data.len() as u32);
+    }
+
+    fn add_entry(&mut self, name: &[u8; 11], attributes: u8, first_cluster: u32, size: u32) {
+        let mut entry = [0; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        self.add_raw_entry(&entry);
+    }
+
+    fn add_raw_entry(&mut self, entry: &[u8; 32]) {
+        let index = self.root_entries;
+        self.root_entries += 1;
+        let at = match self.kind {
+            Kind::Fat16 => self.root_start * BLOCK_LEN + index * 32,
+            Kind::Fat32 => {
+                // the root directory grows a cluster at a time
+                let per_cluster = self.cluster_len() / 32;
+                if index / per_cluster == self.root_clusters.len() {
+                    let cluster = self.allocate();
+                    let last = *self.root_clusters.last().unwrap();
+                    self.set_fat(last, cluster as u32);
+                    self.root_clusters.push(cluster);
+                    // leave a gap so the chain has to be followed
+                    self.next_cluster += 1;
+                }
+                let cluster = self.root_clusters[index / per_cluster];
+                let start = (self.data_start + (cluster - 2) * self.blocks_per_cluster) * BLOCK_LEN;
+                start + index % per_cluster * 32
+            }
+        };
+        self.bytes[at..at + 32].copy_from_slice(entry);
+    }
+
+    fn set_root_cluster(&mut self, cluster: u32) {
+        let boot_block = self.boot_block;
+        self.block_mut(boot_block)[44..48].copy_from_slice(&cluster.to_le_bytes());
+    }
+
+    fn library<const SONGS: usize>(&self) -> SongLibrary<RamDisk<'_>, SONGS> {
+        SongLibrary::open(RamDisk::new(&self.bytes)).unwrap()
+    }
+}
+
+/// a format 0 song long enough to span many clusters
+fn long_song(notes: usize) -> Vec<u8> {
+    let mut events = vec![0x00, 0xFF, 0x03, 0x04, b'l', b'o', b'n', b'g'];
+    for i in 0..notes {
+        let key = 36 + (i % 48) as u8;
+        events.extend_from_slice(&[0x00, 0x90, key, 0x64, 0x30, 0x80, key, 0x40]);
+    }
+    events.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut midi = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+    midi.extend_from_slice(&(events.len() as u32).to_be_bytes());
+    midi.extend_from_slice(&events);
+    midi
+}
+
+/// the whole file read through the library in pieces of the given length
+fn read_song<D: midi_tools::block_device::BlockDevice, const SONGS: usize>(
+    library: &mut SongLibrary<D, SONGS>,
+    index: usize,
+    piece: usize,
+) -> Result<Vec<u8>, FatError<D::Error>> {
+    let mut song = library.song(index).unwrap();
+    let mut cursor = song.cursor();
+    let mut bytes = Vec::new();
+    let mut buffer = vec![0; piece];
+    loop {
+        let read = song.read(&mut cursor, &mut buffer)?;
+        if read == 0 {
+            return Ok(bytes);
+        }
+        bytes.extend_from_slice(&buffer[..read]);
+    }
+}
+
+fn names<D: midi_tools::block_device::BlockDevice, const SONGS: usize>(
+    library: &SongLibrary<D, SONGS>,
+) -> Vec<String> {
+    let songs = library.songs().iter();
+    songs.map(|song| song.file_name().to_string()).collect()
+}
+
+#[test]
+fn the_playlist_is_the_midi_files_of_the_root_sorted_by_name() {
+    let mut image = FatImage::fat16(1);
+    image.add_entry(b"SYNTH      ", ATTR_VOLUME_ID, 0, 0);
+    image.add_file(b"ZED     MID", TWO_TRACKS, false);
+    image.add_file(b"NOTES   TXT", b"not a song", false);
+    image.add_file(b"ALPHA   MID", TWO_TRACKS, false);
+    image.add_entry(b"SONGS   MID", ATTR_DIRECTORY, 0, 0);
+    image.add_file(b"PROFILESTXT", b"[lead]\n", false);
+    // a long name part and a deleted file are not songs either
+    let mut long_name = [0xFF; 32];
+    long_name[11] = ATTR_LONG_NAME;
+    image.add_raw_entry(&long_name);
+    image.add_entry(b"\xE5ONE    MID", ATTR_ARCHIVE, 0, 0);
+    image.add_file(b"mixed   mid", TWO_TRACKS, false);
+
+    let mut library = image.library::<8>();
+    assert_eq!(names(&library), ["ALPHA.MID", "ZED.MID", "mixed.mid"]);
+    assert_eq!(library.left_out(), 0);
+
+    let mut bank = library.profile_bank().unwrap();
+    let mut cursor = bank.cursor();
+    let mut bytes = [0; 16];
+    let read = bank.read(&mut cursor, &mut bytes).unwrap();
+    assert_eq!(&bytes[..read], b"[lead]\n");
+}
+
+#[test]
+fn songs_that_dont_fit_in_the_playlist_are_counted() {
+    let mut image = FatImage::fat16(1);
+    for name in [b"C       MID", b"A       MID", b"B       MID"] {
+        image.add_file(name, TWO_TRACKS, false);
+    }
+    let library = image.library::<2>();
+    assert_eq!(library.songs().len(), 2);
+    assert_eq!(library.left_out(), 1);
+    assert!(image.library::<2>().song(2).is_none());
+}
+
+#[test]
+fn spread_out_songs_read_back_whole() {
+    let song = long_song(1000);
+    for blocks_per_cluster in [1, 4] {
+        let mut image = FatImage::fat16(blocks_per_cluster);
+        image.add_file(b"LONG    MID", &song, true);
+        assert!(song.len() > 3 * image.cluster_len());
+
+        let mut library = image.library::<4>();
+        for piece in [1, 37, 512, 1000, 8192] {
+            let read = read_song(&mut library, 0, piece).unwrap();
+            assert!(read == song, "{piece} byte pieces");
+        }
+    }
+}
+
+#[test]
+fn seeking_back_and_forth_reads_the_same_bytes() {
+    let song = long_song(400);
+    let mut image = FatImage::fat16(2);
+    image.add_file(b"LONG    MID", &song, true);
+    let mut library = image.library::<4>();
+    let mut file = library.song(0).unwrap();
+    let mut cursor = file.cursor();
+
+    let mut byte = [0];
+    for position in [3000, 10, 1023, 1024, 1025, 2500, 0, song.len() as u32 - 1] {
+        file.seek(&mut cursor, position);
+        assert_eq!(file.read(&mut cursor, &mut byte).unwrap(), 1);
+        assert_eq!(byte[0], song[position as usize], "at {position}");
+    }
+    // past the end is the end
+    file.seek(&mut cursor, u32::MAX);
+    assert_eq!(file.read(&mut cursor, &mut byte).unwrap(), 0);
+}
+
+#[test]
+fn the_midi_reader_plays_the_song_off_the_card() {
+    let mut image = FatImage::fat16(1);
+    image.add_file(b"SONG    MID", TWO_TRACKS, true);
+    let mut library = image.library::<4>();
+
+    let mut reader = MidiReader::<_, 4, 32>::open(library.song(0).unwrap()).unwrap();
+    let (header, tracks) = parse(TWO_TRACKS).unwrap();
+    assert_eq!(reader.header(), header);
+    for (track, events) in tracks.enumerate() {
+        let expected: Vec<_> = events.unwrap().map(Result::unwrap).collect();
+        let mut read = 0;
+        while let Some(event) = reader.next_event(track) {
+            assert_eq!(
+                Some(&event),
+                expected.get(read),
+                "track {track} event {read}"
+            );
+            read += 1;
+        }
+        assert_eq!(read, expected.len());
+    }
+    assert!(reader.source_error().is_none());
+}
+
+#[test]
+fn a_fat32_root_directory_follows_its_cluster_chain() {
+    let mut image = FatImage::fat32(1);
+    let mut expected = Vec::new();
+    // 16 entries fit in a cluster, the root directory takes three
+    for i in 0..40 {
+        let name = format!("SONG{i:02}  MID");
+        image.add_file(name.as_bytes().try_into().unwrap(), TWO_TRACKS, i % 2 == 0);
+        expected.push(format!("SONG{i:02}.MID"));
+    }
+    assert_eq!(image.root_clusters.len(), 3);
+
+    let mut library = image.library::<64>();
+    assert_eq!(names(&library), expected);
+    assert_eq!(read_song(&mut library, 39, 100).unwrap(), TWO_TRACKS);
+}
+
+#[test]
+fn a_fat32_root_directory_that_loops_is_an_error() {
+    let mut image = FatImage::fat32(1);
+    // two full clusters of entries, so there's no end of the directory to stop at
+    for i in 0..32 {
+        let name = format!("SONG{i:02}  MID");
+        image.add_file(name.as_bytes().try_into().unwrap(), TWO_TRACKS, false);
+    }
+    assert_eq!(image.root_clusters.len(), 2);
+    image.set_fat(image.root_clusters[1], image.root_clusters[0] as u32);
+
+    let mut volume = FatVolume::mount(RamDisk::new(&image.bytes)).unwrap();
+    let mut listed = 0;
+    let listing = volume.list_root(|_| listed += 1);
+    assert!(
+        matches!(listing, Err(FatError::ClusterChainLoop)),
+        "{listing:?}"
+    );
+    // each time around the loop lists the same entries again, until the volume runs out
+    assert_eq!(listed, image.cluster_count * 16);
+    let library = SongLibrary::<_, 64>::open(RamDisk::new(&image.bytes));
+    assert!(matches!(library, Err(FatError::ClusterChainLoop)));
+}
+
+#[test]
+fn partitioned_cards_mount_the_first_fat_partition() {
+    for kind in [Kind::Fat16, Kind::Fat32] {
+        let mut image = FatImage::partitioned(kind);
+        image.add_file(b"SONG    MID", TWO_TRACKS, false);
+        let mut library = image.library::<4>();
+        assert_eq!(names(&library), ["SONG.MID"], "{kind:?}");
+        assert_eq!(read_song(&mut library, 0, 64).unwrap(), TWO_TRACKS);
+    }
+}
+
+#[test]
+fn a_corrupted_root_cluster_is_refused_when_mounting() {
+    let mut image = FatImage::fat32(1);
+    let past_the_end = image.cluster_count as u32 + 2;
+    for root_cluster in [0, 1, past_the_end, u32::MAX] {
+        image.set_root_cluster(root_cluster);
+        let mounted = FatVolume::mount(RamDisk::new(&image.bytes));
+        assert!(
+            matches!(mounted, Err(FatError::BadCluster(cluster)) if cluster == root_cluster),
+            "root cluster {root_cluster}"
+        );
+    }
+}
+
+#[test]
+fn a_corrupted_directory_entry_is_an_error_when_read() {
+    let past_the_end = FatImage::fat16(1).cluster_count as u32 + 2;
+    for first_cluster in [0, 1, past_the_end, 0xFFFF] {
+        let mut image = FatImage::fat16(1);
+        image.add_entry(b"BROKEN  MID", ATTR_ARCHIVE, first_cluster, 2000);
+        let mut library = image.library::<4>();
+        let read = read_song(&mut library, 0, 64);
+        assert!(
+            matches!(read, Err(FatError::BadCluster(cluster)) if cluster == first_cluster),
+            "first cluster {first_cluster}: {read:?}"
+        );
+    }
+
+    // seeking into a later cluster goes through the FAT instead
+    let mut image = FatImage::fat32(1);
+    image.add_entry(b"BROKEN  MID", ATTR_ARCHIVE, 0x0FFF_FFF0, 2000);
+    let mut library = image.library::<4>();
+    let mut file = library.song(0).unwrap();
+    let mut cursor = file.cursor();
+    file.seek(&mut cursor, 1500);
+    let read = file.read(&mut cursor, &mut [0; 16]);
+    assert!(
+        matches!(read, Err(FatError::BadCluster(0x0FFF_FFF0))),
+        "{read:?}"
+    );
+}
+
+#[test]
+fn a_file_longer_than_its_chain_is_broken() {
+    let mut image = FatImage::fat16(1);
+    let first_cluster = image.write_chain(&[0x42; 1000], false);
+    image.add_entry(b"SHORT   MID", ATTR_ARCHIVE, first_cluster, 3000);
+    let mut library = image.library::<4>();
+    let read = read_song(&mut library, 0, 256);
+    assert!(
+        matches!(read, Err(FatError::BrokenClusterChain)),
+        "{read:?}"
+    );
+}
+
+#[test]
+fn only_fat16_and_fat32_volumes_mount() {
+    let blank = vec![0; 64 * BLOCK_LEN];
+    let mounted = FatVolume::mount(RamDisk::new(&blank));
+    assert!(matches!(mounted, Err(FatError::NoFilesystem)));
+
+    let small = FatImage::format(Kind::Fat16, 0, 1, 2000);
+    let mounted = FatVolume::mount(RamDisk::new(&small.bytes));
+    assert!(matches!(mounted, Err(FatError::Fat12)));
+
+    // an image cut short reads blocks of zeros and then runs out
+    let image = FatImage::fat16(1);
+    let mounted = FatVolume::mount(RamDisk::new(&image.bytes[..100]));
+    assert!(matches!(mounted, Err(FatError::NoFilesystem)));
+    let mounted = FatVolume::mount(RamDisk::new(&[]));
+    assert!(matches!(mounted, Err(FatError::Device(OutOfRange(0)))));
+}
@@ -0,0 +1,222 @@
+// =============================================================================================
+//                     TICKS, TIMES, BARS AND BEATS OF THE FIXTURE SONGS
+// =============================================================================================
+
+use midi_tools::{
+    midi_reader::MidiReader,
+    song_check::check_song,
+    tempo_map::{BarBeat, TempoMap, TempoMapError},
+    test_midi::{EOT, smf, tempo},
+    track_merger::TrackMerger,
+};
+use midly::parse;
+
+const TWO_TRACKS: &[u8] = include_bytes!("fixtures/two_tracks.mid");
+const TEMPO_CHANGES: &[u8] = include_bytes!("fixtures/tempo_changes.mid");
+const PATTERNS: &[u8] = include_bytes!("fixtures/patterns.mid");
+const RUNNING_STATUS: &[u8] = include_bytes!("fixtures/running_status.mid");
+const SMPTE: &[u8] = include_bytes!("fixtures/smpte.mid");
+
+type SongTempoMap = TempoMap<64, 16>;
+
+const fn bar_beat(bar: u32, beat: u32, tick_in_beat: u32) -> BarBeat {
+    BarBeat {
+        bar,
+        beat,
+        tick_in_beat,
+    }
+}
+
+#[test]
+fn every_tick_comes_back_from_its_time() {
+    let tempo_map = SongTempoMap::from_midi(TEMPO_CHANGES, |_| true).unwrap();
+    // a tick at 60 BPM and 480 a quarter is 2083.33 micro seconds, its time is rounded down
+    for tick in 0..=tempo_map.length_ticks() + 480 {
+        let micros = tempo_map.tick_to_micros(tick);
+        assert_eq!(tempo_map.micros_to_tick(micros), tick, "{micros} µs");
+    }
+}
+
+#[test]
+fn the_tempo_changes_move_the_times() {
+    // two quarters at 100 BPM, two at 200 BPM and three at 60 BPM
+    let tempo_map = SongTempoMap::from_midi(TEMPO_CHANGES, |_| true).unwrap();
+    assert_eq!(tempo_map.tick_to_micros(480), 600_000);
+    assert_eq!(tempo_map.tick_to_micros(960), 1_200_000);
+    assert_eq!(tempo_map.tick_to_micros(1440), 1_500_000);
+    assert_eq!(tempo_map.tick_to_micros(1920), 1_800_000);
+    assert_eq!(tempo_map.length_micros(), 4_800_000);
+
+    assert_eq!(tempo_map.micros_to_tick(1_200_000), 960);
+    assert_eq!(tempo_map.micros_to_tick(1_800_000), 1920);
+    // half way between two ticks rounds up, past the end the last tempo goes on
+    assert_eq!(tempo_map.micros_to_tick(1_200_313), 961);
+    assert_eq!(tempo_map.micros_to_tick(5_800_000), 3840);
+
+    // a song without tempo changes is 120 BPM
+    let tempo_map = SongTempoMap::from_midi(TWO_TRACKS, |_| true).unwrap();
+    assert_eq!(tempo_map.tick_to_micros(96), 500_000);
+    assert_eq!(tempo_map.micros_to_tick(1_000_000), 192);
+}
+
+#[test]
+fn the_beats_and_bars_follow_the_time_signature() {
+    // 3/4 from the start, a bar is 1440 ticks
+    let tempo_map = SongTempoMap::from_midi(TEMPO_CHANGES, |_| true).unwrap();
+    assert_eq!(tempo_map.beats_per_bar(0), 3);
+    assert_eq!(tempo_map.tick_to_bar_beat(0), bar_beat(1, 1, 0));
+    assert_eq!(tempo_map.tick_to_bar_beat(480), bar_beat(1, 2, 0));
+    assert_eq!(tempo_map.tick_to_bar_beat(1439), bar_beat(1, 3, 479));
+    assert_eq!(tempo_map.tick_to_bar_beat(1500), bar_beat(2, 1, 60));
+    assert_eq!(tempo_map.tick_to_bar_beat(3360), bar_beat(3, 2, 0));
+
+    assert_eq!(tempo_map.next_beat(0), 0);
+    assert_eq!(tempo_map.next_beat(1), 480);
+    assert_eq!(tempo_map.next_beat(960), 960);
+    assert_eq!(tempo_map.next_beat(3000), 3360);
+
+    // a song without a time signature is in 4/4
+    let tempo_map = SongTempoMap::from_midi(TWO_TRACKS, |_| true).unwrap();
+    assert_eq!(tempo_map.beats_per_bar(0), 4);
+    assert_eq!(tempo_map.tick_to_bar_beat(384), bar_beat(2, 1, 0));
+}
+
+#[test]
+fn a_time_signature_change_starts_a_bar_and_a_beat() {
+    // 4/4 at 96 a quarter, then 6/8 from tick 500, in the middle of the second bar
+    const SIX_EIGHT: &[u8] = &[0xFF, 0x58, 0x04, 6, 3, 36, 8];
+    let midi = smf(1, 96, &[&[(500, SIX_EIGHT), (576, EOT)]]);
+    let tempo_map = SongTempoMap::from_midi(&midi, |_| true).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 1076);
+
+    assert_eq!(tempo_map.beats_per_bar(499), 4);
+    assert_eq!(tempo_map.tick_to_bar_beat(499), bar_beat(2, 2, 19));
+    // the beat of the old meter is cut short by the change
+    assert_eq!(tempo_map.next_beat(450), 480);
+    assert_eq!(tempo_map.next_beat(490), 500);
+
+    // the change is the first beat of the third bar, counting eighth notes
+    assert_eq!(tempo_map.beats_per_bar(500), 6);
+    assert_eq!(tempo_map.tick_to_bar_beat(500), bar_beat(3, 1, 0));
+    assert_eq!(
+        tempo_map.tick_to_bar_beat(500 + 5 * 48 + 7),
+        bar_beat(3, 6, 7)
+    );
+    assert_eq!(tempo_map.tick_to_bar_beat(500 + 6 * 48), bar_beat(4, 1, 0));
+    assert_eq!(tempo_map.next_beat(501), 548);
+    // the time signature doesn't change the time
+    assert_eq!(tempo_map.tick_to_micros(548), 548 * 500_000 / 96);
+}
+
+#[test]
+fn the_patterns_of_a_format_2_song_play_one_after_another() {
+    // three patterns of two quarters, at 120, 150 and 100 BPM
+    let tempo_map = SongTempoMap::from_midi(PATTERNS, |_| true).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 576);
+    assert_eq!(tempo_map.tick_to_micros(192), 1_000_000);
+    assert_eq!(tempo_map.tick_to_micros(384), 1_800_000);
+    assert_eq!(tempo_map.length_micros(), 3_000_000);
+
+    // a single pattern starts at the beginning
+    let tempo_map = SongTempoMap::from_midi(PATTERNS, |track| track == 1).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 192);
+    assert_eq!(tempo_map.length_micros(), 800_000);
+}
+
+#[test]
+fn a_skipped_pattern_takes_no_time() {
+    // the second pattern of three is cut off before its end of track
+    let first: &[(u32, &[u8])] = &[
+        (0, &tempo(500_000)),
+        (0, &[0x90, 60, 100]),
+        (192, &[0x80, 60, 0]),
+        (0, EOT),
+    ];
+    let broken: &[(u32, &[u8])] = &[
+        (0, &tempo(250_000)),
+        (0, &[0x90, 62, 100]),
+        (96, &[0x80, 62, 0]),
+    ];
+    let last: &[(u32, &[u8])] = &[
+        (0, &tempo(600_000)),
+        (0, &[0x90, 64, 100]),
+        (192, &[0x80, 64, 0]),
+        (0, EOT),
+    ];
+    let midi = smf(2, 96, &[first, broken, last]);
+
+    let mut broken_tracks = Vec::new();
+    check_song(&midi, |err| broken_tracks.push(err.track().unwrap())).unwrap();
+    assert_eq!(broken_tracks, [1]);
+    let plays_track = |track: usize| !broken_tracks.contains(&track);
+
+    // the last pattern starts where the first one ends, like the merger plays it
+    let tempo_map = SongTempoMap::from_midi(&midi, plays_track).unwrap();
+    assert_eq!(tempo_map.length_ticks(), 384);
+    assert_eq!(tempo_map.tick_to_micros(192), 1_000_000);
+    assert_eq!(tempo_map.length_micros(), 2_200_000);
+
+    let (_, tracks) = parse(&midi).unwrap();
+    let tracks = tracks.map(Result::unwrap).enumerate();
+    let merger = TrackMerger::<4>::sequential(tracks.filter(|(i, _)| plays_track(*i))).unwrap();
+    let played: Vec<(usize, u64)> = merger.map(|event| (event.track, event.tick)).collect();
+    assert_eq!(
+        played.iter().filter(|(track, _)| *track == 2).min(),
+        Some(&(2, 192))
+    );
+    assert_eq!(played.last(), Some(&(2, 384)));
+
+    // the same from the reader
+    let mut reader = MidiReader::<&[u8], 4, 64>::open(&midi).unwrap();
+    let tempo_map = SongTempoMap::from_reader(&mut reader, plays_track).unwrap();
+    assert_eq!(tempo_map.length_micros(), 2_200_000);
+    reader.start_merge(plays_track);
+    let mut streamed = Vec::new();
+    while let Some(event) = reader.next_merged() {
+        streamed.push((event.track, event.tick));
+    }
+    assert_eq!(streamed, played);
+}
+
+#[test]
+fn reading_a_piece_at_a_time_maps_the_same_song() {
+    for midi in [TWO_TRACKS, TEMPO_CHANGES, PATTERNS, RUNNING_STATUS] {
+        let expected = SongTempoMap::from_midi(midi, |_| true).unwrap();
+        let mut reader = MidiReader::<&[u8], 4, 64>::open(midi).unwrap();
+        let tempo_map = SongTempoMap::from_reader(&mut reader, |_| true).unwrap();
+
+        assert_eq!(tempo_map.length_ticks(), expected.length_ticks());
+        for tick in (0..=expected.length_ticks()).step_by(7) {
+            assert_eq!(
+                tempo_map.tick_to_micros(tick),
+                expected.tick_to_micros(tick)
+            );
+            assert_eq!(
+                tempo_map.tick_to_bar_beat(tick),
+                expected.tick_to_bar_beat(tick)
+            );
+        }
+        // the reader is back at the start for the player
+        assert!(reader.next_event(0).is_some());
+    }
+}
+
+#[test]
+fn songs_the_map_cant_hold_are_refused() {
+    assert!(matches!(
+        TempoMap::<2, 16>::from_midi(TEMPO_CHANGES, |_| true),
+        Err(TempoMapError::TooManyTempoChanges)
+    ));
+    assert!(matches!(
+        TempoMap::<64, 1>::from_midi(TEMPO_CHANGES, |_| true),
+        Err(TempoMapError::TooManyTimeSignatures)
+    ));
+    assert!(matches!(
+        SongTempoMap::from_midi(SMPTE, |_| true),
+        Err(TempoMapError::TimecodeTiming)
+    ));
+    assert!(matches!(
+        SongTempoMap::from_midi(b"MThd", |_| true),
+        Err(TempoMapError::Parse(_))
+    ));
+}